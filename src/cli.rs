@@ -0,0 +1,440 @@
+// a small declarative command line parser
+//
+// every subcommand is described once as a Command, and parsing, usage and
+// help text are all generated from that table. the about and help strings
+// are the english defaults for the cli.<command>.about and
+// cli.<command>.<argument> catalog keys.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Config, Setting};
+use whats_a_png::png::locale::{message, message_or};
+pub use whats_a_png::png::plugin::Failure;
+
+pub struct Positional {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub required: bool,
+}
+
+pub struct Opt {
+    pub name: &'static str,
+    // name of the value it takes, None for plain flags
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub positionals: &'static [Positional],
+    pub options: &'static [Opt],
+    // false for commands whose output can't depend on whats-a-png.toml, they
+    // don't load it so a broken file can't get in their way
+    pub reads_config: bool,
+    pub run: fn(&Args) -> Result<(), Failure>,
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    positionals: Vec<String>,
+    // every value given for each option, in order
+    values: HashMap<&'static str, Vec<String>>,
+    flags: Vec<&'static str>,
+    // flags switched off with --no-<flag>, the config file can't turn them on
+    negated: Vec<&'static str>,
+    // options filled in from the config file rather than the command line
+    configured: Vec<&'static str>,
+    // where outputs the command names itself go
+    output_dir: Option<PathBuf>,
+}
+
+impl Args {
+    pub fn positional(&self, index: usize) -> Option<&str> {
+        self.positionals.get(index).map(|s| s.as_str())
+    }
+
+    // the last value given for the option
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).last()
+    }
+
+    pub fn values<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.values
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|s| s.as_str())
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+
+    // whether the option was filled in from the config file
+    pub fn configured(&self, name: &str) -> bool {
+        self.configured.contains(&name)
+    }
+
+    // an output the command line left out, named after the input and put in
+    // the configured output directory. paths given on the command line are
+    // used as they are
+    pub fn derived_output(&self, input: &str) -> Option<PathBuf> {
+        let name = Path::new(input).file_name()?;
+        Some(self.output_dir.as_ref()?.join(name))
+    }
+
+    // fills in the options the command line left out from the [command] table
+    pub fn apply_config(&mut self, command: &Command, config: &Config) {
+        self.output_dir = config.output_dir();
+
+        let table = match config.section(command.name) {
+            Some(t) => t,
+            None => return,
+        };
+        for opt in command.options {
+            match table.get(opt.name) {
+                Some(Setting::Flag(true))
+                    if !self.flag(opt.name) && !self.negated.contains(&opt.name) =>
+                {
+                    self.flags.push(opt.name);
+                    self.configured.push(opt.name);
+                }
+                Some(Setting::Values(values)) if !self.values.contains_key(opt.name) => {
+                    self.values.insert(opt.name, values.clone());
+                    self.configured.push(opt.name);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // parses an option's value, naming the option when it doesn't parse
+    pub fn parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Failure> {
+        match self.value(name) {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| Failure::Usage(message("cli.invalid_value", &[&v, &name]))),
+            None => Ok(None),
+        }
+    }
+}
+
+pub fn parse(command: &Command, args: &[String]) -> Result<Args, Failure> {
+    let mut parsed = Args::default();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        // everything after -- is positional, even when it starts with --
+        if arg == "--" {
+            parsed.positionals.extend(iter.by_ref().cloned());
+            break;
+        }
+
+        let name = match arg.strip_prefix("--") {
+            Some(n) => n,
+            None => {
+                parsed.positionals.push(arg.clone());
+                continue;
+            }
+        };
+
+        // accept both --name value and --name=value
+        let (name, inline) = match name.split_once('=') {
+            Some((n, v)) => (n, Some(v.to_string())),
+            None => (name, None),
+        };
+
+        let opt = match command.options.iter().find(|o| o.name == name) {
+            Some(o) => o,
+            None => match negated_flag(command, name) {
+                Some(o) if inline.is_none() => {
+                    parsed.flags.retain(|f| *f != o.name);
+                    parsed.negated.push(o.name);
+                    continue;
+                }
+                _ => {
+                    return Err(Failure::Usage(message(
+                        "cli.unknown_option",
+                        &[&name, &command.name],
+                    )))
+                }
+            },
+        };
+
+        if opt.value.is_none() {
+            parsed.negated.retain(|f| *f != opt.name);
+            parsed.flags.push(opt.name);
+            continue;
+        }
+
+        let value = match inline.or_else(|| iter.next().cloned()) {
+            Some(v) => v,
+            None => return Err(Failure::Usage(message("cli.needs_value", &[&name]))),
+        };
+        parsed.values.entry(opt.name).or_default().push(value);
+    }
+
+    let required = command.positionals.iter().filter(|p| p.required).count();
+    if parsed.positionals.len() < required {
+        return Err(Failure::Usage(format!(
+            "{}\n\n{}",
+            message("cli.missing_argument", &[]),
+            command_usage(command)
+        )));
+    }
+    if parsed.positionals.len() > command.positionals.len() {
+        return Err(Failure::Usage(format!(
+            "{}\n\n{}",
+            message("cli.too_many_arguments", &[]),
+            command_usage(command)
+        )));
+    }
+
+    Ok(parsed)
+}
+
+// removes flag from args wherever it stands as a flag, which is before any
+// -- and not in place of the value of the option in front of it. options come
+// from the command named by the first other argument, anything else (help or
+// a plugin) has no table to look in so every copy before -- counts
+pub fn take_flag(commands: &[Command], args: &mut Vec<String>, flag: &str) -> bool {
+    let mut found = false;
+    // None until the command name has gone by
+    let mut command: Option<Option<&Command>> = None;
+    let mut i = 0;
+
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "--" {
+            break;
+        }
+        if arg == flag {
+            args.remove(i);
+            found = true;
+            continue;
+        }
+
+        match command {
+            None if !arg.starts_with("--") => {
+                command = Some(commands.iter().find(|c| c.name == arg));
+            }
+            Some(Some(c)) => {
+                let takes_value = arg
+                    .strip_prefix("--")
+                    .is_some_and(|n| c.options.iter().any(|o| o.name == n && o.value.is_some()));
+                if takes_value {
+                    i += 1;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    found
+}
+
+// the flag a --no-<flag> option switches off
+fn negated_flag<'a>(command: &'a Command, name: &str) -> Option<&'a Opt> {
+    let name = name.strip_prefix("no-")?;
+    command
+        .options
+        .iter()
+        .find(|o| o.name == name && o.value.is_none())
+}
+
+pub fn command_usage(command: &Command) -> String {
+    let mut line = format!("whats-a-png {}", command.name);
+
+    for p in command.positionals {
+        if p.required {
+            line += &format!(" <{}>", p.name);
+        } else {
+            line += &format!(" [{}]", p.name);
+        }
+    }
+    if !command.options.is_empty() {
+        line += " [options]";
+    }
+
+    message("cli.usage", &[&line])
+}
+
+pub fn about(command: &Command) -> String {
+    message_or(&format!("cli.{}.about", command.name), command.about, &[])
+}
+
+pub fn help(command: &Command, name: &str, default: &str) -> String {
+    message_or(&format!("cli.{}.{}", command.name, name), default, &[])
+}
+
+pub fn command_help(command: &Command) -> String {
+    let mut text = format!("{}\n\n{}\n", about(command), command_usage(command));
+
+    if !command.positionals.is_empty() {
+        text += &format!("\n{}\n", message("cli.arguments", &[]));
+        for p in command.positionals {
+            text += &format!("  {:<20} {}\n", p.name, help(command, p.name, p.help));
+        }
+    }
+
+    if !command.options.is_empty() {
+        text += &format!("\n{}\n", message("cli.options", &[]));
+        for o in command.options {
+            let name = match o.value {
+                Some(v) => format!("--{} <{}>", o.name, v),
+                None => format!("--{}", o.name),
+            };
+            text += &format!("  {:<20} {}\n", name, help(command, o.name, o.help));
+        }
+    }
+
+    text
+}
+
+// plugins are the whats-a-png-<name> executables found on the PATH
+pub fn usage(commands: &[Command], plugins: &[String]) -> String {
+    let mut text = format!(
+        "{}\n\n{}\n",
+        message(
+            "cli.usage",
+            &[&"whats-a-png [--json] <command> [arguments]"]
+        ),
+        message("cli.commands", &[])
+    );
+    for c in commands {
+        text += &format!("  {:<12} {}\n", c.name, about(c));
+    }
+    if !plugins.is_empty() {
+        text += &format!(
+            "\n{}\n  {}\n",
+            message("cli.plugins", &[]),
+            plugins.join(" ")
+        );
+    }
+    text += &format!("\n{}\n", message("cli.help_hint", &[]));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: Command = Command {
+        name: "test",
+        about: "a test command",
+        positionals: &[
+            Positional {
+                name: "input",
+                help: "file to read",
+                required: true,
+            },
+            Positional {
+                name: "output",
+                help: "file to write",
+                required: false,
+            },
+        ],
+        options: &[
+            Opt {
+                name: "colors",
+                value: Some("N"),
+                help: "how many",
+            },
+            Opt {
+                name: "force",
+                value: None,
+                help: "overwrite",
+            },
+        ],
+        reads_config: true,
+        run: |_| Ok(()),
+    };
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = parse(&TEST, &args(&["a.png", "--colors", "4", "--force"])).unwrap();
+        assert_eq!(parsed.positional(0), Some("a.png"));
+        assert_eq!(parsed.positional(1), None);
+        assert_eq!(parsed.parsed::<usize>("colors").unwrap(), Some(4));
+        assert!(parsed.flag("force"));
+
+        let parsed = parse(
+            &TEST,
+            &args(&["--colors=x", "a.png", "b.png", "--colors=2"]),
+        )
+        .unwrap();
+        assert_eq!(parsed.positional(1), Some("b.png"));
+        assert_eq!(parsed.values("colors").collect::<Vec<_>>(), ["x", "2"]);
+        assert_eq!(parsed.parsed::<usize>("colors").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse(&TEST, &args(&[])).is_err());
+        assert!(parse(&TEST, &args(&["a", "b", "c"])).is_err());
+        assert!(parse(&TEST, &args(&["a", "--nope"])).is_err());
+        assert!(parse(&TEST, &args(&["a", "--colors"])).is_err());
+
+        let failure = parse(&TEST, &args(&["a", "--colors=x"]))
+            .unwrap()
+            .parsed::<usize>("colors")
+            .unwrap_err();
+        assert_eq!((failure.code(), failure.exit_code()), (100, 2));
+    }
+
+    #[test]
+    fn test_take_flag() {
+        let mut list = args(&[
+            "--json", "test", "a.png", "--colors", "--json", "--json", "--", "--json",
+        ]);
+        assert!(take_flag(&[TEST], &mut list, "--json"));
+        assert_eq!(
+            list,
+            ["test", "a.png", "--colors", "--json", "--", "--json"]
+        );
+
+        let parsed = parse(&TEST, &list[1..]).unwrap();
+        assert_eq!(parsed.value("colors"), Some("--json"));
+        assert_eq!(parsed.positional(1), Some("--json"));
+
+        let mut list = args(&["test", "a.png", "--colors=--json"]);
+        assert!(!take_flag(&[TEST], &mut list, "--json"));
+        assert_eq!(list.len(), 3);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let config = Config::parse(
+            Path::new("whats-a-png.toml"),
+            "[test]\ncolors = 3\nforce = true\n[output]\ndir = \"out\"",
+        )
+        .unwrap();
+
+        // the command line wins over the file
+        let mut parsed = parse(&TEST, &args(&["a.png", "--colors=5"])).unwrap();
+        parsed.apply_config(&TEST, &config);
+        assert_eq!(parsed.parsed::<usize>("colors").unwrap(), Some(5));
+        assert!(parsed.flag("force"));
+        assert_eq!(
+            parsed.derived_output("in/b.png"),
+            Some(PathBuf::from("out/b.png"))
+        );
+        assert!(parsed.configured("force") && !parsed.configured("colors"));
+
+        // --no-<flag> keeps the file from switching a flag on
+        let mut parsed = parse(&TEST, &args(&["a.png", "--force", "--no-force"])).unwrap();
+        parsed.apply_config(&TEST, &config);
+        assert!(!parsed.flag("force"));
+        assert!(parse(&TEST, &args(&["a.png", "--no-colors"])).is_err());
+    }
+}
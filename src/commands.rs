@@ -0,0 +1,519 @@
+// the subcommands of the whats-a-png binary
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use whats_a_png::png::{
+    adjust::match_histogram,
+    compare::{diff_image, CompareOptions, RegionThreshold},
+    locale::message,
+    memory::MemoryFootprint,
+    palette::PaletteFormat,
+    plugin::load,
+    transcode, EncodeOptions, MetadataPolicy, PngImage, Preset, Rect, TranscodeOptions,
+};
+
+use crate::{
+    cli::{Args, Command, Failure, Opt, Positional},
+    generate::{self, Shell},
+};
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "info",
+        about: "Print the header and chunk list of a png",
+        positionals: &[Positional {
+            name: "input",
+            help: "png file to inspect",
+            required: true,
+        }],
+        options: &[Opt {
+            name: "stats",
+            value: None,
+            help: "also decode the image data and print compression and memory statistics",
+        }],
+        reads_config: true,
+        run: info,
+    },
+    Command {
+        name: "palette",
+        about: "Export the dominant colors of a png as a palette",
+        positionals: &[Positional {
+            name: "input",
+            help: "png file to take the colors from",
+            required: true,
+        }],
+        options: &[
+            Opt {
+                name: "colors",
+                value: Some("N"),
+                help: "number of colors to extract (default 8)",
+            },
+            Opt {
+                name: "format",
+                value: Some("FORMAT"),
+                help: "gpl, ase or hex (default from --output, else hex)",
+            },
+            Opt {
+                name: "output",
+                value: Some("FILE"),
+                help: "write the palette here instead of stdout",
+            },
+        ],
+        reads_config: true,
+        run: palette,
+    },
+    Command {
+        name: "compare",
+        about: "Compare two pngs pixel by pixel, failing if they differ",
+        positionals: &[
+            Positional {
+                name: "expected",
+                help: "reference png",
+                required: true,
+            },
+            Positional {
+                name: "actual",
+                help: "png to check against it",
+                required: true,
+            },
+        ],
+        options: &[
+            Opt {
+                name: "threshold",
+                value: Some("T"),
+                help: "color tolerance from 0 (exact) to 1 (default 0.1)",
+            },
+            Opt {
+                name: "ignore",
+                value: Some("X,Y,W,H"),
+                help: "area to leave out of the comparison, may be repeated",
+            },
+            Opt {
+                name: "region",
+                value: Some("X,Y,W,H:T"),
+                help: "area with its own threshold, may be repeated",
+            },
+            Opt {
+                name: "anti-aliasing",
+                value: None,
+                help: "tolerate differences in anti-aliased edges",
+            },
+            Opt {
+                name: "match-histogram",
+                value: None,
+                help: "remap the actual colors to the expected ones first",
+            },
+            Opt {
+                name: "diff",
+                value: Some("FILE"),
+                help: "write an image highlighting the differences",
+            },
+        ],
+        reads_config: true,
+        run: compare,
+    },
+    Command {
+        name: "optimize",
+        about: "Recompress a png, dropping metadata it doesn't need",
+        positionals: &[
+            Positional {
+                name: "input",
+                help: "png file to optimize",
+                required: true,
+            },
+            Positional {
+                name: "output",
+                help: "where to write the result, may be the input (default the input's name in the [output] dir)",
+                required: false,
+            },
+        ],
+        options: &[
+            Opt {
+                name: "preset",
+                value: Some("PRESET"),
+                help: "web, archive, fastest or smallest (default archive)",
+            },
+            Opt {
+                name: "keep",
+                value: Some("CHUNK"),
+                help: "ancillary chunk to keep, dropping the rest, may be repeated",
+            },
+            Opt {
+                name: "strip",
+                value: None,
+                help: "drop every ancillary chunk",
+            },
+        ],
+        reads_config: true,
+        run: optimize,
+    },
+    Command {
+        name: "export-chunks",
+        about: "Write every chunk to its own file with a json manifest",
+        positionals: &[
+            Positional {
+                name: "input",
+                help: "png file to take apart",
+                required: true,
+            },
+            Positional {
+                name: "dir",
+                help: "directory for the chunk files",
+                required: true,
+            },
+        ],
+        options: &[],
+        reads_config: true,
+        run: export_chunks,
+    },
+    Command {
+        name: "import-chunks",
+        about: "Put a png back together from an export-chunks directory",
+        positionals: &[
+            Positional {
+                name: "dir",
+                help: "directory holding chunks.json",
+                required: true,
+            },
+            Positional {
+                name: "output",
+                help: "where to write the png",
+                required: true,
+            },
+        ],
+        options: &[],
+        reads_config: true,
+        run: import_chunks,
+    },
+    Command {
+        name: "completions",
+        about: "Print a shell completion script",
+        positionals: &[Positional {
+            name: "shell",
+            help: "bash, zsh or fish",
+            required: true,
+        }],
+        options: &[],
+        reads_config: false,
+        run: completions,
+    },
+    Command {
+        name: "mangen",
+        about: "Print the man page in roff format",
+        positionals: &[],
+        options: &[],
+        reads_config: false,
+        run: mangen,
+    },
+];
+
+fn write_file(path: &Path, bytes: &[u8]) -> Result<(), Failure> {
+    let io_error = |e: io::Error| Failure::Io(format!("{}: {}", path.display(), e));
+
+    // the configured output directory may not exist yet
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(io_error)?;
+    }
+    fs::write(path, bytes).map_err(io_error)
+}
+
+fn write_output(output: Option<&str>, bytes: &[u8]) -> Result<(), Failure> {
+    match output {
+        Some(path) => write_file(Path::new(path), bytes),
+        None => io::stdout()
+            .write_all(bytes)
+            .map_err(|e| Failure::Io(e.to_string())),
+    }
+}
+
+fn parse_rect(text: &str) -> Result<Rect, Failure> {
+    let parts: Vec<u32> = text
+        .split(',')
+        .map(|p| p.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| Failure::Usage(message("cli.invalid_area", &[&text])))?;
+
+    match parts[..] {
+        [x, y, width, height] => Ok(Rect::new(x, y, width, height)),
+        _ => Err(Failure::Usage(message("cli.invalid_area", &[&text]))),
+    }
+}
+
+fn info(args: &Args) -> Result<(), Failure> {
+    let image = load(args.positional(0).unwrap())?;
+    println!("{}", image);
+
+    if args.flag("stats") {
+        let (pixels, stats) = image.decode_with_stats()?;
+        println!("\n{}", message("cli.image_data", &[&stats]));
+
+        let memory = image.memory_footprint() + pixels.memory_footprint();
+        println!("{}", message("cli.memory", &[&memory]));
+    }
+
+    Ok(())
+}
+
+fn palette(args: &Args) -> Result<(), Failure> {
+    let image = load(args.positional(0).unwrap())?;
+    let output = args.value("output");
+
+    let format_name = match (args.value("format"), output) {
+        (Some(f), _) => f,
+        (None, Some(path)) => Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("hex"),
+        (None, None) => "hex",
+    };
+    let format = match PaletteFormat::from_name(format_name) {
+        Some(f) => f,
+        None => {
+            return Err(Failure::Usage(message(
+                "cli.unknown_palette_format",
+                &[&format_name],
+            )))
+        }
+    };
+    let colors = args.parsed("colors")?.unwrap_or(8);
+
+    let bytes = image.export_palette(format, colors)?;
+    write_output(output, &bytes)
+}
+
+fn compare(args: &Args) -> Result<(), Failure> {
+    let expected = load(args.positional(0).unwrap())?;
+    let actual = load(args.positional(1).unwrap())?;
+
+    let mut options = CompareOptions::default();
+    options.anti_aliasing = args.flag("anti-aliasing");
+    if let Some(threshold) = args.parsed("threshold")? {
+        options.threshold = threshold;
+    }
+    for area in args.values("ignore") {
+        options.ignore.push(parse_rect(area)?);
+    }
+    for region in args.values("region") {
+        let (area, threshold) = match region.rsplit_once(':') {
+            Some(r) => r,
+            None => return Err(Failure::Usage(message("cli.invalid_region", &[&region]))),
+        };
+        options.regions.push(RegionThreshold {
+            rect: parse_rect(area)?,
+            threshold: threshold
+                .parse()
+                .map_err(|_| Failure::Usage(message("cli.invalid_region_threshold", &[&region])))?,
+        });
+    }
+
+    let expected = expected.decode()?;
+    let mut actual = actual.decode()?;
+    // screenshots from differently calibrated displays
+    if args.flag("match-histogram") {
+        match_histogram(&mut actual, &expected);
+    }
+
+    let (result, diff) = diff_image(&expected, &actual, &options)?;
+
+    if let Some(path) = args.value("diff") {
+        let image = PngImage::from_pixels(&diff, &EncodeOptions::default())?;
+        write_file(Path::new(path), &image.to_bytes())?;
+    }
+
+    if !result.is_match() {
+        let b = result.diff_bounds.unwrap();
+        return Err(Failure::Mismatch(message(
+            "cli.images_differ",
+            &[
+                &result.diff_pixels,
+                &result.total_pixels,
+                &b.width,
+                &b.height,
+                &b.x,
+                &b.y,
+            ],
+        )));
+    }
+
+    println!(
+        "{}",
+        message(
+            "cli.images_match",
+            &[&result.anti_aliased_pixels, &result.ignored_pixels]
+        )
+    );
+    Ok(())
+}
+
+fn optimize(args: &Args) -> Result<(), Failure> {
+    let input = args.positional(0).unwrap();
+    let output = match args.positional(1) {
+        Some(path) => PathBuf::from(path),
+        None => args
+            .derived_output(input)
+            .ok_or_else(|| Failure::Usage(message("cli.no_output", &[])))?,
+    };
+
+    let preset = match args.value("preset") {
+        Some(name) => Preset::from_name(name)
+            .ok_or_else(|| Failure::Usage(message("cli.unknown_preset", &[&name])))?,
+        None => Preset::Archive,
+    };
+    let mut options = TranscodeOptions::default();
+    options.encode = EncodeOptions::preset(preset);
+
+    let keep: Vec<String> = args.values("keep").map(|c| c.to_string()).collect();
+    // --keep on the command line beats strip = true in the config file
+    let keep_given = !keep.is_empty() && !args.configured("keep");
+    if args.flag("strip") && !(keep_given && args.configured("strip")) {
+        options.encode.metadata = MetadataPolicy::Strip;
+    } else if !keep.is_empty() {
+        options.encode.metadata = MetadataPolicy::KeepOnly(keep);
+    }
+
+    // read it all first so the output may overwrite the input
+    let bytes = fs::read(input).map_err(|e| Failure::Io(format!("{}: {}", input, e)))?;
+    let run = |options: &TranscodeOptions| {
+        let mut out = vec![];
+        transcode(bytes.as_slice(), &mut out, options)
+            .map(|_| out)
+            .map_err(|e| Failure::png(input, e))
+    };
+
+    // recompressing can lose to a better encoder, then only the metadata changes
+    let mut optimized = run(&options)?;
+    options.recompress = false;
+    let copied = run(&options)?;
+    if copied.len() < optimized.len() {
+        optimized = copied;
+    }
+    write_file(&output, &optimized)?;
+
+    println!(
+        "{}",
+        message(
+            "cli.optimized",
+            &[&output.display(), &bytes.len(), &optimized.len()]
+        )
+    );
+    Ok(())
+}
+
+fn completions(args: &Args) -> Result<(), Failure> {
+    let name = args.positional(0).unwrap();
+    let shell = Shell::from_name(name)
+        .ok_or_else(|| Failure::Usage(message("cli.unknown_shell", &[&name])))?;
+
+    print!("{}", generate::completions(shell, COMMANDS));
+    Ok(())
+}
+
+fn export_chunks(args: &Args) -> Result<(), Failure> {
+    let image = load(args.positional(0).unwrap())?;
+    let dir = args.positional(1).unwrap();
+    image.export_chunks(dir)?;
+
+    println!(
+        "{}",
+        message("cli.exported_chunks", &[&image.chunks.len(), &dir])
+    );
+    Ok(())
+}
+
+fn import_chunks(args: &Args) -> Result<(), Failure> {
+    let dir = args.positional(0).unwrap();
+    let image = PngImage::import_chunks(dir).map_err(|e| Failure::png(dir, e))?;
+    write_file(Path::new(args.positional(1).unwrap()), &image.to_bytes())
+}
+
+fn mangen(_: &Args) -> Result<(), Failure> {
+    print!("{}", generate::manpage(COMMANDS));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cli::parse, config::Config};
+    use whats_a_png::png::PNGChunk;
+    const IMAGE_PATH: &str = "./test.png";
+
+    const SAVE_DIR: &str = "./save_test/commands_optimize";
+
+    fn optimized_text(config: &str, extra: &[&str]) -> bool {
+        fs::create_dir_all(SAVE_DIR).unwrap();
+        let (input, output) = (
+            format!("{}/keep_in.png", SAVE_DIR),
+            format!("{}/keep_out.png", SAVE_DIR),
+        );
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image
+            .chunks
+            .insert(1, PNGChunk::new("tEXt", b"Title\0kept".to_vec()));
+        image.save_image(&input).unwrap();
+
+        let command = COMMANDS.iter().find(|c| c.name == "optimize").unwrap();
+        let config = Config::parse(Path::new("whats-a-png.toml"), config).unwrap();
+        let list: Vec<String> = [&input, &output, "--preset", "fastest"]
+            .iter()
+            .chain(extra)
+            .map(|a| a.to_string())
+            .collect();
+        let mut args = parse(command, &list).unwrap();
+        args.apply_config(command, &config);
+
+        optimize(&args).unwrap();
+        PngImage::new(&output).unwrap().get_chunk("tEXt").is_some()
+    }
+
+    #[test]
+    fn test_optimize_keep_beats_config_strip() {
+        let config = "[optimize]\nstrip = true";
+        assert!(!optimized_text(config, &[]));
+        assert!(optimized_text(config, &["--keep", "tEXt"]));
+        assert!(optimized_text(config, &["--no-strip"]));
+        assert!(!optimized_text(config, &["--keep", "tEXt", "--strip"]));
+    }
+
+    #[test]
+    fn test_optimize_output_dir() {
+        let dir = format!("{}/out_dir", SAVE_DIR);
+        let _ = fs::remove_dir_all(&dir);
+        let input = format!("{}/dir_in.png", SAVE_DIR);
+        fs::create_dir_all(SAVE_DIR).unwrap();
+        fs::copy(IMAGE_PATH, &input).unwrap();
+
+        let command = COMMANDS.iter().find(|c| c.name == "optimize").unwrap();
+        let config = Config::parse(
+            Path::new(&format!("{}/whats-a-png.toml", SAVE_DIR)),
+            "[output]\ndir = \"out_dir\"",
+        )
+        .unwrap();
+        let run = |list: &[&str]| {
+            let list: Vec<String> = list.iter().map(|a| a.to_string()).collect();
+            let mut args = parse(command, &list).unwrap();
+            args.apply_config(command, &config);
+            optimize(&args)
+        };
+
+        // an explicit output is written where it says, not under [output] dir
+        let explicit = format!("{}/dir_explicit.png", SAVE_DIR);
+        run(&[&input, &explicit, "--preset", "fastest"]).unwrap();
+        assert!(Path::new(&explicit).exists());
+        assert!(!Path::new(&dir).exists());
+
+        // a left out output takes the input's name in the output directory
+        run(&[&input, "--preset", "fastest"]).unwrap();
+        assert!(Path::new(&format!("{}/dir_in.png", dir)).exists());
+
+        // and without one there's nowhere to put it
+        let config = Config::parse(Path::new("whats-a-png.toml"), "").unwrap();
+        let mut args = parse(command, std::slice::from_ref(&input)).unwrap();
+        args.apply_config(command, &config);
+        assert!(matches!(optimize(&args), Err(Failure::Usage(_))));
+    }
+}
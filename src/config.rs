@@ -0,0 +1,285 @@
+// shared defaults from a whats-a-png.toml file
+//
+// each [command] table holds defaults for that command's options, named as on
+// the command line, and flags given there win over the file:
+//
+//   [compare]
+//   threshold = 0.05
+//   anti-aliasing = true
+//
+//   [optimize]
+//   preset = "web"
+//   keep = ["gAMA", "sRGB"]
+//
+//   [output]
+//   dir = "build/images"
+//
+// only the part of toml these need is understood: tables, strings, numbers,
+// booleans, single line arrays and comments.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use whats_a_png::png::locale::message;
+
+use crate::cli::{Command, Failure};
+
+pub const CONFIG_FILE: &str = "whats-a-png.toml";
+// section for settings that aren't tied to one command
+const OUTPUT_SECTION: &str = "output";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Setting {
+    Flag(bool),
+    // a scalar is a list of one
+    Values(Vec<String>),
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub path: PathBuf,
+    sections: HashMap<String, HashMap<String, Setting>>,
+}
+
+impl Config {
+    // the nearest whats-a-png.toml in the working directory or above it
+    pub fn find() -> Result<Option<Config>, Failure> {
+        let cwd = env::current_dir().map_err(|e| Failure::Io(e.to_string()))?;
+
+        for dir in cwd.ancestors() {
+            let path = dir.join(CONFIG_FILE);
+            if path.is_file() {
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| Failure::Io(format!("{}: {}", path.display(), e)))?;
+                return Config::parse(&path, &text).map(Some);
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<Config, Failure> {
+        let mut config = Config {
+            path: path.to_path_buf(),
+            ..Default::default()
+        };
+        let mut section = None;
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |key: &str, arg: &str| {
+                Failure::Config(message(key, &[&path.display(), &(i + 1), &arg]))
+            };
+
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim();
+                config.sections.entry(name.to_string()).or_default();
+                section = Some(name.to_string());
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), v.trim()),
+                None => return Err(error("config.bad_line", line)),
+            };
+            let setting = parse_setting(value).ok_or_else(|| error("config.bad_value", value))?;
+
+            match &section {
+                Some(name) => {
+                    let table = config.sections.get_mut(name).unwrap();
+                    table.insert(unquote(key).to_string(), setting);
+                }
+                None => return Err(error("config.outside_section", key)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // catches typos before they silently do nothing
+    pub fn validate(&self, commands: &[Command]) -> Result<(), Failure> {
+        for (name, table) in &self.sections {
+            for (key, setting) in table {
+                let known = match commands.iter().find(|c| c.name == name) {
+                    Some(command) => command.options.iter().any(|o| {
+                        o.name == key
+                            && matches!(
+                                (o.value, setting),
+                                (None, Setting::Flag(_)) | (Some(_), Setting::Values(_))
+                            )
+                    }),
+                    None => name == OUTPUT_SECTION && key == "dir",
+                };
+
+                if !known {
+                    return Err(Failure::Config(message(
+                        "config.unknown_setting",
+                        &[&self.path.display(), &key, &name],
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn section(&self, name: &str) -> Option<&HashMap<String, Setting>> {
+        self.sections.get(name)
+    }
+
+    // relative to the config file, so it means the same from every directory
+    pub fn output_dir(&self) -> Option<PathBuf> {
+        match self.section(OUTPUT_SECTION)?.get("dir")? {
+            Setting::Values(v) => {
+                let base = self.path.parent().unwrap_or(Path::new("."));
+                Some(base.join(v.last()?))
+            }
+            Setting::Flag(_) => None,
+        }
+    }
+}
+
+// a # outside of a string starts a comment
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') => return &line[..i],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+fn unquote(text: &str) -> &str {
+    let quoted = text.len() >= 2
+        && ((text.starts_with('"') && text.ends_with('"'))
+            || (text.starts_with('\'') && text.ends_with('\'')));
+
+    if quoted {
+        &text[1..text.len() - 1]
+    } else {
+        text
+    }
+}
+
+fn parse_scalar(text: &str) -> Option<String> {
+    if let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return Some(
+            inner
+                .replace("\\\"", "\"")
+                .replace("\\t", "\t")
+                .replace("\\n", "\n")
+                .replace("\\\\", "\\"),
+        );
+    }
+    if let Some(inner) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        return Some(inner.to_string());
+    }
+
+    // bare numbers, toml allows _ between digits
+    let number = text.replace('_', "");
+    number.parse::<f64>().ok().map(|_| number)
+}
+
+fn parse_setting(text: &str) -> Option<Setting> {
+    match text {
+        "true" => return Some(Setting::Flag(true)),
+        "false" => return Some(Setting::Flag(false)),
+        _ => (),
+    }
+
+    let inner = match text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        Some(inner) => inner,
+        None => return parse_scalar(text).map(|v| Setting::Values(vec![v])),
+    };
+
+    // split on commas outside of strings, a trailing comma is allowed
+    let mut items = vec![];
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, ',') => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    items.push(&inner[start..]);
+
+    items
+        .into_iter()
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(parse_scalar)
+        .collect::<Option<_>>()
+        .map(Setting::Values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::COMMANDS;
+
+    fn parse(text: &str) -> Result<Config, Failure> {
+        Config::parse(Path::new("team/whats-a-png.toml"), text)
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse(
+            "# shared settings\n\
+             [compare]\n\
+             threshold = 0.05 # a little looser\n\
+             anti-aliasing = true\n\
+             ignore = [\"0,0,10,10\", '5,5,1,1',]\n\
+             [output]\n\
+             dir = \"build/#images\"\n",
+        )
+        .unwrap();
+        config.validate(COMMANDS).unwrap();
+
+        let compare = config.section("compare").unwrap();
+        assert_eq!(
+            compare["threshold"],
+            Setting::Values(vec!["0.05".to_string()])
+        );
+        assert_eq!(compare["anti-aliasing"], Setting::Flag(true));
+        assert_eq!(
+            compare["ignore"],
+            Setting::Values(vec!["0,0,10,10".to_string(), "5,5,1,1".to_string()])
+        );
+        assert_eq!(
+            config.output_dir(),
+            Some(PathBuf::from("team/build/#images"))
+        );
+    }
+
+    #[test]
+    fn test_config_errors() {
+        assert!(parse("threshold = 1").is_err());
+        assert!(parse("[compare]\nthreshold").is_err());
+        assert!(parse("[compare]\nthreshold = nope").is_err());
+
+        // unknown options and a flag given a value only show up against the commands
+        for text in ["[compare]\nthreshhold = 1", "[compare]\nanti-aliasing = 1"] {
+            let failure = parse(text).unwrap().validate(COMMANDS).unwrap_err();
+            assert_eq!(failure.code(), 103);
+        }
+    }
+}
@@ -0,0 +1,321 @@
+// shell completions and the man page, generated from the command table so
+// they can't drift from what the parser accepts
+
+use crate::cli::{self, Command};
+use whats_a_png::png::{locale::message, plugin::Failure, PngError};
+
+const BIN: &str = "whats-a-png";
+// options every command accepts
+const GLOBAL_OPTIONS: &[&str] = &["json"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "fish" => Some(Shell::Fish),
+            _ => None,
+        }
+    }
+}
+
+pub fn completions(shell: Shell, commands: &[Command]) -> String {
+    match shell {
+        Shell::Bash => bash(commands),
+        Shell::Zsh => zsh(commands),
+        Shell::Fish => fish(commands),
+    }
+}
+
+fn bash(commands: &[Command]) -> String {
+    let names: Vec<&str> = commands.iter().map(|c| c.name).collect();
+
+    let mut script = format!(
+        "_whats_a_png() {{\n    \
+             local cur=${{COMP_WORDS[COMP_CWORD]}}\n    \
+             local opts=\"{}\"\n\n    \
+             if [ \"$COMP_CWORD\" -eq 1 ]; then\n        \
+                 COMPREPLY=($(compgen -W \"{} help\" -- \"$cur\"))\n        \
+                 return\n    \
+             fi\n\n    \
+             case \"${{COMP_WORDS[1]}}\" in\n",
+        GLOBAL_OPTIONS
+            .iter()
+            .map(|o| format!("--{}", o))
+            .collect::<Vec<_>>()
+            .join(" "),
+        names.join(" ")
+    );
+
+    for command in commands {
+        let options: Vec<String> = command
+            .options
+            .iter()
+            .map(|o| format!("--{}", o.name))
+            .collect();
+        script += &format!(
+            "        {}) opts=\"$opts {}\" ;;\n",
+            command.name,
+            options.join(" ")
+        );
+    }
+    script += &format!(
+        "        help) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;\n",
+        names.join(" ")
+    );
+
+    script += "    esac\n\n    \
+                   if [[ $cur == -* ]]; then\n        \
+                       COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n    \
+                   else\n        \
+                       COMPREPLY=($(compgen -f -- \"$cur\"))\n    \
+                   fi\n\
+               }\n\n";
+    script + &format!("complete -o filenames -F _whats_a_png {}\n", BIN)
+}
+
+// text inside a single quoted zsh _arguments spec
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh(commands: &[Command]) -> String {
+    let mut script = format!(
+        "#compdef {}\n\n_whats_a_png() {{\n  local -a commands\n  commands=(\n",
+        BIN
+    );
+    for command in commands {
+        script += &format!(
+            "    '{}:{}'\n",
+            command.name,
+            zsh_escape(&cli::about(command))
+        );
+    }
+    script += "  )\n\n  \
+                 if (( CURRENT == 2 )); then\n    \
+                     _describe 'command' commands\n    \
+                     return\n  \
+                 fi\n\n  \
+                 local cmd=$words[2]\n  \
+                 shift words\n  \
+                 (( CURRENT-- ))\n\n  \
+                 case $cmd in\n";
+
+    for command in commands {
+        script += &format!("    {})\n      _arguments \\\n", command.name);
+        for o in command.options {
+            let help = zsh_escape(&cli::help(command, o.name, o.help));
+            match o.value {
+                Some("FILE") => {
+                    script += &format!("        '*--{}=[{}]:file:_files' \\\n", o.name, help)
+                }
+                Some(v) => script += &format!("        '*--{}=[{}]:{}: ' \\\n", o.name, help, v),
+                None => script += &format!("        '--{}[{}]' \\\n", o.name, help),
+            }
+        }
+        for o in GLOBAL_OPTIONS {
+            script += &format!("        '--{}' \\\n", o);
+        }
+        script += "        '*:file:_files'\n      ;;\n";
+    }
+    script += "    help)\n      _describe 'command' commands\n      ;;\n  esac\n}\n\n";
+
+    script + "_whats_a_png \"$@\"\n"
+}
+
+fn fish(commands: &[Command]) -> String {
+    let quote = |text: &str| format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"));
+    let mut script = format!("complete -c {} -f\n", BIN);
+
+    for o in GLOBAL_OPTIONS {
+        script += &format!("complete -c {} -l {}\n", BIN, o);
+    }
+    for command in commands {
+        script += &format!(
+            "complete -c {} -n __fish_use_subcommand -a {} -d {}\n",
+            BIN,
+            command.name,
+            quote(&cli::about(command))
+        );
+    }
+
+    for command in commands {
+        let seen = format!("'__fish_seen_subcommand_from {}'", command.name);
+        if !command.positionals.is_empty() {
+            script += &format!("complete -c {} -n {} -F\n", BIN, seen);
+        }
+        for o in command.options {
+            let takes_value = if o.value.is_some() { " -r" } else { "" };
+            script += &format!(
+                "complete -c {} -n {} -l {}{} -d {}\n",
+                BIN,
+                seen,
+                o.name,
+                takes_value,
+                quote(&cli::help(command, o.name, o.help))
+            );
+        }
+    }
+
+    script
+}
+
+// sets the roffed --name in bold wherever it appears in text
+fn flag(text: &str, name: &str) -> String {
+    let plain = roff(&format!("--{}", name));
+    text.replace(&plain, &format!("\\fB{}\\fR", plain))
+}
+
+// text for roff, which treats backslashes and leading dots specially
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        return format!("\\&{}", text);
+    }
+    text
+}
+
+// exit statuses and what leads to them, taken from Failure::exit_code so
+// the page follows the code
+fn exit_statuses() -> Vec<(i32, Vec<&'static str>)> {
+    let failures = [
+        Failure::Mismatch(String::new()),
+        Failure::Usage(String::new()),
+        Failure::Config(String::new()),
+        Failure::Io(String::new()),
+        Failure::Png(PngError::InvalidFileType, String::new()),
+    ];
+    let mut statuses = vec![(0, vec!["man.exit_success"])];
+    for failure in &failures {
+        // no wildcard, so a new variant has to be described here
+        let key = match failure {
+            Failure::Mismatch(_) => "man.exit_mismatch",
+            Failure::Usage(_) => "man.exit_usage",
+            Failure::Config(_) => "man.exit_config",
+            Failure::Io(_) => "man.exit_io",
+            Failure::Png(..) => "man.exit_png",
+        };
+        let status = failure.exit_code();
+        match statuses.iter_mut().find(|(s, _)| *s == status) {
+            Some((_, keys)) => keys.push(key),
+            None => statuses.push((status, vec![key])),
+        }
+    }
+    statuses.sort_by_key(|(status, _)| *status);
+    statuses
+}
+
+pub fn manpage(commands: &[Command]) -> String {
+    let mut page = format!(
+        ".TH WHATS\\-A\\-PNG 1 \"\" \"{} {}\"\n\
+         .SH NAME\n{} \\- {}\n\
+         .SH SYNOPSIS\n\
+         .B {}\n\
+         [\\fB\\-\\-json\\fR] \\fIcommand\\fR [\\fIarguments\\fR]\n\
+         .SH DESCRIPTION\n{}\n\
+         .SH COMMANDS\n",
+        BIN,
+        env!("CARGO_PKG_VERSION"),
+        roff(BIN),
+        roff(env!("CARGO_PKG_DESCRIPTION")),
+        roff(BIN),
+        flag(&roff(&message("man.description", &[&"--json"])), "json"),
+    );
+
+    for command in commands {
+        let mut synopsis = format!("{} {}", BIN, command.name);
+        for p in command.positionals {
+            synopsis += &format!(" <{}>", p.name);
+        }
+        page += &format!(
+            ".SS \"{}\"\n{}\n",
+            roff(&synopsis),
+            roff(&cli::about(command))
+        );
+
+        for p in command.positionals {
+            page += &format!(
+                ".TP\n\\fI{}\\fR\n{}\n",
+                roff(p.name),
+                roff(&cli::help(command, p.name, p.help))
+            );
+        }
+        for o in command.options {
+            let value = o
+                .value
+                .map(|v| format!(" \\fI{}\\fR", roff(v)))
+                .unwrap_or_default();
+            page += &format!(
+                ".TP\n\\fB\\-\\-{}\\fR{}\n{}\n",
+                roff(o.name),
+                value,
+                roff(&cli::help(command, o.name, o.help))
+            );
+        }
+    }
+
+    page += &format!(
+        ".SH ENVIRONMENT\n\
+         .TP\n\\fBWHATS_A_PNG_CATALOG\\fR\n{}\n\
+         .SH FILES\n\
+         .TP\n\\fIwhats\\-a\\-png.toml\\fR\n{}\n\
+         .SH EXIT STATUS\n",
+        roff(&message("man.catalog", &[])),
+        roff(&message("man.config", &[])),
+    );
+    for (status, keys) in exit_statuses() {
+        let text: Vec<String> = keys.iter().map(|k| message(k, &[])).collect();
+        page += &format!(".TP\n{}\n{}\n", status, roff(&text.join("; ")));
+    }
+
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::COMMANDS;
+
+    #[test]
+    fn test_completions_cover_commands() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+            let script = completions(shell, COMMANDS);
+            for command in COMMANDS {
+                assert!(
+                    script.contains(command.name),
+                    "{:?} {}",
+                    shell,
+                    command.name
+                );
+                for o in command.options {
+                    assert!(script.contains(o.name), "{:?} --{}", shell, o.name);
+                }
+            }
+        }
+        assert_eq!(Shell::from_name("ZSH"), Some(Shell::Zsh));
+        assert_eq!(Shell::from_name("cmd"), None);
+    }
+
+    #[test]
+    fn test_manpage() {
+        let page = manpage(COMMANDS);
+        assert!(page.starts_with(".TH WHATS\\-A\\-PNG 1"));
+        assert!(page.contains(".TP\n\\fB\\-\\-anti\\-aliasing\\fR\n"));
+        assert!(page.contains("\\fB\\-\\-colors\\fR \\fIN\\fR\n"));
+        assert!(page.contains("\\fB\\-\\-json\\fR"));
+        assert!(!page.contains("man."));
+        assert!(page.contains(".TP\n2\nbad arguments or an unusable catalog file; "));
+        assert!(page.contains(".TP\n4\nthe png is broken"));
+        assert_eq!(roff(".hidden \\"), "\\&.hidden \\e");
+    }
+}
@@ -0,0 +1,397 @@
+// just enough json for machine readable output and the manifests the
+// library writes itself, objects keep insertion order
+
+use std::fmt::{Display, Formatter};
+
+use crate::png::{locale::message, PngError};
+
+// deepest nesting of arrays and objects parse accepts, so a hostile manifest
+// can't run the recursive parser out of stack
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn object(fields: Vec<(&str, Value)>) -> Self {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Value, PngError> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+
+        parser.skip_whitespace();
+        if parser.pos != text.len() {
+            return Err(parser.error());
+        }
+        Ok(value)
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    // arrays and objects open around pos
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> PngError {
+        PngError::InvalidOperation(message("json.invalid", &[&self.pos]))
+    }
+
+    fn enter(&mut self) -> Result<(), PngError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(PngError::InvalidOperation(message(
+                "json.too_deep",
+                &[&self.pos, &MAX_DEPTH],
+            )));
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, literal: &str) -> bool {
+        if self.text[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), PngError> {
+        self.skip_whitespace();
+        match self.eat(literal) {
+            true => Ok(()),
+            false => Err(self.error()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, PngError> {
+        self.skip_whitespace();
+
+        match self.peek().ok_or_else(|| self.error())? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => self.string().map(Value::String),
+            _ if self.eat("null") => Ok(Value::Null),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, PngError> {
+        let rest = &self.text[self.pos..];
+        // json numbers start with a digit or a minus, never + or .
+        let digits = rest.strip_prefix('-').unwrap_or(rest);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error());
+        }
+
+        let len = rest
+            .find(|c: char| !matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            .unwrap_or(rest.len());
+
+        let n = rest[..len].parse().map_err(|_| self.error())?;
+        self.pos += len;
+        Ok(Value::Number(n))
+    }
+
+    fn hex4(&mut self) -> Result<u32, PngError> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error())?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, PngError> {
+        self.expect("\"")?;
+        let mut out = String::new();
+
+        loop {
+            let rest = &self.text[self.pos..];
+            let stop = rest.find(['"', '\\']).ok_or_else(|| self.error())?;
+            out += &rest[..stop];
+            self.pos += stop + 1;
+
+            if rest.as_bytes()[stop] == b'"' {
+                return Ok(out);
+            }
+
+            let escape = self.peek().ok_or_else(|| self.error())?;
+            self.pos += 1;
+            match escape {
+                b'"' => out.push('"'),
+                b'\\' => out.push('\\'),
+                b'/' => out.push('/'),
+                b'b' => out.push('\u{8}'),
+                b'f' => out.push('\u{c}'),
+                b'n' => out.push('\n'),
+                b'r' => out.push('\r'),
+                b't' => out.push('\t'),
+                b'u' => {
+                    let mut code = self.hex4()?;
+                    // characters outside the basic plane come as a surrogate
+                    // pair, a high surrogate on its own isn't a character
+                    if (0xd800..0xdc00).contains(&code) {
+                        if !self.eat("\\u") {
+                            return Err(self.error());
+                        }
+                        let low = self.hex4()?;
+                        if !(0xdc00..0xe000).contains(&low) {
+                            return Err(self.error());
+                        }
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                    out.push(char::from_u32(code).ok_or_else(|| self.error())?);
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, PngError> {
+        self.expect("[")?;
+        self.enter()?;
+        let mut items = vec![];
+
+        self.skip_whitespace();
+        if self.eat("]") {
+            self.depth -= 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat("]") {
+                self.depth -= 1;
+                return Ok(Value::Array(items));
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, PngError> {
+        self.expect("{")?;
+        self.enter()?;
+        let mut fields = vec![];
+
+        self.skip_whitespace();
+        if self.eat("}") {
+            self.depth -= 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(":")?;
+            fields.push((key, self.value()?));
+
+            self.skip_whitespace();
+            if self.eat("}") {
+                self.depth -= 1;
+                return Ok(Value::Object(fields));
+            }
+            self.expect(",")?;
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+macro_rules! from_number {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Self {
+                Value::Number(n as f64)
+            }
+        })*
+    };
+}
+
+from_number!(u8, u16, u32, u64, i32, i64, usize, f32, f64);
+
+fn write_string(f: &mut Formatter<'_>, s: &str) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            // json has no nan or infinity
+            Value::Number(n) if !n.is_finite() => f.write_str("null"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_output() {
+        let value = Value::object(vec![
+            ("code", 8u16.into()),
+            ("message", "bad \"data\"\n\u{1}".into()),
+            ("ratio", 0.5.into()),
+            ("tags", Value::Array(vec![true.into(), Value::Null])),
+        ]);
+
+        assert_eq!(
+            value.to_string(),
+            r#"{"code":8,"message":"bad \"data\"\n\u0001","ratio":0.5,"tags":[true,null]}"#
+        );
+        assert_eq!(value.get("code"), Some(&Value::Number(8.0)));
+    }
+
+    #[test]
+    fn test_json_parse() {
+        let text = r#" {"a": [1, -2.5e1, true, null], "b": "x\"\u00e9\ud83d\ude00", "c": {}} "#;
+        let value = Value::parse(text).unwrap();
+
+        assert_eq!(
+            value.get("a").and_then(|a| a.as_array()).unwrap()[1].as_f64(),
+            Some(-25.0)
+        );
+        assert_eq!(value.get("b").and_then(|b| b.as_str()), Some("x\"é😀"));
+        assert_eq!(Value::parse(&value.to_string()).unwrap(), value);
+
+        for bad in ["", "{", "[1,]", "{\"a\" 1}", "\"open", "1 2", "tru"] {
+            assert!(Value::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_json_parse_strict() {
+        assert_eq!(Value::parse("-0.5e+2").unwrap(), Value::Number(-50.0));
+        for bad in ["+1", "[+1]", ".5", "-.5", "-+1"] {
+            assert!(Value::parse(bad).is_err(), "{}", bad);
+        }
+
+        // a high surrogate needs a low one right after it
+        for bad in [
+            r#""\ud83d""#,
+            r#""\ud83dx""#,
+            r#""\ud83d\u0041""#,
+            r#""\ude00""#,
+        ] {
+            assert!(Value::parse(bad).is_err(), "{}", bad);
+        }
+
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Value::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Value::parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+}
@@ -0,0 +1,3 @@
+#[cfg(feature = "json")]
+pub mod json;
+pub mod png;
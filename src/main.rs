@@ -1,12 +1,101 @@
-mod png;
+mod cli;
+mod commands;
+mod config;
+mod generate;
+mod plugins;
 
-use png::PngImage;
+use std::{env, fs, process::exit, sync::Arc};
 
-fn main() {
-    let img = match PngImage::new("./test.png") {
-        Ok(image) => image,
-        Err(e) => panic!("error: {:?}", e.get_message()),
+use cli::Failure;
+use commands::COMMANDS;
+use config::Config;
+use whats_a_png::png::{
+    locale::{message, set_locale_provider, Catalog},
+    plugin::JSON_FLAG,
+};
+
+// path of a "key = template" message catalog to use instead of english
+const CATALOG_VAR: &str = "WHATS_A_PNG_CATALOG";
+
+fn fail(failure: Failure, json: bool) -> ! {
+    if json {
+        println!("{}", failure.to_json());
+    } else {
+        eprintln!("{}", message("cli.error", &[&failure.message()]));
+    }
+    exit(failure.exit_code());
+}
+
+fn load_catalog() -> Result<(), Failure> {
+    let path = match std::env::var(CATALOG_VAR) {
+        Ok(p) => p,
+        Err(_) => return Ok(()),
     };
 
-    img.save_image("./save_test/test_copy-main.png").unwrap();
+    let text = fs::read_to_string(&path).map_err(|e| Failure::Io(format!("{}: {}", path, e)))?;
+    let catalog = Catalog::parse(&text).map_err(|e| Failure::png(&path, e))?;
+    set_locale_provider(Some(Arc::new(catalog)));
+
+    Ok(())
+}
+
+fn usage() -> String {
+    let path_var = env::var_os("PATH").unwrap_or_default();
+    cli::usage(COMMANDS, &plugins::discover(&path_var))
+}
+
+// --json may appear anywhere cli::take_flag takes it from, json says whether it did
+fn run(args: &[String], json: bool) -> Result<(), Failure> {
+    load_catalog()?;
+
+    let name = match args.first() {
+        Some(n) => n.as_str(),
+        None => return Err(Failure::Usage(usage())),
+    };
+
+    if name == "help" || name == "--help" {
+        match args
+            .get(1)
+            .and_then(|n| COMMANDS.iter().find(|c| c.name == n))
+        {
+            Some(command) => print!("{}", cli::command_help(command)),
+            None => print!("{}", usage()),
+        }
+        return Ok(());
+    }
+
+    let command = match COMMANDS.iter().find(|c| c.name == name) {
+        Some(c) => c,
+        None => {
+            let path_var = env::var_os("PATH").unwrap_or_default();
+            if let Some(program) = plugins::find(name, &path_var) {
+                // the plugin reports its own failures
+                exit(plugins::run(&program, &args[1..], json)?);
+            }
+
+            return Err(Failure::Usage(format!(
+                "{}\n\n{}",
+                message("cli.unknown_command", &[&name]),
+                usage()
+            )));
+        }
+    };
+
+    let mut parsed = cli::parse(command, &args[1..])?;
+    if command.reads_config {
+        if let Some(config) = Config::find()? {
+            config.validate(COMMANDS)?;
+            parsed.apply_config(command, &config);
+        }
+    }
+    (command.run)(&parsed)
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let json = cli::take_flag(COMMANDS, &mut args, JSON_FLAG);
+
+    if let Err(failure) = run(&args, json) {
+        fail(failure, json);
+    }
 }
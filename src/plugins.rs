@@ -0,0 +1,97 @@
+// whats-a-png-<name> executables on the PATH, run as the <name> subcommand
+
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use whats_a_png::png::plugin::{JSON_FLAG, PLUGIN_PREFIX};
+
+use crate::cli::Failure;
+
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+// the first match wins, like the shell
+pub fn find(name: &str, path_var: &OsStr) -> Option<PathBuf> {
+    let file = format!("{}{}{}", PLUGIN_PREFIX, name, env::consts::EXE_SUFFIX);
+
+    env::split_paths(path_var)
+        .map(|dir| dir.join(&file))
+        .find(|candidate| is_executable(candidate))
+}
+
+// names of every plugin on the path, sorted
+pub fn discover(path_var: &OsStr) -> Vec<String> {
+    let mut names: Vec<String> = env::split_paths(path_var)
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_executable(&entry.path()))
+        .filter_map(|entry| {
+            let file = entry.file_name().into_string().ok()?;
+            let name = file.strip_prefix(PLUGIN_PREFIX)?;
+            let name = name.strip_suffix(env::consts::EXE_SUFFIX).unwrap_or(name);
+            (!name.is_empty()).then(|| name.to_string())
+        })
+        .collect();
+
+    names.sort();
+    names.dedup();
+    names
+}
+
+// runs the plugin with the terminal passed through, returning its exit code
+pub fn run(program: &Path, args: &[String], json: bool) -> Result<i32, Failure> {
+    // in front, after a -- it would be an argument
+    let mut command = process::Command::new(program);
+    if json {
+        command.arg(JSON_FLAG);
+    }
+    command.args(args);
+
+    let status = command
+        .status()
+        .map_err(|e| Failure::Io(format!("{}: {}", program.display(), e)))?;
+
+    // killed by a signal, report it like a shell would
+    Ok(status.code().unwrap_or(128))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_discover_plugins() {
+        let dir = env::temp_dir().join(format!("whats-a-png-plugins-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let script = dir.join("whats-a-png-hello");
+        fs::write(&script, "#!/bin/sh\nexit 7\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        // not executable, so not a plugin
+        fs::write(dir.join("whats-a-png-notes"), "").unwrap();
+
+        let path_var = env::join_paths([Path::new("/nonexistent"), &dir]).unwrap();
+        assert_eq!(discover(&path_var), ["hello"]);
+        assert_eq!(find("hello", &path_var), Some(script.clone()));
+        assert_eq!(find("notes", &path_var), None);
+        assert_eq!(run(&script, &[], false).unwrap(), 7);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -4,7 +4,82 @@ use std::{
     io::{Cursor, Read, Seek, SeekFrom, Write},
 };
 
+#[cfg(feature = "ops")]
+pub mod adjust;
+#[doc(hidden)]
+pub mod checksum;
+#[cfg(feature = "chunk-files")]
+pub mod chunkdir;
+#[cfg(feature = "ops")]
+pub mod compare;
+#[cfg(feature = "codec")]
+mod decode;
+#[cfg(feature = "ops")]
+pub mod deepzoom;
+#[cfg(feature = "codec")]
+mod deflate;
+pub mod diagnostics;
+#[cfg(feature = "codec")]
+#[doc(hidden)]
+pub mod encode;
+#[cfg(feature = "codec")]
+pub mod filter;
+#[cfg(feature = "ops")]
+#[doc(hidden)]
+pub mod history;
+#[cfg(feature = "codec")]
+mod inflate;
+#[cfg(feature = "layers")]
+pub mod layers;
+pub mod locale;
+pub mod memory;
+#[cfg(feature = "ops")]
+pub mod palette;
+pub mod payload;
+#[cfg(feature = "codec")]
+#[doc(hidden)]
+pub mod pixels;
+#[cfg(feature = "cli")]
+pub mod plugin;
+pub mod prelude;
+#[cfg(feature = "provenance")]
+pub mod provenance;
+#[cfg(feature = "ops")]
+#[doc(hidden)]
+pub mod redact;
+#[cfg(feature = "codec")]
+pub mod region;
+#[doc(hidden)]
+pub mod session;
+#[cfg(feature = "codec")]
+#[doc(hidden)]
+pub mod stats;
+#[cfg(all(feature = "ops", any(test, feature = "test-util")))]
+pub mod testing;
+#[cfg(feature = "codec")]
+#[doc(hidden)]
+pub mod transcode;
+
+use locale::message;
+
+// the hidden modules are reachable through these
+pub use session::{ChunkEdit, EditSession};
+#[cfg(feature = "codec")]
+pub use {
+    encode::{EncodeOptions, FilterType, Preset},
+    filter::FilterStrategy,
+    pixels::{PixelBuffer, Rect, MAX_PIXELS},
+    stats::{BlockCounts, CompressionStats},
+    transcode::{transcode, MetadataPolicy, TranscodeOptions},
+};
+#[cfg(feature = "ops")]
+pub use {
+    history::{PixelHistory, DEFAULT_TILE_SIZE},
+    redact::RedactStyle,
+};
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum PngError {
     InvalidFileType,
     InvalidChunk,
@@ -13,28 +88,67 @@ pub enum PngError {
     SaveOperationFailed,
     InvalidChunkSize,
     InvalidPngInfo(String),
+    InvalidImageData(String),
+    InvalidOperation(String),
+    StreamFailed(String),
 }
 
 impl PngError {
+    // stable numbers for scripts to branch on, never renumber or reuse them
+    pub fn code(&self) -> u16 {
+        match self {
+            PngError::InvalidFileType => 1,
+            PngError::InvalidChunk => 2,
+            PngError::InvalidChunkType(_) => 3,
+            PngError::InvalidChunkCrc(_) => 4,
+            PngError::SaveOperationFailed => 5,
+            PngError::InvalidChunkSize => 6,
+            PngError::InvalidPngInfo(_) => 7,
+            PngError::InvalidImageData(_) => 8,
+            PngError::InvalidOperation(_) => 9,
+            PngError::StreamFailed(_) => 10,
+        }
+    }
+
+    // the code as a word, also the error.<name> catalog key of the message
+    pub fn name(&self) -> &'static str {
+        match self {
+            PngError::InvalidFileType => "invalid_file_type",
+            PngError::InvalidChunk => "invalid_chunk",
+            PngError::InvalidChunkType(_) => "invalid_chunk_type",
+            PngError::InvalidChunkCrc(_) => "invalid_chunk_crc",
+            PngError::SaveOperationFailed => "save_failed",
+            PngError::InvalidChunkSize => "invalid_chunk_size",
+            PngError::InvalidPngInfo(_) => "invalid_png_info",
+            PngError::InvalidImageData(_) => "invalid_image_data",
+            PngError::InvalidOperation(_) => "invalid_operation",
+            PngError::StreamFailed(_) => "stream_failed",
+        }
+    }
+
     pub fn get_message(&self) -> String {
         match self {
-            PngError::InvalidFileType => "Invalid file type".to_string(),
-            PngError::InvalidChunk => "Invalid chunk".to_string(),
-            PngError::InvalidChunkType(s) => format!("Invalid chunk type: {}", s),
-            PngError::InvalidChunkCrc(s) => format!("Invalid chunk crc: {}", s),
-            PngError::SaveOperationFailed => "Save operation failed".to_string(),
-            PngError::InvalidChunkSize => "Invalid chunk size".to_string(),
-            PngError::InvalidPngInfo(s) => format!("Invalid png info: {}", s),
+            PngError::InvalidFileType => message("error.invalid_file_type", &[]),
+            PngError::InvalidChunk => message("error.invalid_chunk", &[]),
+            PngError::InvalidChunkType(s) => message("error.invalid_chunk_type", &[s]),
+            PngError::InvalidChunkCrc(s) => message("error.invalid_chunk_crc", &[s]),
+            PngError::SaveOperationFailed => message("error.save_failed", &[]),
+            PngError::InvalidChunkSize => message("error.invalid_chunk_size", &[]),
+            PngError::InvalidPngInfo(s) => message("error.invalid_png_info", &[s]),
+            PngError::InvalidImageData(s) => message("error.invalid_image_data", &[s]),
+            PngError::InvalidOperation(s) => message("error.invalid_operation", &[s]),
+            PngError::StreamFailed(s) => message("error.stream_failed", &[s]),
         }
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct PngImage {
     pub info: PNGInfo,
     pub chunks: Vec<PNGChunk>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PNGChunk {
     pub size: u32,
     pub chunk_type: String,
@@ -42,6 +156,7 @@ pub struct PNGChunk {
     pub crc: u32,
 }
 
+#[derive(Debug, Clone)]
 pub struct PNGInfo {
     pub width: u32,
     pub height: u32,
@@ -54,9 +169,47 @@ pub struct PNGInfo {
 
 const PNG_SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
 
+impl PNGChunk {
+    pub fn new(chunk_type: &str, data: Vec<u8>) -> Self {
+        // crc covers the chunk type and the chunk data but not the size
+        let mut crc = checksum::Crc32::new();
+        crc.update(chunk_type.as_bytes());
+        crc.update(&data);
+
+        PNGChunk {
+            size: data.len() as u32,
+            chunk_type: chunk_type.to_string(),
+            data,
+            crc: crc.finish(),
+        }
+    }
+
+    pub fn is_critical(&self) -> bool {
+        is_critical_type(&self.chunk_type)
+    }
+}
+
+impl PNGInfo {
+    pub fn to_chunk(&self) -> PNGChunk {
+        let mut data = Vec::with_capacity(13);
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.push(self.bit_depth);
+        data.push(self.color_type);
+        data.push(self.compression_method);
+        data.push(self.filter_method);
+        data.push(self.interlace_method);
+        PNGChunk::new("IHDR", data)
+    }
+}
+
 impl PngImage {
     pub fn new(path: &str) -> Result<Self, PngError> {
-        let mut data = Cursor::new(read_image_data(path));
+        Self::from_bytes(read_image_data(path))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, PngError> {
+        let mut data = Cursor::new(bytes);
 
         // check file type
         if Self::check_file_type(&mut data).is_err() {
@@ -67,29 +220,17 @@ impl PngImage {
         let mut is_proccessing_chunk = true;
 
         while is_proccessing_chunk {
-            let chunk_size = match Self::get_chunk_size(&mut data) {
-                Ok(size) => size,
-                Err(e) => return Err(e),
-            };
+            let chunk_size = Self::get_chunk_size(&mut data)?;
 
-            let chunk_type = match Self::get_chunk_type(&mut data) {
-                Ok(s) => s,
-                Err(e) => return Err(e),
-            };
+            let chunk_type = Self::get_chunk_type(&mut data)?;
 
-            let chunk_data = match Self::get_chunk_data(&mut data, chunk_size) {
-                Ok(data) => data,
-                Err(e) => return Err(e),
-            };
+            let chunk_data = Self::get_chunk_data(&mut data, chunk_size)?;
 
-            let chunk_crc = match Self::get_chunk_crc(&mut data) {
-                Ok(crc) => crc,
-                Err(e) => return Err(e),
-            };
+            let chunk_crc = Self::get_chunk_crc(&mut data)?;
 
             let chunk = PNGChunk {
                 size: chunk_size,
-                chunk_type: chunk_type,
+                chunk_type,
                 data: chunk_data,
                 crc: chunk_crc,
             };
@@ -102,22 +243,30 @@ impl PngImage {
         }
 
         // extract image properties from header chunk
-        let png_info = match Self::get_png_info(&chunks[0]) {
-            Ok(info) => info,
-            Err(e) => return Err(e),
-        };
+        let png_info = Self::get_png_info(&chunks[0])?;
 
         Ok(PngImage {
             info: png_info,
-            chunks: chunks,
+            chunks,
         })
     }
 
+    pub fn get_chunk(&self, chunk_type: &str) -> Option<&PNGChunk> {
+        self.chunks.iter().find(|c| c.chunk_type == chunk_type)
+    }
+
+    pub fn get_chunks<'a>(&'a self, chunk_type: &'a str) -> impl Iterator<Item = &'a PNGChunk> {
+        self.chunks
+            .iter()
+            .filter(move |c| c.chunk_type == chunk_type)
+    }
+
     fn get_png_info(header_chunk: &PNGChunk) -> Result<PNGInfo, PngError> {
         if header_chunk.chunk_type != "IHDR" {
-            return Err(PngError::InvalidPngInfo(
-                "Header chunk must be of type IHDR".to_string(),
-            ));
+            return Err(PngError::InvalidPngInfo(message(
+                "png.header_not_ihdr",
+                &[],
+            )));
         }
 
         let mut data = Cursor::new(&header_chunk.data);
@@ -129,13 +278,13 @@ impl PngImage {
         let mut compression_method = [0; 1];
         let mut filter_method = [0; 1];
         let mut interlace_method = [0; 1];
-        let res1 = data.read(&mut width);
-        let res2 = data.read(&mut height);
-        let res3 = data.read(&mut bit_depth);
-        let res4 = data.read(&mut color_type);
-        let res5 = data.read(&mut compression_method);
-        let res6 = data.read(&mut filter_method);
-        let res7 = data.read(&mut interlace_method);
+        let res1 = data.read_exact(&mut width);
+        let res2 = data.read_exact(&mut height);
+        let res3 = data.read_exact(&mut bit_depth);
+        let res4 = data.read_exact(&mut color_type);
+        let res5 = data.read_exact(&mut compression_method);
+        let res6 = data.read_exact(&mut filter_method);
+        let res7 = data.read_exact(&mut interlace_method);
 
         if res1.is_err()
             || res2.is_err()
@@ -145,9 +294,10 @@ impl PngImage {
             || res6.is_err()
             || res7.is_err()
         {
-            return Err(PngError::InvalidPngInfo(
-                "Could not read png info from header data buffer".to_string(),
-            ));
+            return Err(PngError::InvalidPngInfo(message(
+                "png.header_unreadable",
+                &[],
+            )));
         }
 
         Ok(PNGInfo {
@@ -163,7 +313,7 @@ impl PngImage {
 
     fn get_chunk_data(data: &mut Cursor<Vec<u8>>, size: u32) -> Result<Vec<u8>, PngError> {
         let mut chunk_data = vec![0; size as usize];
-        let res = data.read(&mut chunk_data);
+        let res = data.read_exact(&mut chunk_data);
 
         if res.is_err() {
             return Err(PngError::InvalidChunk);
@@ -174,12 +324,13 @@ impl PngImage {
 
     fn get_chunk_crc(data: &mut Cursor<Vec<u8>>) -> Result<u32, PngError> {
         let mut chunk_type_buf = [0; 4];
-        let res = data.read(&mut chunk_type_buf);
+        let res = data.read_exact(&mut chunk_type_buf);
 
         if res.is_err() {
-            return Err(PngError::InvalidChunkCrc(
-                "Failed to read 4 byte CRC hash".to_string(),
-            ));
+            return Err(PngError::InvalidChunkCrc(message(
+                "png.crc_unreadable",
+                &[],
+            )));
         }
 
         Ok(u32::from_be_bytes(chunk_type_buf))
@@ -188,26 +339,28 @@ impl PngImage {
     fn get_chunk_type(data: &mut Cursor<Vec<u8>>) -> Result<String, PngError> {
         // IHDR for head chunk and IEND for end chunk
         let mut chunk_type_buf = [0; 4];
-        let res = data.read(&mut chunk_type_buf);
+        let res = data.read_exact(&mut chunk_type_buf);
 
         if res.is_err() {
-            return Err(PngError::InvalidChunkType(
-                "Failed to read 4 bytes out of data buffer".to_string(),
-            ));
+            return Err(PngError::InvalidChunkType(message(
+                "png.type_unreadable",
+                &[],
+            )));
         }
 
         match String::from_utf8(chunk_type_buf.to_vec()) {
             Ok(s) => Ok(s),
-            Err(_) => Err(PngError::InvalidChunkType(
-                "Failed to convert chunk type to string".to_string(),
-            )),
+            Err(_) => Err(PngError::InvalidChunkType(message(
+                "png.type_not_text",
+                &[],
+            ))),
         }
     }
 
     fn get_chunk_size(data: &mut Cursor<Vec<u8>>) -> Result<u32, PngError> {
         // bytes 8-12 specify the size of the chunk
         let mut chunk_size = [0; 4];
-        let res = data.read(&mut chunk_size);
+        let res = data.read_exact(&mut chunk_size);
 
         if res.is_err() {
             return Err(PngError::InvalidChunkSize);
@@ -224,7 +377,7 @@ impl PngImage {
         }
 
         let mut file_type_bytes = [0; 8];
-        let res = data.read(&mut file_type_bytes);
+        let res = data.read_exact(&mut file_type_bytes);
 
         if res.is_err() {
             return Err(PngError::InvalidFileType);
@@ -237,23 +390,27 @@ impl PngImage {
         Ok(())
     }
 
-    pub fn save_image(self: &Self, path: &str) -> Result<(), PngError> {
-        let mut file = match File::create(path) {
-            Ok(f) => f,
-            Err(_) => return Err(PngError::SaveOperationFailed),
-        };
-
+    pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = vec![];
         bytes.extend_from_slice(&PNG_SIGNATURE);
 
         for chunk in &self.chunks {
             bytes.extend_from_slice(&chunk.size.to_be_bytes());
-            bytes.extend_from_slice(&chunk.chunk_type.as_bytes());
+            bytes.extend_from_slice(chunk.chunk_type.as_bytes());
             bytes.extend_from_slice(&chunk.data);
             bytes.extend_from_slice(&chunk.crc.to_be_bytes());
         }
 
-        match file.write(&bytes) {
+        bytes
+    }
+
+    pub fn save_image(&self, path: &str) -> Result<(), PngError> {
+        let mut file = match File::create(path) {
+            Ok(f) => f,
+            Err(_) => return Err(PngError::SaveOperationFailed),
+        };
+
+        match file.write_all(&self.to_bytes()) {
             Ok(_) => (),
             Err(_) => return Err(PngError::SaveOperationFailed),
         };
@@ -264,15 +421,27 @@ impl PngImage {
 
 impl Display for PngImage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:#?}", self.to_string())
+        write!(f, "{:#?}", self.info)?;
+
+        for chunk in &self.chunks {
+            write!(
+                f,
+                "\n{} ({} bytes, crc {:08X})",
+                chunk.chunk_type, chunk.size, chunk.crc
+            )?;
+        }
+
+        Ok(())
     }
 }
 
+pub(crate) fn is_critical_type(chunk_type: &str) -> bool {
+    // bit 5 of the first byte is the ancillary bit
+    chunk_type.as_bytes().first().is_some_and(|b| b & 0x20 == 0)
+}
+
 fn read_image_data(file_path: &str) -> Vec<u8> {
-    match fs::read(file_path) {
-        Ok(bytes) => bytes,
-        Err(_) => vec![],
-    }
+    fs::read(file_path).unwrap_or_default()
 }
 
 #[cfg(test)]
@@ -292,6 +461,69 @@ mod tests {
         PngImage::new(IMAGE_PATH).unwrap();
     }
 
+    // the modules a default-features = false build compiles, keep this in step
+    // with the minimal build section of the README
+    const MINIMAL_MODULES: [&str; 7] = [
+        "checksum",
+        "diagnostics",
+        "locale",
+        "memory",
+        "payload",
+        "prelude",
+        "session",
+    ];
+
+    #[test]
+    fn test_minimal_build_modules() {
+        let mut ungated = vec![];
+        let mut gated = false;
+
+        for line in include_str!("png.rs").lines() {
+            if line.starts_with("#[cfg(") {
+                gated = true;
+            } else if let Some(name) = line
+                .strip_prefix("pub mod ")
+                .or_else(|| line.strip_prefix("mod "))
+                .and_then(|rest| rest.strip_suffix(';'))
+            {
+                if !gated {
+                    ungated.push(name);
+                }
+                gated = false;
+            } else if !line.starts_with("#[") {
+                gated = false;
+            }
+        }
+
+        assert_eq!(ungated, MINIMAL_MODULES);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let errors = [
+            PngError::InvalidFileType,
+            PngError::InvalidChunk,
+            PngError::InvalidChunkType(String::new()),
+            PngError::InvalidChunkCrc(String::new()),
+            PngError::SaveOperationFailed,
+            PngError::InvalidChunkSize,
+            PngError::InvalidPngInfo(String::new()),
+            PngError::InvalidImageData(String::new()),
+            PngError::InvalidOperation(String::new()),
+            PngError::StreamFailed(String::new()),
+        ];
+
+        // the codes are part of the public interface, they must not move
+        for (i, error) in errors.iter().enumerate() {
+            assert_eq!(error.code() as usize, i + 1);
+            assert!(locale::english(&format!("error.{}", error.name())).is_some());
+        }
+        assert_eq!(
+            PngImage::from_bytes(b"GIF89a".to_vec()).unwrap_err().code(),
+            1
+        );
+    }
+
     #[test]
     fn test_chunks() {
         let image = PngImage::new(IMAGE_PATH).unwrap();
@@ -316,6 +548,7 @@ mod tests {
     #[test]
     fn test_save_image() {
         let image = PngImage::new(IMAGE_PATH).unwrap();
+        fs::create_dir_all("./save_test").unwrap();
         image.save_image(SAVE_PATH).unwrap();
     }
 }
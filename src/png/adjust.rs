@@ -0,0 +1,236 @@
+// color corrections that work from the statistics of the whole image
+
+use super::{EncodeOptions, PixelBuffer, PngError, PngImage};
+
+type Histograms = [[u64; 256]; 3];
+
+// share of the darkest and the brightest samples auto_levels clips, so dust
+// and specular highlights on a scan don't hold the black and white points
+pub const LEVELS_CLIP: f64 = 0.005;
+
+// red, green and blue counts over the pixels that aren't fully transparent,
+// their color says nothing about the image
+fn histograms(pixels: &PixelBuffer) -> (Histograms, u64) {
+    let mut counts = [[0u64; 256]; 3];
+    let mut total = 0;
+
+    for p in pixels.data.chunks_exact(4).filter(|p| p[3] > 0) {
+        for c in 0..3 {
+            counts[c][p[c] as usize] += 1;
+        }
+        total += 1;
+    }
+
+    (counts, total)
+}
+
+fn cumulative(counts: &[u64; 256]) -> [u64; 256] {
+    let mut sums = [0u64; 256];
+    let mut sum = 0;
+    for (s, count) in sums.iter_mut().zip(counts) {
+        sum += count;
+        *s = sum;
+    }
+    sums
+}
+
+fn apply(pixels: &mut PixelBuffer, tables: &[[u8; 256]; 3]) {
+    for p in pixels.data.chunks_exact_mut(4) {
+        for c in 0..3 {
+            p[c] = tables[c][p[c] as usize];
+        }
+    }
+}
+
+// remaps every channel so its distribution follows the reference, each level
+// goes to the first reference level that has seen at least as large a share
+// of the pixels. alpha is left alone
+pub fn match_histogram(pixels: &mut PixelBuffer, reference: &PixelBuffer) {
+    let (source, source_total) = histograms(pixels);
+    let (target, target_total) = histograms(reference);
+    if source_total == 0 || target_total == 0 {
+        return;
+    }
+
+    let mut tables = [[0u8; 256]; 3];
+    for c in 0..3 {
+        let (source, target) = (cumulative(&source[c]), cumulative(&target[c]));
+
+        let mut level = 0;
+        for (s, out) in tables[c].iter_mut().enumerate() {
+            // shares compared as source[s] / source_total <= target[level] / target_total
+            while level < 255 && target[level] * source_total < source[s] * target_total {
+                level += 1;
+            }
+            *out = level as u8;
+        }
+    }
+
+    apply(pixels, &tables);
+}
+
+// stretches the range between the black and white points over 0..=255. the
+// points come from all three channels together, so the color balance stays
+pub fn auto_levels(pixels: &mut PixelBuffer) {
+    let (counts, total) = histograms(pixels);
+    if total == 0 {
+        return;
+    }
+
+    let mut combined = [0u64; 256];
+    for channel in &counts {
+        for (sum, count) in combined.iter_mut().zip(channel) {
+            *sum += count;
+        }
+    }
+
+    let clip = (total as f64 * 3.0 * LEVELS_CLIP) as u64;
+    let sums = cumulative(&combined);
+    let black = sums.iter().position(|s| *s > clip).unwrap_or(0);
+    let white = sums
+        .iter()
+        .rposition(|s| *s < total * 3 - clip)
+        .map_or(255, |l| l + 1);
+    if white <= black {
+        return;
+    }
+
+    let mut table = [0u8; 256];
+    for (level, out) in table.iter_mut().enumerate() {
+        let stretched = (level.clamp(black, white) - black) * 255;
+        *out = ((stretched + (white - black) / 2) / (white - black)) as u8;
+    }
+    apply(pixels, &[table; 3]);
+}
+
+// gray world white balance, scales each channel so the image averages out to gray
+pub fn auto_white_balance(pixels: &mut PixelBuffer) {
+    let (counts, total) = histograms(pixels);
+    if total == 0 {
+        return;
+    }
+
+    let means = counts.map(|channel| {
+        let sum: u64 = channel.iter().enumerate().map(|(l, c)| l as u64 * c).sum();
+        sum as f64 / total as f64
+    });
+    let gray = means.iter().sum::<f64>() / 3.0;
+
+    let mut tables = [[0u8; 256]; 3];
+    for (table, mean) in tables.iter_mut().zip(means) {
+        // a channel with nothing in it can't be scaled up to gray
+        let gain = if mean > 0.0 { gray / mean } else { 1.0 };
+        for (level, out) in table.iter_mut().enumerate() {
+            *out = (level as f64 * gain).round().min(255.0) as u8;
+        }
+    }
+    apply(pixels, &tables);
+}
+
+impl PngImage {
+    pub fn match_histogram(&mut self, reference: &PngImage) -> Result<(), PngError> {
+        let mut pixels = self.decode()?;
+        match_histogram(&mut pixels, &reference.decode()?);
+        self.set_pixels(&pixels, &EncodeOptions::default())?;
+        Ok(())
+    }
+
+    pub fn auto_levels(&mut self) -> Result<(), PngError> {
+        let mut pixels = self.decode()?;
+        auto_levels(&mut pixels);
+        self.set_pixels(&pixels, &EncodeOptions::default())?;
+        Ok(())
+    }
+
+    pub fn auto_white_balance(&mut self) -> Result<(), PngError> {
+        let mut pixels = self.decode()?;
+        auto_white_balance(&mut pixels);
+        self.set_pixels(&pixels, &EncodeOptions::default())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    fn gradient(offset: u8, scale: u8) -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(100, 1).unwrap();
+        for x in 0..100 {
+            let v = offset + x as u8 / scale;
+            pixels.set_pixel(x, 0, [v, v / 2, 255 - v, 255]);
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_match_histogram() {
+        // a dark low contrast capture of the same gradient
+        let mut pixels = gradient(10, 2);
+        let reference = gradient(100, 1);
+        match_histogram(&mut pixels, &reference);
+
+        for x in 0..100 {
+            let matched = pixels.get_pixel(x, 0);
+            let expected = reference.get_pixel(x, 0);
+            for c in 0..3 {
+                assert!(
+                    matched[c].abs_diff(expected[c]) <= 1,
+                    "{:?} {:?}",
+                    matched,
+                    expected
+                );
+            }
+            assert_eq!(matched[3], 255);
+        }
+    }
+
+    #[test]
+    fn test_auto_levels() {
+        // a washed out scan between 60 and 180 with a speck of dust
+        let mut pixels = PixelBuffer::new(200, 1).unwrap();
+        for x in 0..200 {
+            let v = 60 + (x * 120 / 199) as u8;
+            pixels.set_pixel(x, 0, [v, v, v.saturating_sub(20), 255]);
+        }
+        pixels.set_pixel(0, 0, [0, 0, 0, 255]);
+        auto_levels(&mut pixels);
+
+        // the dust is clipped, the darkest blue and brightest red span the range
+        assert_eq!(pixels.get_pixel(0, 0), [0, 0, 0, 255]);
+        assert!(pixels.get_pixel(1, 0)[2] <= 2);
+        assert!(pixels.get_pixel(199, 0)[0] >= 253);
+        let reds: Vec<u8> = pixels.data.chunks_exact(4).map(|p| p[0]).collect();
+        assert!(reds.windows(2).all(|w| w[0] <= w[1]));
+        // blue stays below red, the balance doesn't change
+        assert!(pixels.data.chunks_exact(4).all(|p| p[2] <= p[0]));
+    }
+
+    #[test]
+    fn test_auto_white_balance() {
+        // gray tones under a warm cast
+        let mut pixels = PixelBuffer::new(64, 1).unwrap();
+        for x in 0..64 {
+            let v = 64 + x as u8 * 2;
+            pixels.set_pixel(x, 0, [v, (v as u32 * 4 / 5) as u8, v / 2, 255]);
+        }
+        auto_white_balance(&mut pixels);
+
+        for p in pixels.data.chunks_exact(4) {
+            assert!(
+                p[0].abs_diff(p[1]) <= 2 && p[1].abs_diff(p[2]) <= 2,
+                "{:?}",
+                p
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_histogram_to_itself() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        let before = image.decode().unwrap();
+        image.match_histogram(&image.clone()).unwrap();
+        assert_eq!(image.decode().unwrap(), before);
+    }
+}
@@ -0,0 +1,158 @@
+// every chunk as a file of its own, for editing with tools that know nothing about png
+//
+// export_chunks writes each payload to NN_TYPE.bin in file order next to a
+// chunks.json manifest listing them. import_chunks follows the manifest, so
+// chunks can be edited, reordered or dropped by changing the files and the
+// manifest, and computes fresh lengths and CRCs for whatever it finds.
+
+use std::{
+    fs,
+    path::{Component, Path},
+};
+
+use super::{locale::message, PNGChunk, PngError, PngImage};
+use crate::json::Value;
+
+pub const MANIFEST_NAME: &str = "chunks.json";
+
+fn malformed(key: &str, args: &[&dyn std::fmt::Display]) -> PngError {
+    PngError::InvalidOperation(message(key, args))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, PngError> {
+    fs::read(path)
+        .map_err(|e| PngError::StreamFailed(message("chunks.unreadable", &[&path.display(), &e])))
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), PngError> {
+    fs::write(path, bytes)
+        .map_err(|e| PngError::StreamFailed(message("chunks.unwritable", &[&path.display(), &e])))
+}
+
+impl PngImage {
+    pub fn export_chunks(&self, dir: &str) -> Result<(), PngError> {
+        let dir = Path::new(dir);
+        fs::create_dir_all(dir).map_err(|e| {
+            PngError::StreamFailed(message("chunks.unwritable", &[&dir.display(), &e]))
+        })?;
+
+        // wide enough that the names sort in file order
+        let digits = self.chunks.len().saturating_sub(1).to_string().len().max(2);
+        let mut entries = vec![];
+
+        for (i, chunk) in self.chunks.iter().enumerate() {
+            let file = format!("{:0digits$}_{}.bin", i, chunk.chunk_type);
+            write(&dir.join(&file), &chunk.data)?;
+
+            entries.push(Value::object(vec![
+                ("type", chunk.chunk_type.as_str().into()),
+                ("file", file.into()),
+                ("length", chunk.data.len().into()),
+                ("crc", format!("{:08x}", chunk.crc).into()),
+            ]));
+        }
+
+        let manifest = Value::object(vec![
+            (
+                "generator",
+                format!("whats-a-png/{}", env!("CARGO_PKG_VERSION")).into(),
+            ),
+            ("chunks", Value::Array(entries)),
+        ]);
+        write(&dir.join(MANIFEST_NAME), manifest.to_string().as_bytes())
+    }
+
+    // the length and crc in the manifest are only informational, both are
+    // worked out again from the files
+    pub fn import_chunks(dir: &str) -> Result<Self, PngError> {
+        let dir = Path::new(dir);
+        let text = String::from_utf8(read(&dir.join(MANIFEST_NAME))?)
+            .map_err(|_| malformed("chunks.no_list", &[]))?;
+        let manifest = Value::parse(&text)?;
+        let entries = manifest
+            .get("chunks")
+            .and_then(|c| c.as_array())
+            .ok_or_else(|| malformed("chunks.no_list", &[]))?;
+
+        let mut chunks = vec![];
+        for (i, entry) in entries.iter().enumerate() {
+            let field = |name: &str| {
+                entry
+                    .get(name)
+                    .and_then(|v| v.as_str())
+                    .ok_or_else(|| malformed("chunks.missing_field", &[&i, &name]))
+            };
+            let chunk_type = field("type")?;
+            let file = field("file")?;
+
+            if chunk_type.len() != 4 || !chunk_type.bytes().all(|b| b.is_ascii_alphabetic()) {
+                return Err(PngError::InvalidChunkType(chunk_type.to_string()));
+            }
+            // keep the manifest from pointing outside the directory
+            let mut components = Path::new(file).components();
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(Component::Normal(_)), None)
+            ) {
+                return Err(malformed("chunks.bad_file", &[&file]));
+            }
+
+            chunks.push(PNGChunk::new(chunk_type, read(&dir.join(file))?));
+        }
+
+        let info = match chunks.first() {
+            Some(header) => Self::get_png_info(header)?,
+            None => return Err(malformed("chunks.no_list", &[])),
+        };
+        Ok(PngImage { info, chunks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_chunk_round_trip() {
+        let dir = "./save_test/chunks";
+        let _ = fs::remove_dir_all(dir);
+
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        image.export_chunks(dir).unwrap();
+        assert!(Path::new(dir).join("00_IHDR.bin").exists());
+        // a file where the directory should go
+        let error = image.export_chunks(IMAGE_PATH).unwrap_err();
+        assert!(error.get_message().contains(IMAGE_PATH));
+
+        let imported = PngImage::import_chunks(dir).unwrap();
+        assert_eq!(imported.to_bytes(), image.to_bytes());
+
+        // edit a chunk outside the library, the crc follows the new data
+        let last = image.chunks.len() - 1;
+        fs::write(Path::new(dir).join(format!("{:02}_IEND.bin", last)), b"x").unwrap();
+        let edited = PngImage::import_chunks(dir).unwrap();
+        let iend = edited.chunks.last().unwrap();
+        assert_eq!(
+            (iend.size, iend.crc),
+            (1, PNGChunk::new("IEND", b"x".to_vec()).crc)
+        );
+    }
+
+    #[test]
+    fn test_import_rejects_bad_manifests() {
+        let dir = "./save_test/chunks_bad";
+        fs::create_dir_all(dir).unwrap();
+
+        for manifest in [
+            "[]",
+            r#"{"chunks": [{"type": "IHDR"}]}"#,
+            r#"{"chunks": [{"type": "IH", "file": "a.bin"}]}"#,
+            r#"{"chunks": [{"type": "IHDR", "file": "../test.png"}]}"#,
+            r#"{"chunks": []}"#,
+        ] {
+            fs::write(Path::new(dir).join(MANIFEST_NAME), manifest).unwrap();
+            assert!(PngImage::import_chunks(dir).is_err(), "{}", manifest);
+        }
+    }
+}
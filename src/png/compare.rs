@@ -0,0 +1,395 @@
+// pixel comparison for visual regression tests
+//
+// colors are compared by their perceived difference in YIQ space after
+// blending onto white, the same metric pixelmatch uses, so thresholds carry
+// over from suites built on it. with anti_aliasing on, differing pixels that
+// look like anti-aliased edges in either image are reported separately
+// instead of failing the comparison, using pixelmatch's heuristic from
+// "Anti-aliased Pixel and Intensity Slope Detector" by Vysniauskas (2009).
+
+use super::{locale::message, PixelBuffer, PngError, PngImage, Rect};
+
+// the largest possible YIQ delta, between black and white
+const MAX_DELTA: f64 = 35215.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionThreshold {
+    pub rect: Rect,
+    pub threshold: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct CompareOptions {
+    // 0.0 needs an exact match, 1.0 accepts any color
+    pub threshold: f64,
+    // areas that never count as different, like a clock widget
+    pub ignore: Vec<Rect>,
+    // areas with their own threshold, later entries win where they overlap
+    pub regions: Vec<RegionThreshold>,
+    // tolerate differences in anti-aliased edges, like text rendered on another platform
+    pub anti_aliasing: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions {
+            threshold: 0.1,
+            ignore: vec![],
+            regions: vec![],
+            anti_aliasing: false,
+        }
+    }
+}
+
+impl CompareOptions {
+    fn threshold_at(&self, x: u32, y: u32) -> Option<f64> {
+        if self.ignore.iter().any(|r| r.contains(x, y)) {
+            return None;
+        }
+
+        match self.regions.iter().rev().find(|r| r.rect.contains(x, y)) {
+            Some(region) => Some(region.threshold),
+            None => Some(self.threshold),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    pub diff_pixels: u64,
+    // differing pixels put down to anti-aliasing, these don't fail the comparison
+    pub anti_aliased_pixels: u64,
+    pub ignored_pixels: u64,
+    pub total_pixels: u64,
+    // smallest rect holding every differing pixel
+    pub diff_bounds: Option<Rect>,
+}
+
+impl Comparison {
+    pub fn is_match(&self) -> bool {
+        self.diff_pixels == 0
+    }
+}
+
+fn blend_white(channel: u8, alpha: f64) -> f64 {
+    255.0 + (channel as f64 - 255.0) * alpha
+}
+
+fn blended(pixel: [u8; 4]) -> (f64, f64, f64) {
+    let a = pixel[3] as f64 / 255.0;
+    (
+        blend_white(pixel[0], a),
+        blend_white(pixel[1], a),
+        blend_white(pixel[2], a),
+    )
+}
+
+fn brightness(pixel: [u8; 4]) -> f64 {
+    let (r, g, b) = blended(pixel);
+    r * 0.29889531 + g * 0.58662247 + b * 0.11448223
+}
+
+fn yiq(pixel: [u8; 4]) -> (f64, f64, f64) {
+    let (r, g, b) = blended(pixel);
+
+    (
+        brightness(pixel),
+        r * 0.59597799 - g * 0.27417610 - b * 0.32180189,
+        r * 0.21147017 - g * 0.52261711 + b * 0.31114694,
+    )
+}
+
+// perceived difference between two pixels from 0.0 to MAX_DELTA
+pub(crate) fn color_delta(a: [u8; 4], b: [u8; 4]) -> f64 {
+    if a == b {
+        return 0.0;
+    }
+
+    let (y1, i1, q1) = yiq(a);
+    let (y2, i2, q2) = yiq(b);
+    let (y, i, q) = (y1 - y2, i1 - i2, q1 - q2);
+
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
+
+// thresholds are given on the square root scale like pixelmatch
+fn exceeds(delta: f64, threshold: f64) -> bool {
+    delta > MAX_DELTA * threshold * threshold
+}
+
+// the 3x3 neighborhood around a pixel, without the pixel itself
+fn neighbors(pixels: &PixelBuffer, x: u32, y: u32) -> impl Iterator<Item = (u32, u32)> {
+    let (x0, y0) = (x.saturating_sub(1), y.saturating_sub(1));
+    let (x1, y1) = (
+        (x + 1).min(pixels.width - 1),
+        (y + 1).min(pixels.height - 1),
+    );
+
+    (y0..=y1)
+        .flat_map(move |ny| (x0..=x1).map(move |nx| (nx, ny)))
+        .filter(move |&(nx, ny)| (nx, ny) != (x, y))
+}
+
+fn on_edge(pixels: &PixelBuffer, x: u32, y: u32) -> bool {
+    x == 0 || y == 0 || x == pixels.width - 1 || y == pixels.height - 1
+}
+
+// whether at least three neighbors have exactly the pixel's color
+fn has_many_siblings(pixels: &PixelBuffer, x: u32, y: u32) -> bool {
+    let pixel = pixels.get_pixel(x, y);
+    // pixels on the border count the missing side as a sibling
+    let mut same = on_edge(pixels, x, y) as u32;
+
+    for (nx, ny) in neighbors(pixels, x, y) {
+        if pixels.get_pixel(nx, ny) == pixel {
+            same += 1;
+            if same > 2 {
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+// an anti-aliased pixel sits between a darker and a brighter neighbor, has at
+// most two neighbors of its own brightness, and either extreme belongs to a
+// flat area in both images
+fn anti_aliased(pixels: &PixelBuffer, x: u32, y: u32, other: &PixelBuffer) -> bool {
+    let center = brightness(pixels.get_pixel(x, y));
+    let mut same = on_edge(pixels, x, y) as u32;
+    let mut darkest = (0.0, x, y);
+    let mut brightest = (0.0, x, y);
+
+    for (nx, ny) in neighbors(pixels, x, y) {
+        let delta = brightness(pixels.get_pixel(nx, ny)) - center;
+
+        if delta == 0.0 {
+            same += 1;
+            if same > 2 {
+                return false;
+            }
+        } else if delta < darkest.0 {
+            darkest = (delta, nx, ny);
+        } else if delta > brightest.0 {
+            brightest = (delta, nx, ny);
+        }
+    }
+
+    if darkest.0 == 0.0 || brightest.0 == 0.0 {
+        return false;
+    }
+
+    let flat = |(_, x, y): (f64, u32, u32)| {
+        has_many_siblings(pixels, x, y) && has_many_siblings(other, x, y)
+    };
+    flat(darkest) || flat(brightest)
+}
+
+// grayscale faded towards white, the backdrop differences are drawn over
+fn faded(pixel: [u8; 4]) -> [u8; 4] {
+    let (y, _, _) = yiq(pixel);
+    let v = (255.0 + (y - 255.0) * 0.1).round() as u8;
+    [v, v, v, 255]
+}
+
+const DIFF_COLOR: [u8; 4] = [255, 0, 0, 255];
+const ANTI_ALIASED_COLOR: [u8; 4] = [255, 255, 0, 255];
+const IGNORED_COLOR: [u8; 4] = [120, 160, 255, 255];
+
+fn compare_into(
+    expected: &PixelBuffer,
+    actual: &PixelBuffer,
+    options: &CompareOptions,
+    mut diff: Option<&mut PixelBuffer>,
+) -> Result<Comparison, PngError> {
+    if expected.width != actual.width || expected.height != actual.height {
+        return Err(PngError::InvalidOperation(message(
+            "compare.size_mismatch",
+            &[
+                &expected.width,
+                &expected.height,
+                &actual.width,
+                &actual.height,
+            ],
+        )));
+    }
+
+    let mut result = Comparison {
+        diff_pixels: 0,
+        anti_aliased_pixels: 0,
+        ignored_pixels: 0,
+        total_pixels: expected.width as u64 * expected.height as u64,
+        diff_bounds: None,
+    };
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+
+    for y in 0..expected.height {
+        for x in 0..expected.width {
+            let a = expected.get_pixel(x, y);
+            let b = actual.get_pixel(x, y);
+
+            let out = match options.threshold_at(x, y) {
+                None => {
+                    result.ignored_pixels += 1;
+                    IGNORED_COLOR
+                }
+                Some(threshold) if !exceeds(color_delta(a, b), threshold) => faded(a),
+                Some(_)
+                    if options.anti_aliasing
+                        && (anti_aliased(expected, x, y, actual)
+                            || anti_aliased(actual, x, y, expected)) =>
+                {
+                    result.anti_aliased_pixels += 1;
+                    ANTI_ALIASED_COLOR
+                }
+                Some(_) => {
+                    result.diff_pixels += 1;
+                    bounds = Some(match bounds {
+                        Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                        None => (x, y, x, y),
+                    });
+                    DIFF_COLOR
+                }
+            };
+
+            if let Some(diff) = diff.as_deref_mut() {
+                diff.set_pixel(x, y, out);
+            }
+        }
+    }
+
+    result.diff_bounds = bounds.map(|(x0, y0, x1, y1)| Rect::new(x0, y0, x1 - x0 + 1, y1 - y0 + 1));
+    Ok(result)
+}
+
+pub fn compare(
+    expected: &PixelBuffer,
+    actual: &PixelBuffer,
+    options: &CompareOptions,
+) -> Result<Comparison, PngError> {
+    compare_into(expected, actual, options, None)
+}
+
+// the comparison plus an image with differences in red, anti-aliasing in
+// yellow and ignored areas in blue
+pub fn diff_image(
+    expected: &PixelBuffer,
+    actual: &PixelBuffer,
+    options: &CompareOptions,
+) -> Result<(Comparison, PixelBuffer), PngError> {
+    let mut diff = PixelBuffer::new(expected.width, expected.height)?;
+    let result = compare_into(expected, actual, options, Some(&mut diff))?;
+    Ok((result, diff))
+}
+
+impl PngImage {
+    pub fn compare(
+        &self,
+        other: &PngImage,
+        options: &CompareOptions,
+    ) -> Result<Comparison, PngError> {
+        compare(&self.decode()?, &other.decode()?, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screenshot() -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(40, 20).unwrap();
+        pixels.fill_rect(pixels.bounds(), [240, 240, 240, 255]);
+        pixels.fill_rect(Rect::new(2, 2, 20, 4), [30, 30, 30, 255]);
+        pixels
+    }
+
+    #[test]
+    fn test_compare() {
+        let expected = screenshot();
+        let mut actual = screenshot();
+        assert!(compare(&expected, &actual, &CompareOptions::default())
+            .unwrap()
+            .is_match());
+
+        // a barely visible shade passes, a new shape doesn't
+        actual.set_pixel(30, 10, [236, 236, 236, 255]);
+        actual.fill_rect(Rect::new(4, 12, 3, 2), [200, 0, 0, 255]);
+        let result = compare(&expected, &actual, &CompareOptions::default()).unwrap();
+        assert_eq!(result.diff_pixels, 6);
+        assert_eq!(result.diff_bounds, Some(Rect::new(4, 12, 3, 2)));
+
+        let exact = CompareOptions {
+            threshold: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(compare(&expected, &actual, &exact).unwrap().diff_pixels, 7);
+        assert!(compare(&expected, &PixelBuffer::new(1, 1).unwrap(), &exact).is_err());
+    }
+
+    #[test]
+    fn test_compare_ignore_and_regions() {
+        let expected = screenshot();
+        let mut actual = screenshot();
+        // the clock changed and the anti-aliased logo shifted slightly
+        actual.fill_rect(Rect::new(32, 2, 6, 3), [0, 0, 0, 255]);
+        actual.fill_rect(Rect::new(2, 2, 20, 4), [90, 90, 90, 255]);
+
+        let options = CompareOptions {
+            ignore: vec![Rect::new(30, 0, 10, 6)],
+            regions: vec![RegionThreshold {
+                rect: Rect::new(0, 0, 24, 8),
+                threshold: 0.3,
+            }],
+            ..Default::default()
+        };
+        let (result, diff) = diff_image(&expected, &actual, &options).unwrap();
+        assert!(result.is_match());
+        assert_eq!(result.ignored_pixels, 60);
+        assert_eq!(diff.get_pixel(35, 3), IGNORED_COLOR);
+
+        // later regions win, so a strict one inside puts the logo back in play
+        let mut strict = options.clone();
+        strict.regions.push(RegionThreshold {
+            rect: Rect::new(2, 2, 1, 1),
+            threshold: 0.0,
+        });
+        let (result, diff) = diff_image(&expected, &actual, &strict).unwrap();
+        assert_eq!(result.diff_pixels, 1);
+        assert_eq!(diff.get_pixel(2, 2), DIFF_COLOR);
+    }
+
+    // black on the left, white on the right and a gray edge column between
+    fn edge(gray: u8) -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(10, 10).unwrap();
+        pixels.fill_rect(Rect::new(0, 0, 5, 10), [0, 0, 0, 255]);
+        pixels.fill_rect(Rect::new(5, 0, 1, 10), [gray, gray, gray, 255]);
+        pixels.fill_rect(Rect::new(6, 0, 4, 10), [255, 255, 255, 255]);
+        pixels
+    }
+
+    #[test]
+    fn test_compare_anti_aliasing() {
+        let expected = edge(128);
+        let mut actual = edge(60);
+        let options = CompareOptions {
+            anti_aliasing: true,
+            ..Default::default()
+        };
+
+        let strict = compare(&expected, &actual, &CompareOptions::default()).unwrap();
+        assert_eq!(strict.diff_pixels, 10);
+
+        let (result, diff) = diff_image(&expected, &actual, &options).unwrap();
+        assert!(result.is_match());
+        assert_eq!(result.anti_aliased_pixels, 10);
+        assert_eq!(diff.get_pixel(5, 5), ANTI_ALIASED_COLOR);
+
+        // a dot inside the flat black area is a real change
+        actual.set_pixel(2, 4, [255, 255, 255, 255]);
+        let result = compare(&expected, &actual, &options).unwrap();
+        assert_eq!(result.diff_pixels, 1);
+        assert_eq!(result.diff_bounds, Some(Rect::new(2, 4, 1, 1)));
+    }
+}
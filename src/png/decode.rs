@@ -0,0 +1,527 @@
+// turns the zlib stream in the IDAT chunks back into scanlines and pixels
+
+use std::{io::Read, time::Instant};
+
+use super::{
+    filter::unfilter_row, inflate::Inflater, locale::message, pixels::rgba_len,
+    stats::CompressionStats, PNGChunk, PNGInfo, PixelBuffer, PngError, PngImage,
+};
+
+// adam7 pass origins and steps as (x0, y0, dx, dy)
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pass {
+    pub x0: u32,
+    pub y0: u32,
+    pub dx: u32,
+    pub dy: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Layout {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: u8,
+    pub channels: usize,
+    pub interlaced: bool,
+}
+
+impl Layout {
+    pub(crate) fn new(info: &PNGInfo) -> Result<Self, PngError> {
+        let channels = match (info.color_type, info.bit_depth) {
+            (0, 1 | 2 | 4 | 8 | 16) => 1,
+            (2, 8 | 16) => 3,
+            (3, 1 | 2 | 4 | 8) => 1,
+            (4, 8 | 16) => 2,
+            (6, 8 | 16) => 4,
+            _ => {
+                return Err(PngError::InvalidPngInfo(message(
+                    "decode.unsupported_format",
+                    &[&info.color_type, &info.bit_depth],
+                )))
+            }
+        };
+
+        if info.width == 0 || info.height == 0 {
+            return Err(PngError::InvalidPngInfo(message("decode.empty", &[])));
+        }
+        rgba_len(info.width, info.height)?;
+        if info.compression_method != 0 || info.filter_method != 0 {
+            return Err(PngError::InvalidPngInfo(message(
+                "decode.unknown_method",
+                &[],
+            )));
+        }
+        if info.interlace_method > 1 {
+            return Err(PngError::InvalidPngInfo(message(
+                "decode.unknown_interlace",
+                &[],
+            )));
+        }
+
+        Ok(Layout {
+            width: info.width,
+            height: info.height,
+            bit_depth: info.bit_depth,
+            color_type: info.color_type,
+            channels,
+            interlaced: info.interlace_method == 1,
+        })
+    }
+
+    pub(crate) fn bits_per_pixel(&self) -> usize {
+        self.channels * self.bit_depth as usize
+    }
+
+    // bytes per complete pixel as used by the filters, at least one
+    pub(crate) fn filter_bpp(&self) -> usize {
+        (self.bits_per_pixel() / 8).max(1)
+    }
+
+    pub(crate) fn row_bytes(&self, width: u32) -> usize {
+        (width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    pub(crate) fn passes(&self) -> Vec<Pass> {
+        let steps: &[(u32, u32, u32, u32)] = if self.interlaced {
+            &ADAM7
+        } else {
+            &[(0, 0, 1, 1)]
+        };
+
+        steps
+            .iter()
+            .map(|(x0, y0, dx, dy)| Pass {
+                x0: *x0,
+                y0: *y0,
+                dx: *dx,
+                dy: *dy,
+                width: (self.width + dx - 1 - x0) / dx,
+                height: (self.height + dy - 1 - y0) / dy,
+            })
+            .filter(|p| p.width > 0 && p.height > 0)
+            .collect()
+    }
+}
+
+// reads the payloads of a run of chunks as one continuous stream
+pub(crate) struct ChunkDataReader<'a> {
+    chunks: Vec<&'a [u8]>,
+    index: usize,
+    pos: usize,
+}
+
+impl<'a> ChunkDataReader<'a> {
+    pub(crate) fn new(chunks: Vec<&'a [u8]>) -> Self {
+        ChunkDataReader {
+            chunks,
+            index: 0,
+            pos: 0,
+        }
+    }
+
+    // moves on to offset bytes into the joined payloads
+    pub(crate) fn skip_to(&mut self, mut offset: u64) {
+        while self.index < self.chunks.len() {
+            let left = (self.chunks[self.index].len() - self.pos) as u64;
+            if offset < left {
+                self.pos += offset as usize;
+                return;
+            }
+
+            offset -= left;
+            self.index += 1;
+            self.pos = 0;
+        }
+    }
+}
+
+impl Read for ChunkDataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.index < self.chunks.len() {
+            let chunk = self.chunks[self.index];
+
+            if self.pos < chunk.len() {
+                let n = buf.len().min(chunk.len() - self.pos);
+                buf[..n].copy_from_slice(&chunk[self.pos..self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+
+            self.index += 1;
+            self.pos = 0;
+        }
+
+        Ok(0)
+    }
+}
+
+pub(crate) struct Scanline<'a> {
+    pub pass: Pass,
+    pub y: u32,
+    pub filter: u8,
+    pub data: &'a [u8],
+}
+
+// yields unfiltered scanlines one at a time, holding only the current and previous row
+pub(crate) struct ScanlineReader<R: Read> {
+    inflater: Inflater<R>,
+    layout: Layout,
+    passes: Vec<Pass>,
+    pass_index: usize,
+    y: u32,
+    current: Vec<u8>,
+    prev: Vec<u8>,
+}
+
+impl<R: Read> ScanlineReader<R> {
+    pub(crate) fn new(layout: Layout, compressed: R) -> Self {
+        ScanlineReader {
+            inflater: Inflater::new(compressed),
+            layout,
+            passes: layout.passes(),
+            pass_index: 0,
+            y: 0,
+            current: vec![],
+            prev: vec![],
+        }
+    }
+
+    // carries on from row y of a non interlaced image, prev is row y - 1 unfiltered
+    pub(crate) fn resume(layout: Layout, inflater: Inflater<R>, y: u32, prev: Vec<u8>) -> Self {
+        ScanlineReader {
+            inflater,
+            layout,
+            passes: layout.passes(),
+            pass_index: 0,
+            y,
+            current: prev,
+            prev: vec![],
+        }
+    }
+
+    pub(crate) fn next_row(&mut self) -> Result<Option<Scanline<'_>>, PngError> {
+        if self.pass_index >= self.passes.len() {
+            return Ok(None);
+        }
+
+        let pass = self.passes[self.pass_index];
+        let row_bytes = self.layout.row_bytes(pass.width);
+
+        if self.y == 0 {
+            self.prev = vec![0; row_bytes];
+        } else {
+            std::mem::swap(&mut self.prev, &mut self.current);
+        }
+
+        let mut filter = [0u8];
+        self.current.resize(row_bytes, 0);
+        read_full(&mut self.inflater, &mut filter)?;
+        read_full(&mut self.inflater, &mut self.current)?;
+        unfilter_row(
+            filter[0],
+            self.layout.filter_bpp(),
+            &mut self.current,
+            &self.prev,
+        )?;
+
+        let y = self.y;
+        self.y += 1;
+        if self.y == pass.height {
+            self.pass_index += 1;
+            self.y = 0;
+        }
+
+        Ok(Some(Scanline {
+            pass,
+            y,
+            filter: filter[0],
+            data: &self.current,
+        }))
+    }
+
+    // reads the rest of the zlib stream, which checks its adler-32, and
+    // leaves the inflater's counts covering every block
+    pub(crate) fn drain(&mut self) -> Result<(), PngError> {
+        match std::io::copy(&mut self.inflater, &mut std::io::sink()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PngError::InvalidImageData(e.to_string())),
+        }
+    }
+
+    // checks the rest of the zlib stream and hands back the compressed reader
+    pub(crate) fn finish(mut self) -> Result<R, PngError> {
+        self.drain()?;
+        Ok(self.inflater.into_inner())
+    }
+}
+
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), PngError> {
+    match reader.read_exact(buf) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Err(PngError::InvalidImageData(
+            message("decode.ended_early", &[]),
+        )),
+        Err(e) => Err(PngError::InvalidImageData(e.to_string())),
+    }
+}
+
+fn sample(row: &[u8], index: usize, bit_depth: u8) -> u16 {
+    match bit_depth {
+        16 => u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]),
+        8 => row[index] as u16,
+        d => {
+            let bit = index * d as usize;
+            let shift = 8 - d as usize - bit % 8;
+            ((row[bit / 8] >> shift) & ((1 << d) - 1)) as u16
+        }
+    }
+}
+
+fn to_8bit(value: u16, bit_depth: u8) -> u8 {
+    match bit_depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        d => (value as u32 * 255 / ((1 << d) - 1)) as u8,
+    }
+}
+
+// expands raw scanlines of any color type and bit depth into RGBA8
+pub(crate) struct ColorConverter {
+    layout: Layout,
+    palette: Vec<[u8; 3]>,
+    transparency: Option<Vec<u8>>,
+}
+
+impl ColorConverter {
+    pub(crate) fn new(
+        layout: Layout,
+        palette: Option<&PNGChunk>,
+        transparency: Option<&PNGChunk>,
+    ) -> Result<Self, PngError> {
+        let palette: Vec<[u8; 3]> = match palette {
+            Some(chunk) => chunk
+                .data
+                .chunks_exact(3)
+                .map(|c| [c[0], c[1], c[2]])
+                .collect(),
+            None => vec![],
+        };
+
+        if layout.color_type == 3 && palette.is_empty() {
+            return Err(PngError::InvalidImageData(message(
+                "decode.no_palette",
+                &[],
+            )));
+        }
+
+        Ok(ColorConverter {
+            layout,
+            palette,
+            transparency: transparency.map(|c| c.data.clone()),
+        })
+    }
+
+    fn is_transparent(&self, samples: &[u16]) -> bool {
+        match &self.transparency {
+            Some(t) if t.len() >= samples.len() * 2 => samples
+                .iter()
+                .enumerate()
+                .all(|(i, s)| u16::from_be_bytes([t[i * 2], t[i * 2 + 1]]) == *s),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn expand(&self, row: &[u8], width: u32, out: &mut Vec<u8>) -> Result<(), PngError> {
+        out.clear();
+        let depth = self.layout.bit_depth;
+        let channels = self.layout.channels;
+
+        for x in 0..width as usize {
+            let s = |c: usize| sample(row, x * channels + c, depth);
+
+            let pixel = match self.layout.color_type {
+                0 => {
+                    let g = to_8bit(s(0), depth);
+                    let a = if self.is_transparent(&[s(0)]) { 0 } else { 255 };
+                    [g, g, g, a]
+                }
+                2 => {
+                    let a = if self.is_transparent(&[s(0), s(1), s(2)]) {
+                        0
+                    } else {
+                        255
+                    };
+                    [
+                        to_8bit(s(0), depth),
+                        to_8bit(s(1), depth),
+                        to_8bit(s(2), depth),
+                        a,
+                    ]
+                }
+                3 => {
+                    let index = s(0) as usize;
+                    let [r, g, b] = match self.palette.get(index) {
+                        Some(p) => *p,
+                        None => {
+                            return Err(PngError::InvalidImageData(message(
+                                "decode.palette_index",
+                                &[&index],
+                            )))
+                        }
+                    };
+                    let a = match &self.transparency {
+                        Some(t) => t.get(index).copied().unwrap_or(255),
+                        None => 255,
+                    };
+                    [r, g, b, a]
+                }
+                4 => {
+                    let g = to_8bit(s(0), depth);
+                    [g, g, g, to_8bit(s(1), depth)]
+                }
+                _ => [
+                    to_8bit(s(0), depth),
+                    to_8bit(s(1), depth),
+                    to_8bit(s(2), depth),
+                    to_8bit(s(3), depth),
+                ],
+            };
+
+            out.extend_from_slice(&pixel);
+        }
+
+        Ok(())
+    }
+}
+
+impl PngImage {
+    pub(crate) fn idat_reader(&self) -> ChunkDataReader<'_> {
+        ChunkDataReader::new(self.get_chunks("IDAT").map(|c| c.data.as_slice()).collect())
+    }
+
+    pub fn decode(&self) -> Result<PixelBuffer, PngError> {
+        Ok(self.decode_with_stats()?.0)
+    }
+
+    pub fn decode_with_stats(&self) -> Result<(PixelBuffer, CompressionStats), PngError> {
+        let start = Instant::now();
+        let layout = Layout::new(&self.info)?;
+        let converter =
+            ColorConverter::new(layout, self.get_chunk("PLTE"), self.get_chunk("tRNS"))?;
+
+        let mut pixels = PixelBuffer::new(layout.width, layout.height)?;
+        let mut reader = ScanlineReader::new(layout, self.idat_reader());
+        let mut rgba = vec![];
+
+        while let Some(line) = reader.next_row()? {
+            converter.expand(line.data, line.pass.width, &mut rgba)?;
+
+            let y = line.pass.y0 + line.y * line.pass.dy;
+            for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+                let x = line.pass.x0 + i as u32 * line.pass.dx;
+                pixels.set_pixel(x, y, [pixel[0], pixel[1], pixel[2], pixel[3]]);
+            }
+        }
+        reader.drain()?;
+
+        let stats = CompressionStats {
+            compressed_bytes: self.get_chunks("IDAT").map(|c| c.data.len() as u64).sum(),
+            uncompressed_bytes: reader.inflater.total_out(),
+            blocks: reader.inflater.blocks(),
+            duration: start.elapsed(),
+        };
+
+        Ok((pixels, stats))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_decode() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let pixels = image.decode().unwrap();
+
+        assert_eq!(pixels.width, 800);
+        assert_eq!(pixels.height, 600);
+        assert_eq!(pixels.data.len(), 800 * 600 * 4);
+    }
+
+    #[test]
+    fn test_decode_stats() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let (_, stats) = image.decode_with_stats().unwrap();
+
+        assert_eq!(stats.uncompressed_bytes, 600 * (1 + 800 * 4));
+        assert_eq!(
+            stats.compressed_bytes,
+            image.get_chunk("IDAT").unwrap().size as u64
+        );
+        assert!(stats.blocks.total() > 0);
+        assert!(stats.ratio() > 0.0 && stats.ratio() < 1.0);
+    }
+
+    #[test]
+    fn test_decode_checks_adler() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        let last = image
+            .chunks
+            .iter()
+            .rposition(|c| c.chunk_type == "IDAT")
+            .unwrap();
+        *image.chunks[last].data.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            image.decode_with_stats(),
+            Err(PngError::InvalidImageData(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_rejects_huge_dimensions() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image.info.width = u32::MAX / 2;
+        image.info.height = u32::MAX / 2;
+        assert!(matches!(image.decode(), Err(PngError::InvalidPngInfo(_))));
+    }
+
+    #[test]
+    fn test_passes() {
+        let info = PNGInfo {
+            width: 5,
+            height: 3,
+            bit_depth: 8,
+            color_type: 6,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: 1,
+        };
+        let layout = Layout::new(&info).unwrap();
+        let pixels: u32 = layout.passes().iter().map(|p| p.width * p.height).sum();
+        assert_eq!(pixels, 15);
+    }
+
+    #[test]
+    fn test_sub_byte_samples() {
+        let row = [0b1011_0010];
+        assert_eq!(sample(&row, 0, 2), 0b10);
+        assert_eq!(sample(&row, 1, 2), 0b11);
+        assert_eq!(sample(&row, 3, 2), 0b10);
+        assert_eq!(to_8bit(3, 2), 255);
+    }
+}
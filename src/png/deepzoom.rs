@@ -0,0 +1,124 @@
+// tile pyramids for deep zoom viewers
+//
+// writes the Deep Zoom layout OpenSeadragon and most map style viewers read:
+// an image.dzi descriptor next to image_files/<level>/<column>_<row>.png.
+// the top level is the full image, every level below halves it until a
+// single pixel is left, and each level is cut into tile_size squares.
+
+use std::{fs, path::Path};
+
+use super::{locale::message, EncodeOptions, PngError, PngImage, Rect};
+
+pub const DZI_NAME: &str = "image.dzi";
+pub const TILES_DIR: &str = "image_files";
+
+fn write(path: &Path, bytes: &[u8]) -> Result<(), PngError> {
+    fs::write(path, bytes).map_err(|_| PngError::SaveOperationFailed)
+}
+
+fn descriptor(width: u32, height: u32, tile_size: u32) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" \
+         Format=\"png\" Overlap=\"0\" TileSize=\"{}\">\n  \
+         <Size Width=\"{}\" Height=\"{}\"/>\n\
+         </Image>\n",
+        tile_size, width, height
+    )
+}
+
+// the level holding the full image, level 0 is 1x1
+fn top_level(width: u32, height: u32) -> u32 {
+    width.max(height).next_power_of_two().trailing_zeros()
+}
+
+impl PngImage {
+    // writes the pyramid into dir and returns how many tiles it holds
+    pub fn export_deepzoom(&self, dir: &str, tile_size: u32) -> Result<usize, PngError> {
+        if tile_size == 0 {
+            return Err(PngError::InvalidOperation(message(
+                "deepzoom.tile_size",
+                &[],
+            )));
+        }
+
+        let mut pixels = self.decode()?;
+        let dir = Path::new(dir);
+        let options = EncodeOptions::default();
+        let mut tiles = 0;
+
+        fs::create_dir_all(dir).map_err(|_| PngError::SaveOperationFailed)?;
+        write(
+            &dir.join(DZI_NAME),
+            descriptor(pixels.width, pixels.height, tile_size).as_bytes(),
+        )?;
+
+        for level in (0..=top_level(pixels.width, pixels.height)).rev() {
+            let level_dir = dir.join(TILES_DIR).join(level.to_string());
+            fs::create_dir_all(&level_dir).map_err(|_| PngError::SaveOperationFailed)?;
+
+            for row in 0..pixels.height.div_ceil(tile_size) {
+                for column in 0..pixels.width.div_ceil(tile_size) {
+                    let rect = Rect::new(column * tile_size, row * tile_size, tile_size, tile_size);
+                    let tile = PngImage::from_pixels(&pixels.crop(rect), &options)?;
+                    write(
+                        &level_dir.join(format!("{}_{}.png", column, row)),
+                        &tile.to_bytes(),
+                    )?;
+                    tiles += 1;
+                }
+            }
+
+            pixels = pixels.resize(pixels.width.div_ceil(2), pixels.height.div_ceil(2))?;
+        }
+
+        Ok(tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::PixelBuffer;
+    const IMAGE_PATH: &str = "./test.png";
+
+    fn read_tile(dir: &str, level: u32, column: u32, row: u32) -> PixelBuffer {
+        let path = Path::new(dir)
+            .join(TILES_DIR)
+            .join(level.to_string())
+            .join(format!("{}_{}.png", column, row));
+        PngImage::new(path.to_str().unwrap())
+            .unwrap()
+            .decode()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_export_deepzoom() {
+        let dir = "./save_test/deepzoom";
+        let _ = fs::remove_dir_all(dir);
+
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let tiles = image.export_deepzoom(dir, 256).unwrap();
+
+        // 800x600 takes 10 halvings to reach 1x1, 4x3 tiles on top, 2x2 for
+        // 400x300 and a single tile for each of the 9 levels below
+        assert_eq!(top_level(800, 600), 10);
+        assert_eq!(tiles, 12 + 4 + 9);
+
+        let dzi = fs::read_to_string(Path::new(dir).join(DZI_NAME)).unwrap();
+        assert!(dzi.contains("TileSize=\"256\""));
+        assert!(dzi.contains("Width=\"800\" Height=\"600\""));
+
+        // the last column and row are cut short
+        let corner = read_tile(dir, 10, 3, 2);
+        assert_eq!((corner.width, corner.height), (800 - 768, 600 - 512));
+        let full = image.decode().unwrap();
+        assert_eq!(corner, full.crop(Rect::new(768, 512, 32, 88)));
+
+        let smallest = read_tile(dir, 0, 0, 0);
+        assert_eq!((smallest.width, smallest.height), (1, 1));
+
+        assert!(image.export_deepzoom(dir, 0).is_err());
+    }
+}
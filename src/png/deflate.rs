@@ -0,0 +1,675 @@
+// streaming zlib/DEFLATE encoder (RFC 1950 / RFC 1951)
+//
+// input is buffered into 64K blocks, each block is run through a hash chain
+// LZ77 matcher against the previous 32K, then written out as whichever of a
+// stored, fixed or dynamic huffman block comes out smallest.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::{self, Write},
+};
+
+use super::{
+    checksum::Adler32,
+    inflate::{
+        fixed_literal_lengths, reverse_bits, CODE_LENGTH_ORDER, DIST_BASE, DIST_EXTRA, LENGTH_BASE,
+        LENGTH_EXTRA, WINDOW_SIZE,
+    },
+    stats::BlockCounts,
+};
+
+const BLOCK_SIZE: usize = 1 << 16;
+const HASH_BITS: usize = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const NO_POS: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Literal(u8),
+    Match { length: u16, distance: u16 },
+}
+
+// (max chain length, good enough match length, lazy matching) per level
+fn level_config(level: u8) -> (usize, usize, bool) {
+    match level {
+        0 => (0, 0, false),
+        1 => (4, 8, false),
+        2 => (8, 16, false),
+        3 => (16, 32, false),
+        4 => (16, 32, true),
+        5 => (32, 64, true),
+        6 => (128, 128, true),
+        7 => (256, MAX_MATCH, true),
+        8 => (1024, MAX_MATCH, true),
+        _ => (4096, MAX_MATCH, true),
+    }
+}
+
+struct BitWriter<W: Write> {
+    inner: W,
+    buf: u64,
+    count: u32,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn new(inner: W) -> Self {
+        BitWriter {
+            inner,
+            buf: 0,
+            count: 0,
+            bytes: vec![],
+        }
+    }
+
+    fn write_bits(&mut self, value: u32, n: u32) {
+        self.buf |= (value as u64) << self.count;
+        self.count += n;
+
+        while self.count >= 8 {
+            self.bytes.push(self.buf as u8);
+            self.buf >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.count > 0 {
+            self.write_bits(0, 8 - self.count);
+        }
+    }
+
+    fn flush_bytes(&mut self) -> io::Result<()> {
+        self.inner.write_all(&self.bytes)?;
+        self.bytes.clear();
+        Ok(())
+    }
+}
+
+pub(crate) struct Deflater<W: Write> {
+    out: BitWriter<W>,
+    level: u8,
+    history: Vec<u8>,
+    pending: Vec<u8>,
+    adler: Adler32,
+    header_written: bool,
+    blocks: BlockCounts,
+}
+
+impl<W: Write> Deflater<W> {
+    pub(crate) fn new(inner: W, level: u8) -> Self {
+        Deflater {
+            out: BitWriter::new(inner),
+            level: level.min(9),
+            history: vec![],
+            pending: vec![],
+            adler: Adler32::new(),
+            header_written: false,
+            blocks: BlockCounts::default(),
+        }
+    }
+
+    fn write_header(&mut self) {
+        if self.header_written {
+            return;
+        }
+
+        let level_flag = match self.level {
+            0..=1 => 0,
+            2..=5 => 1,
+            6 => 2,
+            _ => 3,
+        };
+        let cmf = 0x78u32;
+        let mut flg = level_flag << 6;
+        flg += 31 - ((cmf << 8) | flg) % 31;
+
+        self.out.write_bits(cmf, 8);
+        self.out.write_bits(flg, 8);
+        self.header_written = true;
+    }
+
+    fn compress_pending(&mut self, len: usize, final_block: bool) -> io::Result<()> {
+        let block: Vec<u8> = self.pending.drain(..len).collect();
+
+        if self.level == 0 {
+            self.write_stored(&block, final_block);
+        } else {
+            let mut data = std::mem::take(&mut self.history);
+            let start = data.len();
+            data.extend_from_slice(&block);
+
+            let tokens = find_matches(&data, start, self.level);
+            self.write_best_block(&block, &tokens, final_block);
+
+            let keep = data.len().saturating_sub(WINDOW_SIZE);
+            data.drain(..keep);
+            self.history = data;
+        }
+
+        self.out.flush_bytes()
+    }
+
+    fn write_stored(&mut self, block: &[u8], final_block: bool) {
+        let mut pieces: Vec<&[u8]> = block.chunks(65535).collect();
+        if pieces.is_empty() {
+            pieces.push(&[]);
+        }
+
+        let last = pieces.len() - 1;
+        for (i, piece) in pieces.into_iter().enumerate() {
+            self.out.write_bits((final_block && i == last) as u32, 1);
+            self.out.write_bits(0, 2);
+            self.blocks.add(0);
+            self.out.align();
+            self.out.write_bits(piece.len() as u32, 16);
+            self.out.write_bits(!(piece.len() as u32) & 0xFFFF, 16);
+            self.out.bytes.extend_from_slice(piece);
+        }
+    }
+
+    fn write_best_block(&mut self, block: &[u8], tokens: &[Token], final_block: bool) {
+        let mut lit_freq = [0u32; 286];
+        let mut dist_freq = [0u32; 30];
+        let mut extra_bits = 0u64;
+
+        for token in tokens {
+            match *token {
+                Token::Literal(b) => lit_freq[b as usize] += 1,
+                Token::Match { length, distance } => {
+                    let l = length_index(length);
+                    let d = dist_index(distance);
+                    lit_freq[257 + l] += 1;
+                    dist_freq[d] += 1;
+                    extra_bits += LENGTH_EXTRA[l] as u64 + DIST_EXTRA[d] as u64;
+                }
+            }
+        }
+        lit_freq[256] = 1;
+
+        let fixed_lit = fixed_literal_lengths();
+        let fixed_dist = [5u8; 30];
+        let fixed_cost =
+            3 + extra_bits + code_cost(&lit_freq, &fixed_lit) + code_cost(&dist_freq, &fixed_dist);
+
+        let dyn_lit = code_lengths(&lit_freq, 15);
+        let dyn_dist = code_lengths(&dist_freq, 15);
+        let header = DynamicHeader::new(&dyn_lit, &dyn_dist);
+        let dynamic_cost = 3
+            + header.cost()
+            + extra_bits
+            + code_cost(&lit_freq, &dyn_lit)
+            + code_cost(&dist_freq, &dyn_dist);
+
+        // stored blocks need a byte boundary plus 4 bytes of length per 64K
+        let stored_cost = 3 + 7 + (block.len() as u64 / 65535 + 1) * 32 + block.len() as u64 * 8;
+
+        if stored_cost <= fixed_cost && stored_cost <= dynamic_cost {
+            self.write_stored(block, final_block);
+        } else if fixed_cost <= dynamic_cost {
+            self.out.write_bits(final_block as u32, 1);
+            self.out.write_bits(1, 2);
+            self.blocks.add(1);
+            self.write_tokens(tokens, &fixed_lit, &fixed_dist);
+        } else {
+            self.out.write_bits(final_block as u32, 1);
+            self.out.write_bits(2, 2);
+            self.blocks.add(2);
+            header.write(&mut self.out);
+            self.write_tokens(tokens, &dyn_lit, &dyn_dist);
+        }
+    }
+
+    fn write_tokens(&mut self, tokens: &[Token], lit_lengths: &[u8], dist_lengths: &[u8]) {
+        let lit_codes = canonical_codes(lit_lengths);
+        let dist_codes = canonical_codes(dist_lengths);
+
+        for token in tokens {
+            match *token {
+                Token::Literal(b) => {
+                    self.out
+                        .write_bits(lit_codes[b as usize], lit_lengths[b as usize] as u32);
+                }
+                Token::Match { length, distance } => {
+                    let l = length_index(length);
+                    let d = dist_index(distance);
+                    self.out
+                        .write_bits(lit_codes[257 + l], lit_lengths[257 + l] as u32);
+                    self.out
+                        .write_bits((length - LENGTH_BASE[l]) as u32, LENGTH_EXTRA[l] as u32);
+                    self.out.write_bits(dist_codes[d], dist_lengths[d] as u32);
+                    self.out
+                        .write_bits((distance - DIST_BASE[d]) as u32, DIST_EXTRA[d] as u32);
+                }
+            }
+        }
+
+        self.out.write_bits(lit_codes[256], lit_lengths[256] as u32);
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        Ok(self.finish_with_blocks()?.0)
+    }
+
+    pub(crate) fn finish_with_blocks(mut self) -> io::Result<(W, BlockCounts)> {
+        self.write_header();
+        let len = self.pending.len();
+        self.compress_pending(len, true)?;
+
+        self.out.align();
+        let adler = self.adler.finish();
+        self.out.bytes.extend_from_slice(&adler.to_be_bytes());
+        self.out.flush_bytes()?;
+        self.out.inner.flush()?;
+        Ok((self.out.inner, self.blocks))
+    }
+}
+
+impl<W: Write> Write for Deflater<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_header();
+        self.adler.update(buf);
+        self.pending.extend_from_slice(buf);
+
+        while self.pending.len() >= BLOCK_SIZE {
+            self.compress_pending(BLOCK_SIZE, false)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.inner.flush()
+    }
+}
+
+// whole buffer helper for the formats the library defines itself
+pub(crate) fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+    let mut deflater = Deflater::new(vec![], level);
+    // writing into a vec can't fail
+    deflater.write_all(data).unwrap();
+    deflater.finish().unwrap()
+}
+
+fn length_index(length: u16) -> usize {
+    LENGTH_BASE.partition_point(|base| *base <= length) - 1
+}
+
+fn dist_index(distance: u16) -> usize {
+    DIST_BASE.partition_point(|base| *base <= distance) - 1
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    ((data[pos] as usize) << 10 ^ (data[pos + 1] as usize) << 5 ^ data[pos + 2] as usize)
+        & (HASH_SIZE - 1)
+}
+
+// LZ77 over data[start..], positions before start are only used as history
+fn find_matches(data: &[u8], start: usize, level: u8) -> Vec<Token> {
+    let (max_chain, good_len, lazy) = level_config(level);
+    let mut head = vec![NO_POS; HASH_SIZE];
+    let mut prev = vec![NO_POS; data.len()];
+    let mut tokens = Vec::with_capacity(data.len() - start);
+
+    let insert = |head: &mut Vec<u32>, prev: &mut Vec<u32>, pos: usize| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(data, pos);
+            prev[pos] = head[h];
+            head[h] = pos as u32;
+        }
+    };
+
+    let longest_match = |head: &Vec<u32>, prev: &Vec<u32>, pos: usize| -> (usize, usize) {
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut best = (0, 0);
+        let mut candidate = head[hash(data, pos)];
+        let mut chain = max_chain;
+
+        while candidate != NO_POS && chain > 0 {
+            let c = candidate as usize;
+            let distance = pos - c;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+
+            if data[c + best.0] == data[pos + best.0] {
+                let mut len = 0;
+                while len < max_len && data[c + len] == data[pos + len] {
+                    len += 1;
+                }
+
+                if len > best.0 {
+                    best = (len, distance);
+                    if len >= good_len || len == max_len {
+                        break;
+                    }
+                }
+            }
+
+            candidate = prev[c];
+            chain -= 1;
+        }
+
+        best
+    };
+
+    for pos in start.saturating_sub(WINDOW_SIZE)..start {
+        insert(&mut head, &mut prev, pos);
+    }
+
+    let mut pos = start;
+    while pos < data.len() {
+        let (len, distance) = longest_match(&head, &prev, pos);
+        insert(&mut head, &mut prev, pos);
+
+        if len >= MIN_MATCH && lazy && len < good_len && pos + 1 < data.len() {
+            let (next_len, _) = longest_match(&head, &prev, pos + 1);
+            if next_len > len {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+                continue;
+            }
+        }
+
+        if len >= MIN_MATCH {
+            tokens.push(Token::Match {
+                length: len as u16,
+                distance: distance as u16,
+            });
+            for p in pos + 1..pos + len {
+                insert(&mut head, &mut prev, p);
+            }
+            pos += len;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    tokens
+}
+
+fn code_cost(freqs: &[u32], lengths: &[u8]) -> u64 {
+    freqs
+        .iter()
+        .zip(lengths)
+        .map(|(f, l)| *f as u64 * *l as u64)
+        .sum()
+}
+
+// huffman code lengths for the given frequencies, no longer than limit and
+// always forming a complete code so strict decoders accept them
+pub(crate) fn code_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|i| freqs[*i] > 0).collect();
+
+    if used.len() < 2 {
+        // a single symbol still needs a partner to make a complete code
+        let first = used.first().copied().unwrap_or(0);
+        let second = if first == 0 { 1 } else { 0 };
+        lengths[first] = 1;
+        lengths[second] = 1;
+        return lengths;
+    }
+
+    // plain huffman tree, leaves are 0..n and internal nodes follow
+    let mut parent = vec![0usize; used.len() * 2 - 1];
+    let mut heap = BinaryHeap::new();
+    for (node, symbol) in used.iter().enumerate() {
+        heap.push(Reverse((freqs[*symbol] as u64, node)));
+    }
+
+    let mut next = used.len();
+    while heap.len() > 1 {
+        let Reverse((fa, a)) = heap.pop().unwrap();
+        let Reverse((fb, b)) = heap.pop().unwrap();
+        parent[a] = next;
+        parent[b] = next;
+        heap.push(Reverse((fa + fb, next)));
+        next += 1;
+    }
+
+    let root = next - 1;
+    let mut depth = vec![0u32; parent.len()];
+    for node in (0..root).rev() {
+        depth[node] = depth[parent[node]] + 1;
+    }
+
+    for (node, symbol) in used.iter().enumerate() {
+        lengths[*symbol] = depth[node].min(limit as u32) as u8;
+    }
+
+    // clamping can over-subscribe the code, lengthen codes until the kraft
+    // sum fits and then shorten the longest codes to fill any slack
+    let capacity = 1u64 << limit;
+    let kraft = |lengths: &[u8]| -> u64 {
+        lengths
+            .iter()
+            .filter(|l| **l > 0)
+            .map(|l| 1u64 << (limit - *l))
+            .sum()
+    };
+
+    let mut sum = kraft(&lengths);
+    while sum > capacity {
+        let symbol = used
+            .iter()
+            .copied()
+            .filter(|s| lengths[*s] < limit)
+            .max_by_key(|s| (lengths[*s], Reverse(freqs[*s])))
+            .unwrap();
+        lengths[symbol] += 1;
+        sum -= 1u64 << (limit - lengths[symbol]);
+    }
+
+    while sum < capacity {
+        let symbol = used
+            .iter()
+            .copied()
+            .max_by_key(|s| (lengths[*s], freqs[*s]))
+            .unwrap();
+        sum += 1u64 << (limit - lengths[symbol]);
+        lengths[symbol] -= 1;
+    }
+
+    lengths
+}
+
+// bit reversed canonical codes, ready to be written lsb first
+pub(crate) fn canonical_codes(lengths: &[u8]) -> Vec<u32> {
+    let mut counts = [0u32; 16];
+    for l in lengths {
+        counts[*l as usize] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next_code = [0u32; 16];
+    let mut code = 0;
+    for len in 1..16 {
+        code = (code + counts[len - 1]) << 1;
+        next_code[len] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|l| {
+            if *l == 0 {
+                return 0;
+            }
+            let code = next_code[*l as usize];
+            next_code[*l as usize] += 1;
+            reverse_bits(code, *l)
+        })
+        .collect()
+}
+
+struct DynamicHeader {
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+    // (code length symbol, extra bits value)
+    symbols: Vec<(u8, u8)>,
+    cl_lengths: Vec<u8>,
+}
+
+impl DynamicHeader {
+    fn new(lit_lengths: &[u8], dist_lengths: &[u8]) -> Self {
+        let hlit = 257.max(lit_lengths.iter().rposition(|l| *l > 0).unwrap_or(0) + 1);
+        let hdist = 1.max(dist_lengths.iter().rposition(|l| *l > 0).unwrap_or(0) + 1);
+
+        let mut all = lit_lengths[..hlit].to_vec();
+        all.extend_from_slice(&dist_lengths[..hdist]);
+
+        let symbols = run_length_encode(&all);
+        let mut cl_freq = [0u32; 19];
+        for (symbol, _) in &symbols {
+            cl_freq[*symbol as usize] += 1;
+        }
+
+        let cl_lengths = code_lengths(&cl_freq, 7);
+        let hclen = 4.max(
+            CODE_LENGTH_ORDER
+                .iter()
+                .rposition(|i| cl_lengths[*i] > 0)
+                .unwrap_or(0)
+                + 1,
+        );
+
+        DynamicHeader {
+            hlit,
+            hdist,
+            hclen,
+            symbols,
+            cl_lengths,
+        }
+    }
+
+    fn cost(&self) -> u64 {
+        let mut bits = 5 + 5 + 4 + 3 * self.hclen as u64;
+        for (symbol, _) in &self.symbols {
+            bits += self.cl_lengths[*symbol as usize] as u64;
+            bits += match symbol {
+                16 => 2,
+                17 => 3,
+                18 => 7,
+                _ => 0,
+            };
+        }
+        bits
+    }
+
+    fn write<W: Write>(&self, out: &mut BitWriter<W>) {
+        out.write_bits((self.hlit - 257) as u32, 5);
+        out.write_bits((self.hdist - 1) as u32, 5);
+        out.write_bits((self.hclen - 4) as u32, 4);
+
+        for i in CODE_LENGTH_ORDER.iter().take(self.hclen) {
+            out.write_bits(self.cl_lengths[*i] as u32, 3);
+        }
+
+        let codes = canonical_codes(&self.cl_lengths);
+        for (symbol, extra) in &self.symbols {
+            let s = *symbol as usize;
+            out.write_bits(codes[s], self.cl_lengths[s] as u32);
+            match symbol {
+                16 => out.write_bits(*extra as u32, 2),
+                17 => out.write_bits(*extra as u32, 3),
+                18 => out.write_bits(*extra as u32, 7),
+                _ => (),
+            }
+        }
+    }
+}
+
+fn run_length_encode(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut symbols = vec![];
+    let mut i = 0;
+
+    while i < lengths.len() {
+        let value = lengths[i];
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == value {
+            run += 1;
+        }
+        i += run;
+
+        if value == 0 {
+            while run >= 11 {
+                let r = run.min(138);
+                symbols.push((18, (r - 11) as u8));
+                run -= r;
+            }
+            if run >= 3 {
+                symbols.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            symbols.push((value, 0));
+            run -= 1;
+            while run >= 3 {
+                let r = run.min(6);
+                symbols.push((16, (r - 3) as u8));
+                run -= r;
+            }
+        }
+
+        for _ in 0..run {
+            symbols.push((value, 0));
+        }
+    }
+
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::inflate::inflate;
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let mut data = vec![];
+        for i in 0..200_000u32 {
+            data.push((i % 251) as u8 ^ (i / 1000) as u8);
+        }
+
+        for level in [0, 1, 6, 9] {
+            let compressed = deflate(&data, level);
+            assert_eq!(inflate(&compressed, usize::MAX).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_deflate_compresses_repetition() {
+        let data = b"whats a png? ".repeat(1000);
+        let compressed = deflate(&data, 6);
+        assert!(compressed.len() < data.len() / 20);
+        assert_eq!(inflate(&compressed, usize::MAX).unwrap(), data);
+    }
+
+    #[test]
+    fn test_deflate_empty() {
+        assert_eq!(inflate(&deflate(&[], 6), usize::MAX).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_code_lengths_limited() {
+        // fibonacci frequencies make the deepest possible huffman tree
+        let mut freqs = vec![1u32, 1];
+        for i in 2..30 {
+            freqs.push(freqs[i - 1] + freqs[i - 2]);
+        }
+
+        let lengths = code_lengths(&freqs, 15);
+        assert!(lengths.iter().all(|l| *l <= 15));
+        let kraft: u64 = lengths.iter().map(|l| 1u64 << (15 - l)).sum();
+        assert_eq!(kraft, 1 << 15);
+    }
+}
@@ -0,0 +1,276 @@
+// points at the bytes that make a file fail to parse
+//
+// diagnose walks the raw chunk structure on its own, so it can explain files
+// the parser rejects, and render prints an annotated hex dump around the
+// problem:
+//
+//   error: IDAT chunk runs past the end of the file
+//    --> offset 0x00000021
+//            |
+//   00000010 | 00 00 03 20 00 00 02 58 08 06 00 00 00 9a 76 82  |.......X......v.|
+//   00000020 | 70 00 03 76 3c 49 44 41 54 78 01 ec c6 05 a1 86  |p..v<IDATx......|
+//            |    ^^ ^^ ^^ ^^ length is 226876 but only 55 bytes remain
+//   00000030 | 00 18 03 40 dc 4a d3 87 12 14 a0 d3 d0 02 cf e5  |...@.J..........|
+
+use super::{checksum::Crc32, locale::message, PNG_SIGNATURE};
+
+const ROW_BYTES: usize = 16;
+// largest chunk length the spec allows
+const MAX_CHUNK_SIZE: u32 = (1 << 31) - 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    // the offending bytes
+    pub offset: usize,
+    pub len: usize,
+    // printed under the offending bytes
+    pub label: String,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    fn new(message: String, offset: usize, len: usize, label: String) -> Self {
+        Diagnostic {
+            message,
+            offset,
+            len: len.max(1),
+            label,
+            help: None,
+        }
+    }
+
+    fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    // the message plus a hex dump of the surrounding rows with the span underlined
+    pub fn render(&self, bytes: &[u8]) -> String {
+        let mut out = format!(
+            "{}\n --> {} 0x{:08x}\n         |\n",
+            message("diag.error", &[&self.message]),
+            message("diag.offset", &[]),
+            self.offset
+        );
+
+        let end = self.offset + self.len;
+        if !bytes.is_empty() {
+            let first_row = (self.offset / ROW_BYTES).saturating_sub(1);
+            let last_row = ((end - 1) / ROW_BYTES + 1).min((bytes.len() - 1) / ROW_BYTES);
+
+            for row in first_row..=last_row {
+                let start = row * ROW_BYTES;
+                let line = &bytes[start..(start + ROW_BYTES).min(bytes.len())];
+
+                let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                let ascii: String = line
+                    .iter()
+                    .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                    .collect();
+                out += &format!("{:08x} | {:<47}  |{}|\n", start, hex.join(" "), ascii);
+
+                // carets under the part of the span on this row
+                let from = self.offset.max(start);
+                let to = end.min(start + ROW_BYTES);
+                if from < to {
+                    let pad = " ".repeat((from - start) * 3);
+                    let carets = vec!["^^"; to - from].join(" ");
+                    let label = if to == end { &self.label } else { "" };
+                    out += &format!("         | {}{} {}\n", pad, carets, label);
+                }
+            }
+        }
+
+        // spans past the end of the file have no bytes to underline
+        if self.offset >= bytes.len() {
+            out += &format!("         | {}\n", self.label);
+        }
+        if let Some(help) = &self.help {
+            out += &format!("         = {}\n", message("diag.help", &[help]));
+        }
+
+        out
+    }
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn check_signature(bytes: &[u8]) -> Option<Diagnostic> {
+    let len = bytes.len().min(8);
+    if bytes[..len] == PNG_SIGNATURE[..len] && len == 8 {
+        return None;
+    }
+
+    let diagnostic = Diagnostic::new(
+        message("diag.not_png", &[]),
+        0,
+        len,
+        message("diag.expected_signature", &[&"89 50 4e 47 0d 0a 1a 0a"]),
+    );
+
+    // "\r\n" turned into "\n" or the high bit stripped by a text mode transfer
+    if bytes.get(1..4) == Some(b"PNG") || bytes.get(0..3) == Some(&[0x09, 0x50, 0x4e]) {
+        return Some(diagnostic.with_help(message("diag.text_mode", &[])));
+    }
+
+    Some(diagnostic)
+}
+
+// the first structural problem in the file, None if the chunk layout is sound
+pub fn diagnose(bytes: &[u8]) -> Option<Diagnostic> {
+    if let Some(d) = check_signature(bytes) {
+        return Some(d);
+    }
+
+    let mut pos = PNG_SIGNATURE.len();
+    let mut first = true;
+
+    loop {
+        let remaining = bytes.len() - pos;
+        if remaining == 0 {
+            return Some(Diagnostic::new(
+                message("diag.no_iend", &[]),
+                pos - 4,
+                4,
+                message("diag.last_chunk", &[]),
+            ));
+        }
+        if remaining < 8 {
+            return Some(Diagnostic::new(
+                message("diag.header_cut", &[]),
+                pos,
+                remaining,
+                message("diag.header_bytes", &[&remaining]),
+            ));
+        }
+
+        let size = be_u32(bytes, pos);
+        let chunk_type = &bytes[pos + 4..pos + 8];
+        let name = String::from_utf8_lossy(chunk_type);
+
+        if !chunk_type.iter().all(|b| b.is_ascii_alphabetic()) {
+            return Some(Diagnostic::new(
+                message("diag.bad_type", &[]),
+                pos + 4,
+                4,
+                message("diag.type_letters", &[]),
+            ));
+        }
+
+        if first && chunk_type != b"IHDR" {
+            return Some(Diagnostic::new(
+                message("diag.first_not_ihdr", &[]),
+                pos + 4,
+                4,
+                message("diag.found", &[&name]),
+            ));
+        }
+        if chunk_type == b"IHDR" && size != 13 {
+            return Some(Diagnostic::new(
+                message("diag.ihdr_size", &[]),
+                pos,
+                4,
+                message("diag.length_is", &[&size]),
+            ));
+        }
+
+        let available = remaining - 8;
+        if size > MAX_CHUNK_SIZE || size as usize + 4 > available {
+            return Some(Diagnostic::new(
+                message("diag.past_end", &[&name]),
+                pos,
+                4,
+                message(
+                    "diag.length_remaining",
+                    &[&size, &available.saturating_sub(4)],
+                ),
+            ));
+        }
+
+        let data_end = pos + 8 + size as usize;
+        let mut crc = Crc32::new();
+        crc.update(&bytes[pos + 4..data_end]);
+        let stored = be_u32(bytes, data_end);
+
+        if stored != crc.finish() {
+            return Some(Diagnostic::new(
+                message("diag.crc_mismatch", &[&name]),
+                data_end,
+                4,
+                message("diag.computed", &[&format!("{:08x}", crc.finish())]),
+            ));
+        }
+
+        if chunk_type == b"IEND" {
+            return None;
+        }
+
+        pos = data_end + 4;
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::PngImage;
+    use std::fs;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_diagnose_sound_file() {
+        assert_eq!(diagnose(&fs::read(IMAGE_PATH).unwrap()), None);
+    }
+
+    #[test]
+    fn test_diagnose_bad_crc() {
+        let mut bytes = fs::read(IMAGE_PATH).unwrap();
+        // the IHDR crc sits after the signature, the chunk header and 13 bytes
+        bytes[29] ^= 0xff;
+
+        let d = diagnose(&bytes).unwrap();
+        assert_eq!(d.message, "CRC mismatch in IHDR chunk");
+        assert_eq!((d.offset, d.len), (29, 4));
+
+        let rendered = d.render(&bytes);
+        assert!(rendered.contains("00000010 | "));
+        // the crc straddles two rows, the label goes under the last one
+        assert!(rendered.contains(&format!("         | {}^^ ^^ ^^ \n", " ".repeat(13 * 3))));
+        assert!(rendered.contains("         | ^^ computed "));
+    }
+
+    #[test]
+    fn test_diagnose_truncated() {
+        let bytes = fs::read(IMAGE_PATH).unwrap();
+
+        // cut into the IDAT data, the parser has to fail instead of spinning
+        let cut = &bytes[..100];
+        assert!(PngImage::from_bytes(cut.to_vec()).is_err());
+        let d = diagnose(cut).unwrap();
+        assert_eq!(d.message, "IDAT chunk runs past the end of the file");
+        assert_eq!(d.offset, 33);
+
+        let d = diagnose(&bytes[..8]).unwrap();
+        assert_eq!(d.message, "file ends without an IEND chunk");
+        assert!(d.render(&bytes[..8]).contains("the last chunk ends here"));
+    }
+
+    #[test]
+    fn test_diagnose_signature() {
+        let mut bytes = fs::read(IMAGE_PATH).unwrap();
+        bytes.remove(4);
+
+        let d = diagnose(&bytes).unwrap();
+        assert_eq!(d.message, "not a png file");
+        assert!(d.help.is_some());
+        assert!(diagnose(b"GIF89a").unwrap().help.is_none());
+    }
+}
@@ -0,0 +1,481 @@
+// writes decoded pixels back out as IHDR + IDAT chunks
+
+use std::{
+    io::{self, Write},
+    sync::Arc,
+    time::Instant,
+};
+
+use super::{
+    deflate::Deflater,
+    filter::{
+        choose_adaptive, filter_row, FilterStrategy, FILTER_AVERAGE, FILTER_NONE, FILTER_PAETH,
+        FILTER_SUB, FILTER_UP,
+    },
+    locale::message,
+    stats::CompressionStats,
+    MetadataPolicy, PNGChunk, PNGInfo, PixelBuffer, PngError, PngImage,
+};
+
+// chunks whose contents only make sense for the color type they were written with
+const COLOR_DEPENDENT_CHUNKS: [&str; 5] = ["PLTE", "tRNS", "bKGD", "sBIT", "hIST"];
+
+// chunks browsers need to show the colors right
+const COLOR_SPACE_CHUNKS: [&str; 5] = ["gAMA", "cHRM", "sRGB", "iCCP", "cICP"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterType {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    // pick the filter per row with the minimum sum of absolute differences
+    Adaptive,
+}
+
+impl FilterStrategy for FilterType {
+    fn choose_filter(&self, row: &[u8], prev: &[u8], bpp: usize) -> u8 {
+        match self {
+            FilterType::None => FILTER_NONE,
+            FilterType::Sub => FILTER_SUB,
+            FilterType::Up => FILTER_UP,
+            FilterType::Average => FILTER_AVERAGE,
+            FilterType::Paeth => FILTER_PAETH,
+            FilterType::Adaptive => choose_adaptive(row, prev, bpp),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EncodeOptions {
+    // a FilterType or any custom strategy
+    pub filter: Arc<dyn FilterStrategy>,
+    // 0 stores the data uncompressed, 9 searches hardest for matches
+    pub compression_level: u8,
+    // maximum payload size of each IDAT chunk, streaming transcodes also
+    // hold this much compressed data in memory before writing a chunk
+    pub idat_size: usize,
+    // which ancillary chunks survive when an existing image is re-encoded
+    pub metadata: MetadataPolicy,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            filter: Arc::new(FilterType::Adaptive),
+            compression_level: 6,
+            idat_size: 8192,
+            metadata: MetadataPolicy::Keep,
+        }
+    }
+}
+
+// named bundles of the options above. there is no compression backend to
+// pick between: the crate only depends on std, so its own deflate in
+// deflate.rs is the one backend, and it already writes each block as stored,
+// fixed or dynamic huffman, whichever is smallest. compression_level is how a
+// preset trades speed for size. EncodeOptions is #[non_exhaustive], so a
+// backend field can be added without breaking callers if a second one lands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Preset {
+    // small files that still look the same in a browser
+    Web,
+    // keeps every chunk and compresses as hard as it can
+    Archive,
+    // no filtering and the quickest deflate level, for previews and temp files
+    Fastest,
+    // every byte counts, all metadata goes
+    Smallest,
+}
+
+impl Preset {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "web" => Some(Preset::Web),
+            "archive" => Some(Preset::Archive),
+            "fastest" => Some(Preset::Fastest),
+            "smallest" => Some(Preset::Smallest),
+            _ => None,
+        }
+    }
+}
+
+impl EncodeOptions {
+    pub fn preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Web => EncodeOptions {
+                filter: Arc::new(FilterType::Adaptive),
+                compression_level: 9,
+                idat_size: 8192,
+                metadata: MetadataPolicy::KeepOnly(
+                    COLOR_SPACE_CHUNKS.iter().map(|t| t.to_string()).collect(),
+                ),
+            },
+            Preset::Archive => EncodeOptions {
+                filter: Arc::new(FilterType::Adaptive),
+                compression_level: 9,
+                idat_size: 65536,
+                metadata: MetadataPolicy::Keep,
+            },
+            Preset::Fastest => EncodeOptions {
+                filter: Arc::new(FilterType::None),
+                compression_level: 1,
+                idat_size: 65536,
+                metadata: MetadataPolicy::Keep,
+            },
+            // every IDAT costs 12 bytes of framing, 1 MiB chunks make that
+            // negligible while keeping the transcode buffer bounded
+            Preset::Smallest => EncodeOptions {
+                filter: Arc::new(FilterType::Adaptive),
+                compression_level: 9,
+                idat_size: 1 << 20,
+                metadata: MetadataPolicy::Strip,
+            },
+        }
+    }
+}
+
+// filters scanlines and feeds them through a deflater one row at a time
+pub(crate) struct RowEncoder<W: Write> {
+    deflater: Deflater<W>,
+    filter: Arc<dyn FilterStrategy>,
+    bpp: usize,
+    prev: Vec<u8>,
+    filtered: Vec<u8>,
+    written: u64,
+}
+
+impl<W: Write> RowEncoder<W> {
+    pub(crate) fn new(writer: W, bpp: usize, options: &EncodeOptions) -> Self {
+        RowEncoder {
+            deflater: Deflater::new(writer, options.compression_level),
+            filter: options.filter.clone(),
+            bpp,
+            prev: vec![],
+            filtered: vec![],
+            written: 0,
+        }
+    }
+
+    // the first row of every interlace pass is filtered against zeros
+    pub(crate) fn start_pass(&mut self) {
+        self.prev.clear();
+    }
+
+    pub(crate) fn write_row(&mut self, row: &[u8]) -> io::Result<()> {
+        self.write_row_as(row, None)
+    }
+
+    // writes the row with a fixed filter type instead of the configured one
+    pub(crate) fn write_row_as(&mut self, row: &[u8], filter: Option<u8>) -> io::Result<()> {
+        if self.prev.len() != row.len() {
+            self.prev = vec![0; row.len()];
+        }
+
+        let filter = match filter {
+            Some(f) => f,
+            None => self.filter.choose_filter(row, &self.prev, self.bpp),
+        };
+
+        if filter > FILTER_PAETH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                message("filter.unknown", &[&filter]),
+            ));
+        }
+
+        filter_row(filter, self.bpp, row, &self.prev, &mut self.filtered);
+        self.deflater.write_all(&[filter])?;
+        self.deflater.write_all(&self.filtered)?;
+        self.written += 1 + row.len() as u64;
+        self.prev.clear();
+        self.prev.extend_from_slice(row);
+        Ok(())
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        self.deflater.finish()
+    }
+
+    // the compressed size and duration are left for the caller to fill in
+    pub(crate) fn finish_with_stats(self) -> io::Result<(W, CompressionStats)> {
+        let (writer, blocks) = self.deflater.finish_with_blocks()?;
+        let stats = CompressionStats {
+            uncompressed_bytes: self.written,
+            blocks,
+            ..Default::default()
+        };
+
+        Ok((writer, stats))
+    }
+}
+
+// filters and compresses the scanlines into a zlib stream
+pub(crate) fn compress_rows<'a>(
+    rows: impl Iterator<Item = &'a [u8]>,
+    bpp: usize,
+    options: &EncodeOptions,
+) -> Result<(Vec<u8>, CompressionStats), PngError> {
+    let mut encoder = RowEncoder::new(vec![], bpp, options);
+
+    // writing into a vec only fails on a bad filter type from a custom strategy
+    for row in rows {
+        encoder
+            .write_row(row)
+            .map_err(|e| PngError::InvalidOperation(e.to_string()))?;
+    }
+
+    let (compressed, mut stats) = encoder.finish_with_stats().unwrap();
+    stats.compressed_bytes = compressed.len() as u64;
+    Ok((compressed, stats))
+}
+
+pub(crate) fn split_idat(compressed: &[u8], idat_size: usize) -> Vec<PNGChunk> {
+    compressed
+        .chunks(idat_size.max(1))
+        .map(|c| PNGChunk::new("IDAT", c.to_vec()))
+        .collect()
+}
+
+fn rgba_info(pixels: &PixelBuffer) -> PNGInfo {
+    PNGInfo {
+        width: pixels.width,
+        height: pixels.height,
+        bit_depth: 8,
+        color_type: 6,
+        compression_method: 0,
+        filter_method: 0,
+        interlace_method: 0,
+    }
+}
+
+fn encode_idat(
+    pixels: &PixelBuffer,
+    options: &EncodeOptions,
+) -> Result<(Vec<PNGChunk>, CompressionStats), PngError> {
+    let start = Instant::now();
+    if pixels.width == 0 || pixels.height == 0 {
+        return Err(PngError::InvalidOperation(message("encode.empty", &[])));
+    }
+
+    let rows = (0..pixels.height).map(|y| pixels.row(y));
+    let (compressed, mut stats) = compress_rows(rows, 4, options)?;
+    let chunks = split_idat(&compressed, options.idat_size);
+    stats.duration = start.elapsed();
+    Ok((chunks, stats))
+}
+
+impl PngImage {
+    pub fn from_pixels(pixels: &PixelBuffer, options: &EncodeOptions) -> Result<Self, PngError> {
+        Ok(Self::from_pixels_with_stats(pixels, options)?.0)
+    }
+
+    pub fn from_pixels_with_stats(
+        pixels: &PixelBuffer,
+        options: &EncodeOptions,
+    ) -> Result<(Self, CompressionStats), PngError> {
+        let info = rgba_info(pixels);
+        let (idat, stats) = encode_idat(pixels, options)?;
+        let mut chunks = vec![info.to_chunk()];
+        chunks.extend(idat);
+        chunks.push(PNGChunk::new("IEND", vec![]));
+
+        Ok((PngImage { info, chunks }, stats))
+    }
+
+    // replaces the image data while keeping the other chunks where they were,
+    // chunks tied to the old color type are dropped since the new data is RGBA
+    // and so is anything options.metadata doesn't keep
+    pub fn set_pixels(
+        &mut self,
+        pixels: &PixelBuffer,
+        options: &EncodeOptions,
+    ) -> Result<CompressionStats, PngError> {
+        let info = rgba_info(pixels);
+        let (idat, stats) = encode_idat(pixels, options)?;
+        let mut idat = Some(idat);
+        let mut chunks = vec![];
+
+        for chunk in self.chunks.drain(..) {
+            match chunk.chunk_type.as_str() {
+                "IHDR" => chunks.push(info.to_chunk()),
+                "IDAT" => {
+                    if let Some(idat) = idat.take() {
+                        chunks.extend(idat);
+                    }
+                }
+                t if COLOR_DEPENDENT_CHUNKS.contains(&t) => (),
+                t if !options.metadata.keeps(t) => (),
+                _ => chunks.push(chunk),
+            }
+        }
+
+        self.chunks = chunks;
+        self.info = info;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_encode_round_trip() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let pixels = image.decode().unwrap();
+
+        let encoded = PngImage::from_pixels(&pixels, &EncodeOptions::default()).unwrap();
+        let reparsed = PngImage::from_bytes(encoded.to_bytes()).unwrap();
+        assert_eq!(reparsed.decode().unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_encode_stats() {
+        let pixels = PixelBuffer::new(300, 300).unwrap();
+        let options = EncodeOptions {
+            compression_level: 0,
+            ..Default::default()
+        };
+
+        let (image, stats) = PngImage::from_pixels_with_stats(&pixels, &options).unwrap();
+        let idat: u64 = image.get_chunks("IDAT").map(|c| c.size as u64).sum();
+        assert_eq!(stats.compressed_bytes, idat);
+        assert_eq!(stats.uncompressed_bytes, 300 * (1 + 300 * 4));
+        // five full 64K blocks need two stored blocks each, plus the rest
+        assert_eq!(stats.blocks.stored, 11);
+        assert_eq!(stats.blocks.total(), 11);
+
+        let (_, decoded) = image.decode_with_stats().unwrap();
+        assert_eq!(decoded.blocks, stats.blocks);
+    }
+
+    #[test]
+    fn test_encode_filters() {
+        let mut pixels = PixelBuffer::new(17, 9).unwrap();
+        for (i, b) in pixels.data.iter_mut().enumerate() {
+            *b = (i * 31 % 256) as u8;
+        }
+
+        for filter in [
+            FilterType::None,
+            FilterType::Sub,
+            FilterType::Up,
+            FilterType::Average,
+            FilterType::Paeth,
+        ] {
+            let options = EncodeOptions {
+                filter: Arc::new(filter),
+                compression_level: 0,
+                idat_size: 64,
+                ..Default::default()
+            };
+            let image = PngImage::from_pixels(&pixels, &options).unwrap();
+            assert!(image.get_chunks("IDAT").count() > 1);
+            assert_eq!(image.decode().unwrap(), pixels);
+        }
+    }
+
+    // sees every row the encoder writes and cycles through the filter types
+    #[derive(Debug, Default)]
+    struct Cycle {
+        rows: std::sync::Mutex<Vec<(Vec<u8>, Vec<u8>)>>,
+    }
+
+    impl FilterStrategy for Cycle {
+        fn choose_filter(&self, row: &[u8], prev: &[u8], _bpp: usize) -> u8 {
+            let mut rows = self.rows.lock().unwrap();
+            rows.push((row.to_vec(), prev.to_vec()));
+            (rows.len() % 5) as u8
+        }
+    }
+
+    #[derive(Debug)]
+    struct Broken;
+
+    impl FilterStrategy for Broken {
+        fn choose_filter(&self, _row: &[u8], _prev: &[u8], _bpp: usize) -> u8 {
+            7
+        }
+    }
+
+    #[test]
+    fn test_custom_filter_strategy() {
+        let mut pixels = PixelBuffer::new(5, 6).unwrap();
+        for (i, b) in pixels.data.iter_mut().enumerate() {
+            *b = (i * 7 % 256) as u8;
+        }
+
+        let strategy = Arc::new(Cycle::default());
+        let options = EncodeOptions {
+            filter: strategy.clone(),
+            ..Default::default()
+        };
+        let image = PngImage::from_pixels(&pixels, &options).unwrap();
+        assert_eq!(image.decode().unwrap(), pixels);
+
+        let rows = strategy.rows.lock().unwrap();
+        assert_eq!(rows.len(), 6);
+        assert_eq!(rows[0].1, vec![0; 20]);
+        assert_eq!(rows[3].1, pixels.row(2));
+
+        let options = EncodeOptions {
+            filter: Arc::new(Broken),
+            ..Default::default()
+        };
+        assert!(PngImage::from_pixels(&pixels, &options).is_err());
+    }
+
+    #[test]
+    fn test_set_pixels_keeps_chunk_order() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image
+            .chunks
+            .insert(1, PNGChunk::new("tEXt", b"Title\0test".to_vec()));
+
+        let pixels = PixelBuffer::new(2, 2).unwrap();
+        image
+            .set_pixels(&pixels, &EncodeOptions::default())
+            .unwrap();
+
+        let types: Vec<&str> = image.chunks.iter().map(|c| c.chunk_type.as_str()).collect();
+        assert_eq!(types, ["IHDR", "tEXt", "IDAT", "IEND"]);
+        assert_eq!(image.info.width, 2);
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image
+            .chunks
+            .insert(1, PNGChunk::new("tEXt", b"Title\0test".to_vec()));
+        image
+            .chunks
+            .insert(1, PNGChunk::new("gAMA", 45455u32.to_be_bytes().to_vec()));
+        let pixels = image.decode().unwrap();
+
+        let mut sizes = vec![];
+        for (preset, kept) in [
+            (Preset::Fastest, 2),
+            (Preset::Web, 1),
+            (Preset::Smallest, 0),
+            (Preset::Archive, 2),
+        ] {
+            let mut encoded = image.clone();
+            encoded
+                .set_pixels(&pixels, &EncodeOptions::preset(preset))
+                .unwrap();
+
+            let ancillary = encoded.chunks.iter().filter(|c| !c.is_critical()).count();
+            assert_eq!(ancillary, kept);
+            assert_eq!(encoded.decode().unwrap(), pixels);
+            sizes.push(encoded.to_bytes().len());
+        }
+
+        assert!(sizes[0] > sizes[2]);
+    }
+}
@@ -0,0 +1,150 @@
+// scanline filters from the png spec, each row is prefixed with its filter type
+
+use std::fmt::Debug;
+
+use super::{locale::message, PngError};
+
+pub const FILTER_NONE: u8 = 0;
+pub const FILTER_SUB: u8 = 1;
+pub const FILTER_UP: u8 = 2;
+pub const FILTER_AVERAGE: u8 = 3;
+pub const FILTER_PAETH: u8 = 4;
+
+// decides which filter type the encoder uses for each scanline. row is the
+// unfiltered row, prev the previous unfiltered row of the same interlace pass
+// (zeros for the first) and bpp the filter's byte distance to the left pixel.
+// strategies are shared between encodes, keep any state behind a lock.
+pub trait FilterStrategy: Debug + Send + Sync {
+    fn choose_filter(&self, row: &[u8], prev: &[u8], bpp: usize) -> u8;
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// reverses the filter in place, prev is the previous unfiltered row (zeros for the first)
+pub(crate) fn unfilter_row(
+    filter: u8,
+    bpp: usize,
+    row: &mut [u8],
+    prev: &[u8],
+) -> Result<(), PngError> {
+    match filter {
+        FILTER_NONE => (),
+        FILTER_SUB => {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        FILTER_UP => {
+            for i in 0..row.len() {
+                row[i] = row[i].wrapping_add(prev[i]);
+            }
+        }
+        FILTER_AVERAGE => {
+            for i in 0..row.len() {
+                let left = if i >= bpp { row[i - bpp] } else { 0 };
+                row[i] = row[i].wrapping_add(((left as u16 + prev[i] as u16) / 2) as u8);
+            }
+        }
+        FILTER_PAETH => {
+            for i in 0..row.len() {
+                let (left, up_left) = if i >= bpp {
+                    (row[i - bpp], prev[i - bpp])
+                } else {
+                    (0, 0)
+                };
+                row[i] = row[i].wrapping_add(paeth(left, prev[i], up_left));
+            }
+        }
+        _ => {
+            return Err(PngError::InvalidImageData(message(
+                "filter.unknown",
+                &[&filter],
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+// writes the filtered row (without the filter type byte) into out
+pub fn filter_row(filter: u8, bpp: usize, row: &[u8], prev: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+
+    for i in 0..row.len() {
+        let left = if i >= bpp { row[i - bpp] } else { 0 };
+        let up_left = if i >= bpp { prev[i - bpp] } else { 0 };
+        let up = prev[i];
+
+        let predicted = match filter {
+            FILTER_SUB => left,
+            FILTER_UP => up,
+            FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+            FILTER_PAETH => paeth(left, up, up_left),
+            _ => 0,
+        };
+
+        out.push(row[i].wrapping_sub(predicted));
+    }
+}
+
+// the filtered bytes read as signed values, lower usually compresses better
+pub fn sum_abs(filtered: &[u8]) -> u64 {
+    filtered
+        .iter()
+        .map(|b| (*b as i8).unsigned_abs() as u64)
+        .sum()
+}
+
+// the minimum sum of absolute differences heuristic recommended by the spec
+pub(crate) fn choose_adaptive(row: &[u8], prev: &[u8], bpp: usize) -> u8 {
+    let mut best = (FILTER_NONE, u64::MAX);
+    let mut scratch = Vec::with_capacity(row.len());
+
+    for filter in FILTER_NONE..=FILTER_PAETH {
+        filter_row(filter, bpp, row, prev, &mut scratch);
+        let score = sum_abs(&scratch);
+
+        if score < best.1 {
+            best = (filter, score);
+        }
+    }
+
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_round_trip() {
+        let prev: Vec<u8> = (0..32).map(|i| (i * 7) as u8).collect();
+        let row: Vec<u8> = (0..32).map(|i| (i * 13 + 5) as u8).collect();
+        let mut filtered = vec![];
+
+        for filter in FILTER_NONE..=FILTER_PAETH {
+            filter_row(filter, 4, &row, &prev, &mut filtered);
+            unfilter_row(filter, 4, &mut filtered, &prev).unwrap();
+            assert_eq!(filtered, row);
+        }
+    }
+
+    #[test]
+    fn test_unknown_filter() {
+        let mut row = vec![0; 4];
+        assert!(unfilter_row(5, 1, &mut row, &[0; 4]).is_err());
+    }
+}
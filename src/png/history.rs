@@ -0,0 +1,348 @@
+// undo/redo for pixel edits on a decoded buffer
+//
+// the buffer is split into square tiles, the first write to a tile during an
+// edit snapshots it, and committing the edit keeps before/after copies of only
+// the tiles that actually changed. damage is tracked per tile too, so it
+// stays the size of the tile grid however many writes land between repaints.
+
+use std::{collections::BTreeMap, mem::size_of};
+
+use super::{
+    memory::{Footprint, MemoryFootprint},
+    PixelBuffer, Rect,
+};
+
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+struct TileDiff {
+    tile: Rect,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+pub struct PixelHistory {
+    pixels: PixelBuffer,
+    tile_size: u32,
+    // before snapshots of the tiles touched by the edit in progress
+    pending: BTreeMap<(u32, u32), Vec<u8>>,
+    undo_stack: Vec<Vec<TileDiff>>,
+    redo_stack: Vec<Vec<TileDiff>>,
+    // one bit per tile, row by row, set when the tile changed since take_damage
+    damage: Vec<u64>,
+}
+
+fn read_tile(pixels: &PixelBuffer, tile: Rect) -> Vec<u8> {
+    let mut data = Vec::with_capacity(tile.width as usize * tile.height as usize * 4);
+    for y in tile.y..tile.y + tile.height {
+        let row = pixels.row(y);
+        data.extend_from_slice(&row[tile.x as usize * 4..(tile.x + tile.width) as usize * 4]);
+    }
+    data
+}
+
+fn write_tile(pixels: &mut PixelBuffer, tile: Rect, data: &[u8]) {
+    let width = pixels.width as usize;
+    let row_len = tile.width as usize * 4;
+
+    for (i, y) in (tile.y..tile.y + tile.height).enumerate() {
+        let start = (y as usize * width + tile.x as usize) * 4;
+        pixels.data[start..start + row_len].copy_from_slice(&data[i * row_len..(i + 1) * row_len]);
+    }
+}
+
+impl PixelHistory {
+    pub fn new(pixels: PixelBuffer) -> Self {
+        Self::with_tile_size(pixels, DEFAULT_TILE_SIZE)
+    }
+
+    pub fn with_tile_size(pixels: PixelBuffer, tile_size: u32) -> Self {
+        let tile_size = tile_size.max(1);
+        let tiles =
+            pixels.width.div_ceil(tile_size) as usize * pixels.height.div_ceil(tile_size) as usize;
+
+        PixelHistory {
+            pixels,
+            tile_size,
+            pending: BTreeMap::new(),
+            undo_stack: vec![],
+            redo_stack: vec![],
+            damage: vec![0; tiles.div_ceil(64)],
+        }
+    }
+
+    pub fn pixels(&self) -> &PixelBuffer {
+        &self.pixels
+    }
+
+    pub fn into_pixels(mut self) -> PixelBuffer {
+        self.commit();
+        self.pixels
+    }
+
+    fn tile_rect(&self, tx: u32, ty: u32) -> Rect {
+        Rect::new(
+            tx * self.tile_size,
+            ty * self.tile_size,
+            self.tile_size,
+            self.tile_size,
+        )
+        .clip(self.pixels.width, self.pixels.height)
+        .unwrap()
+    }
+
+    fn tiles_across(&self) -> u32 {
+        self.pixels.width.div_ceil(self.tile_size)
+    }
+
+    fn mark_damaged(&mut self, tx: u32, ty: u32) {
+        let i = ty as usize * self.tiles_across() as usize + tx as usize;
+        self.damage[i / 64] |= 1 << (i % 64);
+    }
+
+    fn is_damaged(&self, tx: u32, ty: u32) -> bool {
+        let i = ty as usize * self.tiles_across() as usize + tx as usize;
+        self.damage[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    // snapshots every tile under rect that the current edit hasn't touched yet
+    fn touch(&mut self, rect: Rect) -> Option<Rect> {
+        let rect = rect.clip(self.pixels.width, self.pixels.height)?;
+
+        for ty in rect.y / self.tile_size..=(rect.y + rect.height - 1) / self.tile_size {
+            for tx in rect.x / self.tile_size..=(rect.x + rect.width - 1) / self.tile_size {
+                if !self.pending.contains_key(&(tx, ty)) {
+                    let before = read_tile(&self.pixels, self.tile_rect(tx, ty));
+                    self.pending.insert((tx, ty), before);
+                }
+                self.mark_damaged(tx, ty);
+            }
+        }
+
+        Some(rect)
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: [u8; 4]) {
+        if self.touch(Rect::new(x, y, 1, 1)).is_some() {
+            self.pixels.set_pixel(x, y, pixel);
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, pixel: [u8; 4]) {
+        if let Some(rect) = self.touch(rect) {
+            self.pixels.fill_rect(rect, pixel);
+        }
+    }
+
+    // runs f over every pixel in rect, for brushes and filters
+    pub fn update_rect(&mut self, rect: Rect, mut f: impl FnMut(u32, u32, [u8; 4]) -> [u8; 4]) {
+        if let Some(rect) = self.touch(rect) {
+            for y in rect.y..rect.y + rect.height {
+                for x in rect.x..rect.x + rect.width {
+                    let pixel = f(x, y, self.pixels.get_pixel(x, y));
+                    self.pixels.set_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    // closes the edit in progress as one undo step, returns false if nothing changed
+    pub fn commit(&mut self) -> bool {
+        let mut diffs = vec![];
+
+        for ((tx, ty), before) in std::mem::take(&mut self.pending) {
+            let tile = self.tile_rect(tx, ty);
+            let after = read_tile(&self.pixels, tile);
+
+            if after != before {
+                diffs.push(TileDiff {
+                    tile,
+                    before,
+                    after,
+                });
+            }
+        }
+
+        if diffs.is_empty() {
+            return false;
+        }
+
+        self.undo_stack.push(diffs);
+        self.redo_stack.clear();
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.pending.is_empty() || !self.undo_stack.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.pending.is_empty() && !self.redo_stack.is_empty()
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.commit();
+
+        let diffs = match self.undo_stack.pop() {
+            Some(d) => d,
+            None => return false,
+        };
+
+        for diff in &diffs {
+            write_tile(&mut self.pixels, diff.tile, &diff.before);
+            self.mark_damaged(diff.tile.x / self.tile_size, diff.tile.y / self.tile_size);
+        }
+
+        self.redo_stack.push(diffs);
+        true
+    }
+
+    pub fn redo(&mut self) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+
+        let diffs = self.redo_stack.pop().unwrap();
+        for diff in &diffs {
+            write_tile(&mut self.pixels, diff.tile, &diff.after);
+            self.mark_damaged(diff.tile.x / self.tile_size, diff.tile.y / self.tile_size);
+        }
+
+        self.undo_stack.push(diffs);
+        true
+    }
+
+    // regions changed since the last call, for repainting. these are whole
+    // tiles, with runs of damaged tiles in a row of the grid joined into one rect
+    pub fn take_damage(&mut self) -> Vec<Rect> {
+        let across = self.tiles_across();
+        let down = self.pixels.height.div_ceil(self.tile_size);
+        let mut rects = vec![];
+
+        for ty in 0..down {
+            let mut tx = 0;
+            while tx < across {
+                if !self.is_damaged(tx, ty) {
+                    tx += 1;
+                    continue;
+                }
+
+                let start = tx;
+                while tx < across && self.is_damaged(tx, ty) {
+                    tx += 1;
+                }
+                let first = self.tile_rect(start, ty);
+                let last = self.tile_rect(tx - 1, ty);
+                rects.push(Rect::new(
+                    first.x,
+                    first.y,
+                    last.x + last.width - first.x,
+                    first.height,
+                ));
+            }
+        }
+
+        self.damage.fill(0);
+        rects
+    }
+
+    // bytes held by the undo and redo stacks
+    pub fn history_size(&self) -> usize {
+        self.undo_stack
+            .iter()
+            .chain(&self.redo_stack)
+            .flatten()
+            .map(|d| d.before.len() + d.after.len())
+            .sum()
+    }
+}
+
+impl MemoryFootprint for PixelHistory {
+    // the live buffer counts as pixels, every snapshot as caches
+    fn memory_footprint(&self) -> Footprint {
+        let diffs: usize = self
+            .undo_stack
+            .iter()
+            .chain(&self.redo_stack)
+            .flatten()
+            .map(|d| size_of::<TileDiff>() + d.before.capacity() + d.after.capacity())
+            .sum();
+        let pending: usize = self
+            .pending
+            .values()
+            .map(|tile| size_of::<((u32, u32), Vec<u8>)>() + tile.capacity())
+            .sum();
+
+        let mut footprint = self.pixels.memory_footprint();
+        footprint.caches += size_of::<Self>() - size_of::<PixelBuffer>()
+            + diffs
+            + pending
+            + self.damage.capacity() * size_of::<u64>();
+        footprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_undo_redo() {
+        let mut history = PixelHistory::with_tile_size(PixelBuffer::new(100, 100).unwrap(), 16);
+        history.fill_rect(Rect::new(10, 10, 20, 20), [255, 0, 0, 255]);
+        history.commit();
+        history.set_pixel(50, 50, [0, 255, 0, 255]);
+
+        assert!(history.undo());
+        assert_eq!(history.pixels().get_pixel(50, 50), [0, 0, 0, 0]);
+        assert_eq!(history.pixels().get_pixel(15, 15), [255, 0, 0, 255]);
+
+        assert!(history.undo());
+        assert_eq!(history.pixels(), &PixelBuffer::new(100, 100).unwrap());
+        assert!(!history.undo());
+
+        assert!(history.redo());
+        assert!(history.redo());
+        assert_eq!(history.pixels().get_pixel(50, 50), [0, 255, 0, 255]);
+        assert!(!history.redo());
+    }
+
+    #[test]
+    fn test_history_keeps_only_changed_tiles() {
+        let mut history = PixelHistory::with_tile_size(PixelBuffer::new(64, 64).unwrap(), 16);
+        history.set_pixel(1, 1, [9, 9, 9, 9]);
+        // writing the value that's already there touches a tile without changing it
+        history.set_pixel(40, 40, [0, 0, 0, 0]);
+        history.commit();
+
+        assert_eq!(history.history_size(), 16 * 16 * 4 * 2);
+    }
+
+    #[test]
+    fn test_history_new_edit_clears_redo() {
+        let mut history = PixelHistory::new(PixelBuffer::new(10, 10).unwrap());
+        history.set_pixel(0, 0, [1, 1, 1, 1]);
+        history.undo();
+        assert!(history.can_redo());
+
+        history.update_rect(Rect::new(0, 0, 10, 10), |_, _, p| [p[0], 2, p[2], p[3]]);
+        history.commit();
+        assert!(!history.can_redo());
+        assert_eq!(history.take_damage(), [Rect::new(0, 0, 10, 10)]);
+        assert!(history.take_damage().is_empty());
+    }
+
+    #[test]
+    fn test_history_damage_is_bounded() {
+        let mut history = PixelHistory::with_tile_size(PixelBuffer::new(100, 100).unwrap(), 16);
+        for i in 0..10_000 {
+            history.set_pixel(i % 40, 20 + i % 3, [1, 2, 3, 4]);
+        }
+        history.set_pixel(99, 99, [1, 2, 3, 4]);
+
+        // the writes cover three tiles of one grid row and the corner tile
+        assert_eq!(
+            history.take_damage(),
+            [Rect::new(0, 16, 48, 16), Rect::new(96, 96, 4, 4)]
+        );
+    }
+}
@@ -0,0 +1,571 @@
+// streaming zlib/DEFLATE decoder (RFC 1950 / RFC 1951)
+//
+// the inflater pulls compressed bytes from any reader and hands out
+// decompressed bytes through `Read`, so callers only ever hold the 32K
+// window plus whatever they ask for.
+
+use std::io::{self, Read};
+
+use super::{checksum::Adler32, locale::message, stats::BlockCounts};
+
+pub(crate) const WINDOW_SIZE: usize = 1 << 15;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub(crate) const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub(crate) fn fixed_literal_lengths() -> [u8; 288] {
+    let mut lengths = [0u8; 288];
+    for (i, l) in lengths.iter_mut().enumerate() {
+        *l = match i {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    lengths
+}
+
+fn invalid_data(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message(key, &[]))
+}
+
+pub(crate) struct BitReader<R: Read> {
+    inner: R,
+    buf: u64,
+    count: u32,
+    exhausted: bool,
+    bytes_read: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        BitReader {
+            inner,
+            buf: 0,
+            count: 0,
+            exhausted: false,
+            bytes_read: 0,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        while self.count <= 56 && !self.exhausted {
+            let want = ((64 - self.count) / 8) as usize;
+            let mut bytes = [0u8; 8];
+
+            let n = match self.inner.read(&mut bytes[..want]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            if n == 0 {
+                self.exhausted = true;
+            }
+            self.bytes_read += n as u64;
+
+            for b in &bytes[..n] {
+                self.buf |= (*b as u64) << self.count;
+                self.count += 8;
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns the next n bits without consuming them, zero padded past the end
+    fn peek(&mut self, n: u32) -> io::Result<u32> {
+        if self.count < n {
+            self.fill()?;
+        }
+
+        Ok((self.buf & ((1u64 << n) - 1)) as u32)
+    }
+
+    fn consume(&mut self, n: u32) -> io::Result<()> {
+        if n > self.count {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                message("inflate.ended_early", &[]),
+            ));
+        }
+
+        self.buf >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        if n == 0 {
+            return Ok(0);
+        }
+
+        let value = self.peek(n)?;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        self.consume(self.count % 8)
+    }
+
+    // bits consumed so far, counted from where the reader started
+    fn position(&self) -> u64 {
+        self.bytes_read * 8 - self.count as u64
+    }
+}
+
+pub(crate) struct Huffman {
+    // indexed by the next max_len bits of input, holds (symbol, code length)
+    table: Vec<(u16, u8)>,
+    max_len: u8,
+}
+
+impl Huffman {
+    pub(crate) fn new(lengths: &[u8]) -> io::Result<Self> {
+        let max_len = lengths.iter().copied().max().unwrap_or(0);
+
+        let mut counts = [0u32; 16];
+        for l in lengths {
+            counts[*l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // reject over-subscribed codes
+        let mut left: i64 = 1;
+        for count in counts.iter().skip(1) {
+            left = (left << 1) - *count as i64;
+            if left < 0 {
+                return Err(invalid_data("inflate.oversubscribed"));
+            }
+        }
+
+        let mut next_code = [0u32; 16];
+        let mut code = 0;
+        for len in 1..16 {
+            code = (code + counts[len - 1]) << 1;
+            next_code[len] = code;
+        }
+
+        let mut table = vec![(0u16, 0u8); 1 << max_len];
+        for (symbol, l) in lengths.iter().enumerate() {
+            let l = *l;
+            if l == 0 {
+                continue;
+            }
+
+            let code = next_code[l as usize];
+            next_code[l as usize] += 1;
+
+            let reversed = reverse_bits(code, l);
+            let mut fill = reversed as usize;
+            while fill < table.len() {
+                table[fill] = (symbol as u16, l);
+                fill += 1 << l;
+            }
+        }
+
+        Ok(Huffman { table, max_len })
+    }
+
+    fn decode<R: Read>(&self, input: &mut BitReader<R>) -> io::Result<u16> {
+        if self.max_len == 0 {
+            return Err(invalid_data("inflate.empty_code"));
+        }
+
+        let bits = input.peek(self.max_len as u32)?;
+        let (symbol, len) = self.table[bits as usize];
+
+        if len == 0 {
+            return Err(invalid_data("inflate.bad_code"));
+        }
+
+        input.consume(len as u32)?;
+        Ok(symbol)
+    }
+}
+
+pub(crate) fn reverse_bits(code: u32, len: u8) -> u32 {
+    let mut code = code;
+    let mut reversed = 0;
+    for _ in 0..len {
+        reversed = (reversed << 1) | (code & 1);
+        code >>= 1;
+    }
+    reversed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Header,
+    BlockStart,
+    Stored(usize),
+    Codes,
+    Copy { length: usize, distance: usize },
+    Trailer,
+    Done,
+}
+
+pub(crate) struct Inflater<R: Read> {
+    input: BitReader<R>,
+    window: Vec<u8>,
+    window_pos: usize,
+    state: State,
+    final_block: bool,
+    literals: Huffman,
+    distances: Huffman,
+    adler: Adler32,
+    // off when resuming in the middle of a stream, the checksum covers all of it
+    check_adler: bool,
+    // stop reads at block boundaries so the caller can look at the state there
+    pause_at_blocks: bool,
+    total_out: u64,
+    blocks: BlockCounts,
+}
+
+impl<R: Read> Inflater<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Inflater {
+            input: BitReader::new(inner),
+            window: vec![0; WINDOW_SIZE],
+            window_pos: 0,
+            state: State::Header,
+            final_block: false,
+            literals: Huffman {
+                table: vec![],
+                max_len: 0,
+            },
+            distances: Huffman {
+                table: vec![],
+                max_len: 0,
+            },
+            adler: Adler32::new(),
+            check_adler: true,
+            pause_at_blocks: false,
+            total_out: 0,
+            blocks: BlockCounts::default(),
+        }
+    }
+
+    // picks up a stream at a block boundary found by an earlier pass. inner
+    // starts at the byte holding the block header, bit is how far into that
+    // byte it begins and history is the output just before it, up to a window
+    pub(crate) fn resume(inner: R, bit: u8, total_out: u64, history: &[u8]) -> io::Result<Self> {
+        let mut inflater = Inflater::new(inner);
+        inflater.input.bits(bit as u32)?;
+        inflater.state = State::BlockStart;
+        inflater.check_adler = false;
+        inflater.total_out = total_out;
+
+        let history = &history[history.len().saturating_sub(WINDOW_SIZE)..];
+        inflater.window[..history.len()].copy_from_slice(history);
+        inflater.window_pos = history.len() & WINDOW_MASK;
+        Ok(inflater)
+    }
+
+    pub(crate) fn pause_at_blocks(&mut self, pause: bool) {
+        self.pause_at_blocks = pause;
+    }
+
+    // true between blocks when another one follows, where resume can pick up
+    pub(crate) fn at_block_boundary(&self) -> bool {
+        self.state == State::BlockStart && !self.final_block
+    }
+
+    // where the next bit will be read from, counted from the zlib header
+    pub(crate) fn bit_position(&self) -> u64 {
+        self.input.position()
+    }
+
+    // the last window worth of output, oldest byte first
+    pub(crate) fn history(&self) -> Vec<u8> {
+        let len = (self.total_out as usize).min(WINDOW_SIZE);
+        let start = (self.window_pos + WINDOW_SIZE - len) & WINDOW_MASK;
+        (0..len)
+            .map(|i| self.window[(start + i) & WINDOW_MASK])
+            .collect()
+    }
+
+    pub(crate) fn total_out(&self) -> u64 {
+        self.total_out
+    }
+
+    pub(crate) fn blocks(&self) -> BlockCounts {
+        self.blocks
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.input.inner
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let cmf = self.input.bits(8)?;
+        let flg = self.input.bits(8)?;
+
+        if cmf & 0x0F != 8 {
+            return Err(invalid_data("inflate.not_deflate"));
+        }
+        if (cmf >> 4) > 7 {
+            return Err(invalid_data("inflate.window_too_large"));
+        }
+        if ((cmf << 8) | flg) % 31 != 0 {
+            return Err(invalid_data("inflate.bad_header"));
+        }
+        if flg & 0x20 != 0 {
+            return Err(invalid_data("inflate.preset_dictionary"));
+        }
+
+        Ok(())
+    }
+
+    fn read_block_start(&mut self) -> io::Result<()> {
+        if self.final_block {
+            self.state = State::Trailer;
+            return Ok(());
+        }
+
+        self.final_block = self.input.bits(1)? == 1;
+        let btype = self.input.bits(2)?;
+        if btype < 3 {
+            self.blocks.add(btype);
+        }
+
+        match btype {
+            0 => {
+                self.input.align()?;
+                let len = self.input.bits(16)?;
+                let nlen = self.input.bits(16)?;
+
+                if len != !nlen & 0xFFFF {
+                    return Err(invalid_data("inflate.bad_stored_length"));
+                }
+
+                self.state = State::Stored(len as usize);
+            }
+            1 => {
+                self.literals = Huffman::new(&fixed_literal_lengths())?;
+                self.distances = Huffman::new(&[5; 30])?;
+                self.state = State::Codes;
+            }
+            2 => {
+                self.read_dynamic_tables()?;
+                self.state = State::Codes;
+            }
+            _ => return Err(invalid_data("inflate.bad_block_type")),
+        }
+
+        Ok(())
+    }
+
+    fn read_dynamic_tables(&mut self) -> io::Result<()> {
+        let hlit = self.input.bits(5)? as usize + 257;
+        let hdist = self.input.bits(5)? as usize + 1;
+        let hclen = self.input.bits(4)? as usize + 4;
+
+        if hlit > 286 || hdist > 30 {
+            return Err(invalid_data("inflate.too_many_codes"));
+        }
+
+        let mut code_lengths = [0u8; 19];
+        for i in CODE_LENGTH_ORDER.iter().take(hclen) {
+            code_lengths[*i] = self.input.bits(3)? as u8;
+        }
+        let code_length_code = Huffman::new(&code_lengths)?;
+
+        let mut lengths = vec![0u8; hlit + hdist];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = code_length_code.decode(&mut self.input)?;
+
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    if i == 0 {
+                        return Err(invalid_data("inflate.repeat_without_length"));
+                    }
+                    (lengths[i - 1], 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+
+            if i + repeat > lengths.len() {
+                return Err(invalid_data("inflate.lengths_overflow"));
+            }
+
+            lengths[i..i + repeat].fill(value);
+            i += repeat;
+        }
+
+        if lengths[256] == 0 {
+            return Err(invalid_data("inflate.no_end_of_block"));
+        }
+
+        self.literals = Huffman::new(&lengths[..hlit])?;
+        self.distances = Huffman::new(&lengths[hlit..])?;
+        Ok(())
+    }
+
+    fn read_match(&mut self, symbol: u16) -> io::Result<()> {
+        let index = symbol as usize - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(invalid_data("inflate.bad_length"));
+        }
+        let length =
+            LENGTH_BASE[index] as usize + self.input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+        let index = self.distances.decode(&mut self.input)? as usize;
+        if index >= DIST_BASE.len() {
+            return Err(invalid_data("inflate.bad_distance"));
+        }
+        let distance =
+            DIST_BASE[index] as usize + self.input.bits(DIST_EXTRA[index] as u32)? as usize;
+
+        if distance as u64 > self.total_out {
+            return Err(invalid_data("inflate.distance_too_far"));
+        }
+
+        self.state = State::Copy { length, distance };
+        Ok(())
+    }
+
+    fn emit(&mut self, byte: u8, buf: &mut [u8], n: &mut usize) {
+        buf[*n] = byte;
+        *n += 1;
+        self.window[self.window_pos] = byte;
+        self.window_pos = (self.window_pos + 1) & WINDOW_MASK;
+        self.total_out += 1;
+    }
+}
+
+impl<R: Read> Read for Inflater<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+
+        while n < buf.len() {
+            match self.state {
+                State::Header => {
+                    self.read_header()?;
+                    self.state = State::BlockStart;
+                }
+                State::BlockStart if self.pause_at_blocks && n > 0 => break,
+                State::BlockStart => self.read_block_start()?,
+                State::Stored(0) => self.state = State::BlockStart,
+                State::Stored(remaining) => {
+                    let byte = self.input.bits(8)? as u8;
+                    self.emit(byte, buf, &mut n);
+                    self.state = State::Stored(remaining - 1);
+                }
+                State::Codes => {
+                    let symbol = self.literals.decode(&mut self.input)?;
+                    match symbol {
+                        0..=255 => self.emit(symbol as u8, buf, &mut n),
+                        256 => self.state = State::BlockStart,
+                        _ => self.read_match(symbol)?,
+                    }
+                }
+                State::Copy { length, distance } => {
+                    let mut length = length;
+                    while length > 0 && n < buf.len() {
+                        let byte =
+                            self.window[(self.window_pos + WINDOW_SIZE - distance) & WINDOW_MASK];
+                        self.emit(byte, buf, &mut n);
+                        length -= 1;
+                    }
+
+                    self.state = if length == 0 {
+                        State::Codes
+                    } else {
+                        State::Copy { length, distance }
+                    };
+                }
+                State::Trailer => {
+                    self.input.align()?;
+                    let expected = self.input.bits(32)?.swap_bytes();
+                    self.adler.update(&buf[..n]);
+
+                    if self.check_adler && expected != self.adler.finish() {
+                        return Err(invalid_data("inflate.adler_mismatch"));
+                    }
+
+                    self.state = State::Done;
+                    return Ok(n);
+                }
+                State::Done => break,
+            }
+        }
+
+        self.adler.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+// whole buffer helper for the formats the library defines itself
+// stops as soon as the output grows past limit, so data read from a file
+// can't inflate into more memory than the caller expects
+pub(crate) fn inflate(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    Inflater::new(data)
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut out)?;
+
+    if out.len() > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            message("inflate.too_large", &[&limit]),
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inflate_stored() {
+        // zlib stream holding a single stored block with "hello"
+        let data = [
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFF, b'h', b'e', b'l', b'l', b'o', 0x06, 0x2C,
+            0x02, 0x15,
+        ];
+        assert_eq!(inflate(&data, usize::MAX).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_inflate_fixed() {
+        // "hello hello hello" compressed by zlib with fixed huffman codes
+        let data = [
+            0x78, 0x01, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x90, 0x00, 0x3A, 0x2E,
+            0x06, 0x7D,
+        ];
+        assert_eq!(inflate(&data, usize::MAX).unwrap(), b"hello hello hello");
+    }
+
+    #[test]
+    fn test_inflate_bad_checksum() {
+        let data = [
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFF, b'h', b'e', b'l', b'l', b'o', 0x00, 0x00,
+            0x00, 0x00,
+        ];
+        assert!(inflate(&data, usize::MAX).is_err());
+    }
+}
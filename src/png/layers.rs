@@ -0,0 +1,414 @@
+// a small layered document format on top of png
+//
+// the flattened composite is stored as the regular image so any viewer can
+// show it, and every layer is kept in its own private laYR chunk (bottom to
+// top) so this module can load them back. laYR is unsafe-to-copy, editors
+// that change the pixels without knowing about it will drop the layers.
+
+use std::mem::size_of;
+
+use super::{
+    deflate::deflate,
+    inflate::inflate,
+    locale::message,
+    memory::{Footprint, MemoryFootprint},
+    pixels::rgba_len,
+    EncodeOptions, PixelBuffer, PngError, PngImage,
+};
+
+pub const LAYER_CHUNK: &str = "laYR";
+const LAYER_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    Add,
+    Difference,
+}
+
+impl BlendMode {
+    fn to_byte(self) -> u8 {
+        match self {
+            BlendMode::Normal => 0,
+            BlendMode::Multiply => 1,
+            BlendMode::Screen => 2,
+            BlendMode::Overlay => 3,
+            BlendMode::Darken => 4,
+            BlendMode::Lighten => 5,
+            BlendMode::Add => 6,
+            BlendMode::Difference => 7,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => BlendMode::Normal,
+            1 => BlendMode::Multiply,
+            2 => BlendMode::Screen,
+            3 => BlendMode::Overlay,
+            4 => BlendMode::Darken,
+            5 => BlendMode::Lighten,
+            6 => BlendMode::Add,
+            7 => BlendMode::Difference,
+            _ => return None,
+        })
+    }
+
+    // backdrop and source channels in 0..1
+    fn blend(self, b: f32, s: f32) -> f32 {
+        match self {
+            BlendMode::Normal => s,
+            BlendMode::Multiply => b * s,
+            BlendMode::Screen => b + s - b * s,
+            BlendMode::Overlay => {
+                if b <= 0.5 {
+                    2.0 * b * s
+                } else {
+                    1.0 - 2.0 * (1.0 - b) * (1.0 - s)
+                }
+            }
+            BlendMode::Darken => b.min(s),
+            BlendMode::Lighten => b.max(s),
+            BlendMode::Add => (b + s).min(1.0),
+            BlendMode::Difference => (b - s).abs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub pixels: PixelBuffer,
+    // position of the layer's top left corner on the canvas
+    pub x: i32,
+    pub y: i32,
+    // 0.0 is invisible, 1.0 uses the layer's own alpha as is
+    pub opacity: f32,
+    pub blend: BlendMode,
+    pub visible: bool,
+}
+
+impl Layer {
+    pub fn new(name: &str, pixels: PixelBuffer) -> Self {
+        Layer {
+            name: name.to_string(),
+            pixels,
+            x: 0,
+            y: 0,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+            visible: true,
+        }
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, PngError> {
+        let name_len = u16::try_from(self.name.len()).map_err(|_| {
+            PngError::InvalidOperation(message(
+                "layers.name_too_long",
+                &[&self.name.len(), &LAYER_CHUNK, &u16::MAX],
+            ))
+        })?;
+
+        let mut bytes = vec![LAYER_VERSION];
+        bytes.extend_from_slice(&name_len.to_be_bytes());
+        bytes.extend_from_slice(self.name.as_bytes());
+        bytes.extend_from_slice(&self.x.to_be_bytes());
+        bytes.extend_from_slice(&self.y.to_be_bytes());
+        bytes.extend_from_slice(&self.pixels.width.to_be_bytes());
+        bytes.extend_from_slice(&self.pixels.height.to_be_bytes());
+        bytes.extend_from_slice(&self.opacity.to_be_bytes());
+        bytes.push(self.blend.to_byte());
+        bytes.push(self.visible as u8);
+        bytes.extend(deflate(&self.pixels.data, 6));
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, PngError> {
+        let mut pos = 0;
+
+        if take(bytes, &mut pos, 1)?[0] != LAYER_VERSION {
+            return Err(PngError::InvalidImageData(message(
+                "layers.unknown_version",
+                &[&LAYER_CHUNK],
+            )));
+        }
+
+        let name_len = u16::from_be_bytes(take(bytes, &mut pos, 2)?.try_into().unwrap());
+        let name = take(bytes, &mut pos, name_len as usize)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| malformed())?;
+        let mut word =
+            || -> Result<[u8; 4], PngError> { Ok(take(bytes, &mut pos, 4)?.try_into().unwrap()) };
+        let x = i32::from_be_bytes(word()?);
+        let y = i32::from_be_bytes(word()?);
+        let width = u32::from_be_bytes(word()?);
+        let height = u32::from_be_bytes(word()?);
+        let opacity = f32::from_be_bytes(word()?);
+        let blend = BlendMode::from_byte(take(bytes, &mut pos, 1)?[0]).ok_or_else(malformed)?;
+        let visible = take(bytes, &mut pos, 1)?[0] != 0;
+
+        // the size comes from the file, check it before inflating anything and
+        // stop inflating once the data outgrows it
+        let expected = rgba_len(width, height)?;
+        let data = inflate(&bytes[pos..], expected).map_err(|_| malformed())?;
+        if data.len() != expected {
+            return Err(malformed());
+        }
+        let pixels = PixelBuffer::from_rgba(width, height, data)?;
+
+        Ok(Layer {
+            name,
+            pixels,
+            x,
+            y,
+            opacity,
+            blend,
+            visible,
+        })
+    }
+}
+
+fn malformed() -> PngError {
+    PngError::InvalidImageData(message("layers.malformed", &[&LAYER_CHUNK]))
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, n: usize) -> Result<&'a [u8], PngError> {
+    match bytes.get(*pos..*pos + n) {
+        Some(slice) => {
+            *pos += n;
+            Ok(slice)
+        }
+        None => Err(malformed()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayeredImage {
+    pub width: u32,
+    pub height: u32,
+    // bottom to top
+    pub layers: Vec<Layer>,
+}
+
+fn composite_pixel(backdrop: [u8; 4], source: [u8; 4], opacity: f32, blend: BlendMode) -> [u8; 4] {
+    let ab = backdrop[3] as f32 / 255.0;
+    let as_ = source[3] as f32 / 255.0 * opacity.clamp(0.0, 1.0);
+    let ao = as_ + ab * (1.0 - as_);
+
+    if ao <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+
+    let mut out = [0u8; 4];
+    for c in 0..3 {
+        let cb = backdrop[c] as f32 / 255.0;
+        let cs = source[c] as f32 / 255.0;
+        // the blend result only applies where the backdrop is opaque
+        let mixed = (1.0 - ab) * cs + ab * blend.blend(cb, cs);
+        let co = as_ * mixed + ab * cb * (1.0 - as_);
+        out[c] = (co / ao * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    out[3] = (ao * 255.0).round() as u8;
+    out
+}
+
+impl LayeredImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        LayeredImage {
+            width,
+            height,
+            layers: vec![],
+        }
+    }
+
+    pub fn add_layer(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    pub fn get_layer(&self, name: &str) -> Option<&Layer> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+
+    pub fn composite(&self) -> Result<PixelBuffer, PngError> {
+        let mut canvas = PixelBuffer::new(self.width, self.height)?;
+
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            for ly in 0..layer.pixels.height {
+                let y = layer.y as i64 + ly as i64;
+                if y < 0 || y >= self.height as i64 {
+                    continue;
+                }
+
+                for lx in 0..layer.pixels.width {
+                    let x = layer.x as i64 + lx as i64;
+                    if x < 0 || x >= self.width as i64 {
+                        continue;
+                    }
+
+                    let backdrop = canvas.get_pixel(x as u32, y as u32);
+                    let source = layer.pixels.get_pixel(lx, ly);
+                    let pixel = composite_pixel(backdrop, source, layer.opacity, layer.blend);
+                    canvas.set_pixel(x as u32, y as u32, pixel);
+                }
+            }
+        }
+
+        Ok(canvas)
+    }
+
+    // the flattened composite plus one laYR chunk per layer
+    pub fn to_png(&self, options: &EncodeOptions) -> Result<PngImage, PngError> {
+        let mut image = PngImage::from_pixels(&self.composite()?, options)?;
+
+        for layer in &self.layers {
+            image.add_payload(LAYER_CHUNK, layer.to_bytes()?)?;
+        }
+
+        Ok(image)
+    }
+
+    // images without layer chunks load as a single layer holding the decoded pixels
+    pub fn from_png(image: &PngImage) -> Result<Self, PngError> {
+        let mut layers = vec![];
+        for payload in image.payloads(LAYER_CHUNK) {
+            layers.push(Layer::from_bytes(&payload?)?);
+        }
+
+        if layers.is_empty() {
+            layers.push(Layer::new("Background", image.decode()?));
+        }
+
+        Ok(LayeredImage {
+            width: image.info.width,
+            height: image.info.height,
+            layers,
+        })
+    }
+}
+
+impl MemoryFootprint for LayeredImage {
+    fn memory_footprint(&self) -> Footprint {
+        let mut footprint = Footprint {
+            pixels: size_of::<Self>() + self.layers.capacity() * size_of::<Layer>(),
+            ..Default::default()
+        };
+        for layer in &self.layers {
+            footprint += layer.pixels.memory_footprint();
+            footprint.pixels += layer.name.capacity();
+        }
+        footprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::Rect;
+    const IMAGE_PATH: &str = "./test.png";
+
+    fn solid(width: u32, height: u32, pixel: [u8; 4]) -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(width, height).unwrap();
+        pixels.fill_rect(pixels.bounds(), pixel);
+        pixels
+    }
+
+    #[test]
+    fn test_composite_normal_with_opacity() {
+        let mut image = LayeredImage::new(4, 4);
+        image.add_layer(Layer::new("base", solid(4, 4, [0, 0, 255, 255])));
+
+        let mut top = Layer::new("top", solid(2, 2, [255, 0, 0, 255]));
+        top.x = 3;
+        top.y = -1;
+        top.opacity = 0.5;
+        image.add_layer(top);
+
+        let flat = image.composite().unwrap();
+        assert_eq!(flat.get_pixel(3, 0), [128, 0, 128, 255]);
+        assert_eq!(flat.get_pixel(2, 0), [0, 0, 255, 255]);
+        assert_eq!(flat.get_pixel(3, 1), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_composite_blend_modes() {
+        let mut image = LayeredImage::new(1, 1);
+        image.add_layer(Layer::new("base", solid(1, 1, [200, 100, 50, 255])));
+        let mut top = Layer::new("top", solid(1, 1, [128, 128, 128, 255]));
+
+        top.blend = BlendMode::Multiply;
+        image.layers.push(top.clone());
+        assert_eq!(
+            image.composite().unwrap().get_pixel(0, 0),
+            [100, 50, 25, 255]
+        );
+
+        top.blend = BlendMode::Difference;
+        image.layers[1] = top.clone();
+        assert_eq!(
+            image.composite().unwrap().get_pixel(0, 0),
+            [72, 28, 78, 255]
+        );
+
+        top.visible = false;
+        image.layers[1] = top;
+        assert_eq!(
+            image.composite().unwrap().get_pixel(0, 0),
+            [200, 100, 50, 255]
+        );
+    }
+
+    #[test]
+    fn test_layers_round_trip() {
+        let mut image = LayeredImage::new(8, 8);
+        image.add_layer(Layer::new("background", solid(8, 8, [10, 20, 30, 255])));
+
+        let mut sketch = Layer::new("sketch", PixelBuffer::new(3, 5).unwrap());
+        sketch
+            .pixels
+            .fill_rect(Rect::new(1, 1, 1, 3), [255, 255, 255, 200]);
+        sketch.x = -2;
+        sketch.y = 4;
+        sketch.opacity = 0.75;
+        sketch.blend = BlendMode::Screen;
+        image.add_layer(sketch);
+
+        let png = image.to_png(&EncodeOptions::default()).unwrap();
+        let saved = PngImage::from_bytes(png.to_bytes()).unwrap();
+
+        assert_eq!(saved.decode().unwrap(), image.composite().unwrap());
+        assert_eq!(LayeredImage::from_png(&saved).unwrap(), image);
+    }
+
+    #[test]
+    fn test_flat_png_loads_as_one_layer() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let layered = LayeredImage::from_png(&image).unwrap();
+
+        assert_eq!(layered.layers.len(), 1);
+        assert_eq!(layered.get_layer("Background").unwrap().pixels.width, 800);
+    }
+
+    #[test]
+    fn test_layer_limits() {
+        let mut image = LayeredImage::new(1, 1);
+        image.add_layer(Layer::new(&"x".repeat(70000), solid(1, 1, [0; 4])));
+        assert!(image.to_png(&EncodeOptions::default()).is_err());
+
+        // a chunk claiming a size no buffer can hold
+        let mut bytes = Layer::new("big", solid(1, 1, [0; 4])).to_bytes().unwrap();
+        bytes[14..22].copy_from_slice(&[0xFF; 8]);
+        assert!(matches!(
+            Layer::from_bytes(&bytes),
+            Err(PngError::InvalidPngInfo(_))
+        ));
+
+        // pixel data that inflates to more than the size says
+        let mut bytes = Layer::new("big", solid(2, 2, [0; 4])).to_bytes().unwrap();
+        bytes[14..22].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(Layer::from_bytes(&bytes).is_err());
+    }
+}
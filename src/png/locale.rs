@@ -0,0 +1,485 @@
+// message catalog for user facing text
+//
+// every message has a key and an english template where {0}, {1}, ... stand
+// for the arguments, so translations can reorder them. an installed
+// LocaleProvider gets asked first and anything it doesn't know falls back to
+// english, which lets a translation ship before it covers every message.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, RwLock},
+};
+
+use super::PngError;
+
+pub trait LocaleProvider: Send + Sync {
+    // the template for key, None to fall back to english
+    fn lookup(&self, key: &str) -> Option<String>;
+}
+
+const ENGLISH: &[(&str, &str)] = &[
+    // PngError
+    ("error.invalid_file_type", "Invalid file type"),
+    ("error.invalid_chunk", "Invalid chunk"),
+    ("error.invalid_chunk_type", "Invalid chunk type: {0}"),
+    ("error.invalid_chunk_crc", "Invalid chunk crc: {0}"),
+    ("error.save_failed", "Save operation failed"),
+    ("error.invalid_chunk_size", "Invalid chunk size"),
+    ("error.invalid_png_info", "Invalid png info: {0}"),
+    ("error.invalid_image_data", "Invalid image data: {0}"),
+    ("error.invalid_operation", "Invalid operation: {0}"),
+    ("error.stream_failed", "Stream failed: {0}"),
+    // pixels
+    (
+        "pixels.too_large",
+        "{0}x{1} is more than the {2} pixels a buffer may hold",
+    ),
+    (
+        "pixels.wrong_length",
+        "Expected {0} bytes of RGBA data for a {1}x{2} image, got {3}",
+    ),
+    // chunks
+    ("png.header_not_ihdr", "Header chunk must be of type IHDR"),
+    (
+        "png.header_unreadable",
+        "Could not read png info from header data buffer",
+    ),
+    ("png.crc_unreadable", "Failed to read 4 byte CRC hash"),
+    (
+        "png.type_unreadable",
+        "Failed to read 4 bytes out of data buffer",
+    ),
+    (
+        "png.type_not_text",
+        "Failed to convert chunk type to string",
+    ),
+    // decoding
+    (
+        "decode.unsupported_format",
+        "Unsupported color type {0} with bit depth {1}",
+    ),
+    ("decode.empty", "Image dimensions must be non-zero"),
+    (
+        "decode.unknown_method",
+        "Unknown compression or filter method",
+    ),
+    ("decode.unknown_interlace", "Unknown interlace method"),
+    ("decode.ended_early", "Image data ended early"),
+    ("decode.no_palette", "Indexed image has no PLTE chunk"),
+    ("decode.palette_index", "Palette index {0} out of range"),
+    ("filter.unknown", "Unknown filter type {0}"),
+    ("inflate.ended_early", "compressed stream ended early"),
+    ("inflate.oversubscribed", "over-subscribed huffman code"),
+    (
+        "inflate.empty_code",
+        "symbol read from an empty huffman code",
+    ),
+    ("inflate.bad_code", "invalid huffman code"),
+    (
+        "inflate.not_deflate",
+        "zlib compression method must be deflate",
+    ),
+    ("inflate.window_too_large", "zlib window size is too large"),
+    ("inflate.bad_header", "zlib header check failed"),
+    (
+        "inflate.preset_dictionary",
+        "zlib preset dictionaries are not supported",
+    ),
+    (
+        "inflate.bad_stored_length",
+        "stored block length check failed",
+    ),
+    ("inflate.bad_block_type", "invalid deflate block type"),
+    (
+        "inflate.too_many_codes",
+        "too many deflate length or distance codes",
+    ),
+    (
+        "inflate.repeat_without_length",
+        "repeated code length with no previous length",
+    ),
+    (
+        "inflate.lengths_overflow",
+        "code lengths overflow the table",
+    ),
+    ("inflate.no_end_of_block", "missing end of block code"),
+    ("inflate.bad_length", "invalid length symbol"),
+    ("inflate.bad_distance", "invalid distance symbol"),
+    ("inflate.adler_mismatch", "adler32 checksum mismatch"),
+    (
+        "inflate.too_large",
+        "inflated data is larger than {0} bytes",
+    ),
+    (
+        "inflate.distance_too_far",
+        "distance reaches before the start of the stream",
+    ),
+    // encoding and transcoding
+    ("encode.empty", "Cannot encode an empty image"),
+    ("transcode.ended_early", "Unexpected end of stream"),
+    (
+        "transcode.bad_type",
+        "Chunk type must be four ASCII letters",
+    ),
+    ("transcode.crc_mismatch", "crc mismatch in {0} chunk"),
+    ("transcode.idat_split", "IDAT chunks must be consecutive"),
+    // editing
+    (
+        "region.interlaced",
+        "Interlaced images can't be indexed by scanline",
+    ),
+    (
+        "region.outside",
+        "Region {0}x{1} at {2},{3} is outside the image",
+    ),
+    (
+        "region.wrong_index",
+        "Scanline index was built for different image data",
+    ),
+    (
+        "redact.block_size",
+        "Redaction block size must be at least 1",
+    ),
+    ("deepzoom.tile_size", "Tile size must be at least one pixel"),
+    (
+        "compare.size_mismatch",
+        "Cannot compare a {0}x{1} image with a {2}x{3} image",
+    ),
+    (
+        "session.out_of_range",
+        "Chunk index out of range for {0} chunks",
+    ),
+    ("session.no_chunks", "Edited image has no chunks"),
+    (
+        "session.no_iend",
+        "Edited image must end with an IEND chunk",
+    ),
+    // payloads and layers
+    ("payload.not_letters", "{0} is not four ASCII letters"),
+    (
+        "payload.not_private",
+        "{0} is not a private ancillary chunk type",
+    ),
+    ("payload.corrupt", "Compressed payload: {0}"),
+    (
+        "payload.needs_codec",
+        "Compressed payloads need the codec feature",
+    ),
+    ("payload.unknown_encoding", "Unknown payload encoding {0}"),
+    ("payload.no_header", "{0} chunk has no payload header"),
+    ("layers.unknown_version", "Unknown {0} chunk version"),
+    ("layers.malformed", "Malformed {0} chunk"),
+    (
+        "layers.name_too_long",
+        "Layer name is {0} bytes, a {1} chunk holds at most {2}",
+    ),
+    // golden files
+    (
+        "golden.actual_unreadable",
+        "could not decode the actual image: {0}",
+    ),
+    ("golden.unreadable", "could not decode {0}: {1}"),
+    ("golden.unwritable", "could not write {0}: {1}"),
+    (
+        "golden.missing",
+        "golden file {0} does not exist, run with {1}=1 to create it",
+    ),
+    (
+        "golden.size_differs",
+        "size differs, expected {0}x{1} but got {2}x{3}",
+    ),
+    ("golden.pixels_differ", "{0} of {1} pixels differ, see {2}"),
+    (
+        "golden.mismatch",
+        "{0} does not match: {1}, actual image saved to {2}",
+    ),
+    ("golden.failed", "assert_png_eq! failed: {0}"),
+    // reports
+    (
+        "report.compression",
+        "{0} -> {1} bytes ({2}%), {3} blocks ({4} stored, {5} fixed, {6} dynamic) in {7}",
+    ),
+    (
+        "report.memory",
+        "{0} bytes ({1} chunks, {2} pixels, {3} palettes, {4} caches)",
+    ),
+    // diagnostics
+    ("diag.error", "error: {0}"),
+    ("diag.offset", "offset"),
+    ("diag.help", "help: {0}"),
+    ("diag.not_png", "not a png file"),
+    ("diag.expected_signature", "expected {0}"),
+    (
+        "diag.text_mode",
+        "the signature looks mangled by a text mode transfer, copy the file as binary",
+    ),
+    ("diag.no_iend", "file ends without an IEND chunk"),
+    ("diag.last_chunk", "the last chunk ends here"),
+    ("diag.header_cut", "file ends inside a chunk header"),
+    ("diag.header_bytes", "only {0} of 8 header bytes"),
+    ("diag.bad_type", "invalid chunk type"),
+    ("diag.type_letters", "chunk types are four ASCII letters"),
+    ("diag.first_not_ihdr", "first chunk must be IHDR"),
+    ("diag.found", "found {0}"),
+    ("diag.ihdr_size", "IHDR chunk must be 13 bytes long"),
+    ("diag.length_is", "length is {0}"),
+    ("diag.past_end", "{0} chunk runs past the end of the file"),
+    (
+        "diag.length_remaining",
+        "length is {0} but only {1} bytes remain",
+    ),
+    ("diag.crc_mismatch", "CRC mismatch in {0} chunk"),
+    ("diag.computed", "computed {0}"),
+    // command line
+    ("cli.error", "error: {0}"),
+    ("cli.usage", "usage: {0}"),
+    ("cli.arguments", "arguments:"),
+    ("cli.options", "options:"),
+    ("cli.commands", "commands:"),
+    ("cli.plugins", "plugins:"),
+    (
+        "cli.help_hint",
+        "run 'whats-a-png help <command>' for a command's options",
+    ),
+    ("cli.unknown_command", "unknown command '{0}'"),
+    ("cli.unknown_option", "unknown option --{0} for {1}"),
+    ("cli.needs_value", "--{0} needs a value"),
+    ("cli.invalid_value", "invalid value '{0}' for --{1}"),
+    ("cli.missing_argument", "missing argument"),
+    ("cli.too_many_arguments", "too many arguments"),
+    ("cli.unknown_palette_format", "unknown palette format '{0}'"),
+    ("cli.invalid_area", "invalid area '{0}', expected X,Y,W,H"),
+    (
+        "cli.invalid_region",
+        "invalid region '{0}', expected X,Y,W,H:T",
+    ),
+    (
+        "cli.invalid_region_threshold",
+        "invalid threshold in region '{0}'",
+    ),
+    (
+        "cli.images_differ",
+        "{0} of {1} pixels differ, within {2}x{3} at {4},{5}",
+    ),
+    (
+        "cli.images_match",
+        "images match ({0} anti-aliased, {1} ignored pixels)",
+    ),
+    ("cli.image_data", "image data: {0}"),
+    ("cli.memory", "memory: {0}"),
+    ("cli.unknown_preset", "unknown preset '{0}'"),
+    ("cli.optimized", "{0}: {1} -> {2} bytes"),
+    (
+        "cli.no_output",
+        "give an output path or set dir in the [output] table of whats-a-png.toml",
+    ),
+    ("cli.exported_chunks", "wrote {0} chunks to {1}"),
+    (
+        "cli.unknown_shell",
+        "unknown shell '{0}', expected bash, zsh or fish",
+    ),
+    // man page
+    (
+        "man.description",
+        "Inspects, compares and rewrites png files. {0} may appear anywhere before a -- separator, except as the value of another option, and reports failures as json on stdout.",
+    ),
+    (
+        "man.catalog",
+        "a \"key = template\" message catalog to use instead of english",
+    ),
+    (
+        "man.config",
+        "defaults for command options, read from the working directory or its parents",
+    ),
+    ("man.exit_success", "success"),
+    ("man.exit_mismatch", "compare found differences"),
+    ("man.exit_usage", "bad arguments or an unusable catalog file"),
+    (
+        "man.exit_config",
+        "whats-a-png.toml doesn't parse or names options that don't exist",
+    ),
+    ("man.exit_io", "a file couldn't be read or written"),
+    ("man.exit_png", "the png is broken or the operation on it failed"),
+    // whats-a-png.toml
+    (
+        "config.bad_line",
+        "{0} line {1}: expected [table] or key = value, found '{2}'",
+    ),
+    ("config.bad_value", "{0} line {1}: unsupported value {2}"),
+    (
+        "config.outside_section",
+        "{0} line {1}: '{2}' must be inside a [command] table",
+    ),
+    ("config.unknown_setting", "{0}: no setting '{1}' in [{2}]"),
+    // provenance
+    (
+        "provenance.malformed",
+        "malformed content credentials in the caBX chunk",
+    ),
+    ("provenance.no_idat", "the image has no IDAT chunk to hash"),
+    // chunk directories
+    (
+        "chunks.no_list",
+        "the chunk manifest doesn't list any chunks",
+    ),
+    (
+        "chunks.missing_field",
+        "chunk {0} in the manifest has no {1}",
+    ),
+    (
+        "chunks.bad_file",
+        "{0} is not a file name inside the chunk directory",
+    ),
+    ("chunks.unreadable", "could not read {0}: {1}"),
+    ("chunks.unwritable", "could not write {0}: {1}"),
+    // json
+    ("json.invalid", "invalid json at byte {0}"),
+    (
+        "json.too_deep",
+        "json at byte {0} is nested deeper than {1} levels",
+    ),
+    // catalog files
+    ("catalog.bad_line", "line {0} is not a key = value pair"),
+];
+
+static PROVIDER: RwLock<Option<Arc<dyn LocaleProvider>>> = RwLock::new(None);
+
+// installs the provider for the whole process, None goes back to english
+pub fn set_locale_provider(provider: Option<Arc<dyn LocaleProvider>>) {
+    *PROVIDER.write().unwrap_or_else(|e| e.into_inner()) = provider;
+}
+
+pub fn english(key: &str) -> Option<&'static str> {
+    ENGLISH.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+// one pass so braces inside the arguments are left alone
+fn fill(template: &str, args: &[&dyn Display]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        out += &rest[..open];
+        let after = &rest[open + 1..];
+
+        let arg = after
+            .find('}')
+            .and_then(|close| Some((after[..close].parse::<usize>().ok()?, close)))
+            .and_then(|(i, close)| Some((args.get(i)?, close)));
+
+        match arg {
+            Some((arg, close)) => {
+                out += &arg.to_string();
+                rest = &after[close + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+
+    out + rest
+}
+
+fn lookup(key: &str) -> Option<String> {
+    let provider = PROVIDER.read().unwrap_or_else(|e| e.into_inner());
+    provider.as_ref().and_then(|p| p.lookup(key))
+}
+
+// for text that isn't in the english table, like the help of a cli command
+pub fn message_or(key: &str, default: &str, args: &[&dyn Display]) -> String {
+    match lookup(key) {
+        Some(template) => fill(&template, args),
+        None => fill(english(key).unwrap_or(default), args),
+    }
+}
+
+// the message for key in the current locale, unknown keys come back as is
+pub fn message(key: &str, args: &[&dyn Display]) -> String {
+    message_or(key, key, args)
+}
+
+// a provider read from "key = template" lines, # starts a comment
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    entries: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn parse(text: &str) -> Result<Self, PngError> {
+        let mut catalog = Catalog::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once('=') {
+                Some((key, template)) => catalog.insert(key.trim(), template.trim()),
+                None => {
+                    return Err(PngError::InvalidOperation(message(
+                        "catalog.bad_line",
+                        &[&(i + 1)],
+                    )))
+                }
+            }
+        }
+
+        Ok(catalog)
+    }
+
+    pub fn insert(&mut self, key: &str, template: &str) {
+        self.entries.insert(key.to_string(), template.to_string());
+    }
+}
+
+impl LocaleProvider for Catalog {
+    fn lookup(&self, key: &str) -> Option<String> {
+        self.entries.get(key).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_english_messages() {
+        assert_eq!(
+            message("diag.length_remaining", &[&12, &4]),
+            "length is 12 but only 4 bytes remain"
+        );
+        assert_eq!(
+            message("region.outside", &[&1, &2, &3, &4]),
+            "Region 1x2 at 3,4 is outside the image"
+        );
+        assert_eq!(message("no.such.key", &[]), "no.such.key");
+        assert_eq!(fill("{0} {1} {x} {2}", &[&"{1}", &1]), "{1} 1 {x} {2}");
+        assert_eq!(message_or("cli.about", "About {0}", &[&"it"]), "About it");
+        assert_eq!(
+            PngError::InvalidChunkType("x".to_string()).get_message(),
+            "Invalid chunk type: x"
+        );
+    }
+
+    #[test]
+    fn test_catalog_provider() {
+        let catalog = Catalog::parse(
+            "# only used by this test so other tests keep seeing english\n\
+             test.reorder = {1} avant {0}\n\n",
+        )
+        .unwrap();
+        assert!(Catalog::parse("no separator").is_err());
+
+        set_locale_provider(Some(Arc::new(catalog)));
+        assert_eq!(message("test.reorder", &[&"a", &"b"]), "b avant a");
+        // keys the catalog lacks fall back to english
+        assert_eq!(message("diag.not_png", &[]), "not a png file");
+
+        set_locale_provider(None);
+        assert_eq!(message("test.reorder", &[]), "test.reorder");
+    }
+}
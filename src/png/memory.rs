@@ -0,0 +1,273 @@
+// how much memory images and the structures around them hold
+//
+// a footprint is the heap bytes a value owns, counted by capacity rather than
+// length, plus its own inline size, split by what the memory is for. data
+// shared behind an Arc is counted by every owner.
+
+use std::{
+    fmt::{Display, Formatter},
+    mem::size_of,
+    ops::{Add, AddAssign},
+};
+
+use super::{locale::message, PNGChunk, PngImage};
+
+// chunks counted as palettes instead of chunk payloads
+const PALETTE_CHUNKS: [&str; 2] = ["PLTE", "tRNS"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Footprint {
+    // chunk headers and payloads other than palettes
+    pub chunks: usize,
+    // decoded pixel buffers
+    pub pixels: usize,
+    // PLTE and tRNS chunks and extracted palettes
+    pub palettes: usize,
+    // undo/redo snapshots and edit logs
+    pub caches: usize,
+}
+
+impl Footprint {
+    pub fn total(&self) -> usize {
+        self.chunks + self.pixels + self.palettes + self.caches
+    }
+
+    // everything in other counted as caches, for copies kept around for undo
+    pub(crate) fn as_cache(self) -> Self {
+        Footprint {
+            caches: self.total(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Add for Footprint {
+    type Output = Footprint;
+
+    fn add(self, other: Footprint) -> Footprint {
+        Footprint {
+            chunks: self.chunks + other.chunks,
+            pixels: self.pixels + other.pixels,
+            palettes: self.palettes + other.palettes,
+            caches: self.caches + other.caches,
+        }
+    }
+}
+
+impl AddAssign for Footprint {
+    fn add_assign(&mut self, other: Footprint) {
+        *self = *self + other;
+    }
+}
+
+impl Display for Footprint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let report = message(
+            "report.memory",
+            &[
+                &self.total(),
+                &self.chunks,
+                &self.pixels,
+                &self.palettes,
+                &self.caches,
+            ],
+        );
+        f.write_str(&report)
+    }
+}
+
+pub trait MemoryFootprint {
+    fn memory_footprint(&self) -> Footprint;
+}
+
+impl MemoryFootprint for PNGChunk {
+    fn memory_footprint(&self) -> Footprint {
+        let bytes = size_of::<PNGChunk>() + self.chunk_type.capacity() + self.data.capacity();
+
+        if PALETTE_CHUNKS.contains(&self.chunk_type.as_str()) {
+            Footprint {
+                palettes: bytes,
+                ..Default::default()
+            }
+        } else {
+            Footprint {
+                chunks: bytes,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+impl MemoryFootprint for PngImage {
+    fn memory_footprint(&self) -> Footprint {
+        let mut footprint = Footprint {
+            chunks: size_of::<PngImage>()
+                + (self.chunks.capacity() - self.chunks.len()) * size_of::<PNGChunk>(),
+            ..Default::default()
+        };
+        for chunk in &self.chunks {
+            footprint += chunk.memory_footprint();
+        }
+        footprint
+    }
+}
+
+#[cfg(feature = "codec")]
+impl MemoryFootprint for super::PixelBuffer {
+    fn memory_footprint(&self) -> Footprint {
+        Footprint {
+            pixels: size_of::<Self>() + self.data.capacity(),
+            ..Default::default()
+        }
+    }
+}
+
+// the colors palette::dominant_colors returns
+impl MemoryFootprint for Vec<[u8; 3]> {
+    fn memory_footprint(&self) -> Footprint {
+        Footprint {
+            palettes: size_of::<Self>() + self.capacity() * 3,
+            ..Default::default()
+        }
+    }
+}
+
+// counts every heap allocation made through it, install it with
+//
+//   #[global_allocator]
+//   static ALLOCATOR: CountingAllocator = CountingAllocator::new();
+//
+// and read ALLOCATOR.allocated() to see what the whole process holds
+#[cfg(feature = "alloc-stats")]
+pub use counting::CountingAllocator;
+
+#[cfg(feature = "alloc-stats")]
+mod counting {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    pub struct CountingAllocator {
+        allocated: AtomicUsize,
+        peak: AtomicUsize,
+        allocations: AtomicUsize,
+    }
+
+    impl CountingAllocator {
+        pub const fn new() -> Self {
+            CountingAllocator {
+                allocated: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                allocations: AtomicUsize::new(0),
+            }
+        }
+
+        // bytes currently allocated
+        pub fn allocated(&self) -> usize {
+            self.allocated.load(Ordering::Relaxed)
+        }
+
+        // the most allocated at once since the start or the last reset_peak
+        pub fn peak(&self) -> usize {
+            self.peak.load(Ordering::Relaxed)
+        }
+
+        // number of allocations made so far, reallocations included
+        pub fn allocations(&self) -> usize {
+            self.allocations.load(Ordering::Relaxed)
+        }
+
+        pub fn reset_peak(&self) {
+            self.peak.store(self.allocated(), Ordering::Relaxed);
+        }
+
+        fn grow(&self, bytes: usize) {
+            let now = self.allocated.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            self.peak.fetch_max(now, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn shrink(&self, bytes: usize) {
+            self.allocated.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+
+    impl Default for CountingAllocator {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    // every call goes straight to the system allocator, only the counters are added
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                self.grow(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc_zeroed(layout);
+            if !ptr.is_null() {
+                self.grow(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            self.shrink(layout.size());
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = System.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                self.shrink(layout.size());
+                self.grow(new_size);
+            }
+            new_ptr
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_image_footprint() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        let before = image.memory_footprint();
+        let payload: usize = image.chunks.iter().map(|c| c.data.len()).sum();
+        assert!(before.chunks >= payload);
+        assert_eq!((before.pixels, before.palettes, before.caches), (0, 0, 0));
+
+        image.chunks.push(PNGChunk::new("PLTE", vec![0; 768]));
+        let after = image.memory_footprint();
+        assert!(after.palettes >= 768);
+        assert!(after.total() > before.total());
+    }
+
+    #[cfg(feature = "alloc-stats")]
+    #[test]
+    fn test_counting_allocator() {
+        use std::alloc::{GlobalAlloc, Layout};
+
+        let allocator = CountingAllocator::new();
+        let layout = Layout::from_size_align(1024, 8).unwrap();
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            let ptr = allocator.realloc(ptr, layout, 4096);
+            assert_eq!(allocator.allocated(), 4096);
+            allocator.dealloc(ptr, Layout::from_size_align(4096, 8).unwrap());
+        }
+
+        assert_eq!(allocator.allocated(), 0);
+        assert_eq!(allocator.peak(), 4096);
+        assert_eq!(allocator.allocations(), 2);
+    }
+}
@@ -0,0 +1,289 @@
+// pulls the dominant colors out of an image with k-means and writes them in
+// formats design tools can import
+
+use std::collections::HashMap;
+
+use super::{PixelBuffer, PngError, PngImage};
+
+// rounds of k-means before settling for what it has
+const MAX_ITERATIONS: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteFormat {
+    // GIMP / Inkscape / Krita palette
+    Gpl,
+    // Adobe swatch exchange
+    Ase,
+    // one #rrggbb per line
+    Hex,
+}
+
+impl PaletteFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "gpl" => Some(PaletteFormat::Gpl),
+            "ase" => Some(PaletteFormat::Ase),
+            "hex" | "txt" => Some(PaletteFormat::Hex),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bin {
+    color: [f64; 3],
+    weight: f64,
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    (0..3).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
+
+// groups the visible pixels into 6 bit per channel bins holding their mean
+// color, which keeps k-means fast on large photos
+fn color_bins(pixels: &PixelBuffer) -> Vec<Bin> {
+    let mut sums: HashMap<u32, [u64; 4]> = HashMap::new();
+
+    for pixel in pixels.data.chunks_exact(4) {
+        // mostly transparent pixels aren't part of what the image looks like
+        if pixel[3] < 128 {
+            continue;
+        }
+
+        let key = (pixel[0] as u32 >> 2) << 12 | (pixel[1] as u32 >> 2) << 6 | pixel[2] as u32 >> 2;
+        let sum = sums.entry(key).or_default();
+        for c in 0..3 {
+            sum[c] += pixel[c] as u64;
+        }
+        sum[3] += 1;
+    }
+
+    let mut bins: Vec<(u32, Bin)> = sums
+        .into_iter()
+        .map(|(key, s)| {
+            let n = s[3] as f64;
+            let bin = Bin {
+                color: [s[0] as f64 / n, s[1] as f64 / n, s[2] as f64 / n],
+                weight: n,
+            };
+            (key, bin)
+        })
+        .collect();
+
+    // hash map order would make the result differ between runs
+    bins.sort_by_key(|(key, _)| *key);
+    bins.into_iter().map(|(_, bin)| bin).collect()
+}
+
+// deterministic k-means++ style seeding, the heaviest bin first and then
+// whichever bin is worst served by the centers so far
+fn seed_centers(bins: &[Bin], count: usize) -> Vec<[f64; 3]> {
+    let heaviest = bins
+        .iter()
+        .max_by(|a, b| a.weight.total_cmp(&b.weight))
+        .unwrap();
+    let mut centers = vec![heaviest.color];
+    let mut nearest: Vec<f64> = bins
+        .iter()
+        .map(|b| distance(b.color, heaviest.color))
+        .collect();
+
+    while centers.len() < count {
+        let (index, score) = bins
+            .iter()
+            .zip(&nearest)
+            .map(|(b, d)| b.weight * d)
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+
+        if score <= 0.0 {
+            break;
+        }
+
+        let center = bins[index].color;
+        for (d, bin) in nearest.iter_mut().zip(bins) {
+            *d = d.min(distance(bin.color, center));
+        }
+        centers.push(center);
+    }
+
+    centers
+}
+
+// the most common colors of the visible pixels, most used first
+pub fn dominant_colors(pixels: &PixelBuffer, count: usize) -> Vec<[u8; 3]> {
+    let bins = color_bins(pixels);
+    if bins.is_empty() || count == 0 {
+        return vec![];
+    }
+
+    let mut centers = seed_centers(&bins, count);
+    let mut assignment = vec![usize::MAX; bins.len()];
+    let mut weights = vec![0.0; centers.len()];
+
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (i, bin) in bins.iter().enumerate() {
+            let closest = (0..centers.len())
+                .min_by(|a, b| {
+                    distance(bin.color, centers[*a]).total_cmp(&distance(bin.color, centers[*b]))
+                })
+                .unwrap();
+
+            if assignment[i] != closest {
+                assignment[i] = closest;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![[0.0; 3]; centers.len()];
+        weights = vec![0.0; centers.len()];
+        for (bin, &cluster) in bins.iter().zip(&assignment) {
+            for (sum, c) in sums[cluster].iter_mut().zip(bin.color) {
+                *sum += c * bin.weight;
+            }
+            weights[cluster] += bin.weight;
+        }
+
+        for (center, (sum, weight)) in centers.iter_mut().zip(sums.iter().zip(&weights)) {
+            if *weight > 0.0 {
+                *center = [sum[0] / weight, sum[1] / weight, sum[2] / weight];
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut clusters: Vec<([f64; 3], f64)> = centers
+        .into_iter()
+        .zip(weights)
+        .filter(|(_, weight)| *weight > 0.0)
+        .collect();
+    clusters.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    clusters
+        .into_iter()
+        .map(|(color, _)| color.map(|c| c.round().clamp(0.0, 255.0) as u8))
+        .collect()
+}
+
+fn to_hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn write_gpl(colors: &[[u8; 3]], name: &str) -> Vec<u8> {
+    let mut out = format!("GIMP Palette\nName: {}\nColumns: 8\n#\n", name);
+    for color in colors {
+        out += &format!(
+            "{:3} {:3} {:3}\t{}\n",
+            color[0],
+            color[1],
+            color[2],
+            to_hex(*color)
+        );
+    }
+    out.into_bytes()
+}
+
+fn write_ase(colors: &[[u8; 3]]) -> Vec<u8> {
+    let mut out = b"ASEF".to_vec();
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&(colors.len() as u32).to_be_bytes());
+
+    for color in colors {
+        // names are null terminated utf-16 with the length counted in code units
+        let name: Vec<u16> = to_hex(*color).encode_utf16().chain([0]).collect();
+
+        let mut block = (name.len() as u16).to_be_bytes().to_vec();
+        for unit in &name {
+            block.extend_from_slice(&unit.to_be_bytes());
+        }
+        block.extend_from_slice(b"RGB ");
+        for c in color {
+            block.extend_from_slice(&(*c as f32 / 255.0).to_be_bytes());
+        }
+        // global swatch
+        block.extend_from_slice(&0u16.to_be_bytes());
+
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        out.extend(block);
+    }
+
+    out
+}
+
+pub fn write_palette(colors: &[[u8; 3]], format: PaletteFormat, name: &str) -> Vec<u8> {
+    match format {
+        PaletteFormat::Gpl => write_gpl(colors, name),
+        PaletteFormat::Ase => write_ase(colors),
+        PaletteFormat::Hex => colors
+            .iter()
+            .map(|c| to_hex(*c) + "\n")
+            .collect::<String>()
+            .into_bytes(),
+    }
+}
+
+impl PngImage {
+    // the image's dominant colors as a palette file
+    pub fn export_palette(
+        &self,
+        format: PaletteFormat,
+        colors: usize,
+    ) -> Result<Vec<u8>, PngError> {
+        let palette = dominant_colors(&self.decode()?, colors);
+        Ok(write_palette(&palette, format, "whats-a-png"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{EncodeOptions, Rect};
+
+    fn stripes() -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(30, 10).unwrap();
+        pixels.fill_rect(Rect::new(0, 0, 15, 10), [200, 30, 30, 255]);
+        pixels.fill_rect(Rect::new(15, 0, 10, 10), [20, 40, 220, 255]);
+        pixels.fill_rect(Rect::new(25, 0, 5, 10), [250, 250, 250, 255]);
+        // nudges that should fall into the red cluster
+        pixels.set_pixel(0, 0, [204, 30, 30, 255]);
+        pixels.set_pixel(1, 0, [196, 30, 30, 255]);
+        pixels
+    }
+
+    #[test]
+    fn test_dominant_colors() {
+        let pixels = stripes();
+        assert_eq!(
+            dominant_colors(&pixels, 3),
+            [[200, 30, 30], [20, 40, 220], [250, 250, 250]]
+        );
+        assert_eq!(dominant_colors(&pixels, 10).len(), 5);
+        assert!(dominant_colors(&PixelBuffer::new(4, 4).unwrap(), 3).is_empty());
+    }
+
+    #[test]
+    fn test_palette_formats() {
+        let image = PngImage::from_pixels(&stripes(), &EncodeOptions::default()).unwrap();
+
+        let hex = image.export_palette(PaletteFormat::Hex, 3).unwrap();
+        assert_eq!(hex, b"#c81e1e\n#1428dc\n#fafafa\n");
+
+        let gpl = image.export_palette(PaletteFormat::Gpl, 2).unwrap();
+        let gpl = String::from_utf8(gpl).unwrap();
+        assert!(gpl.starts_with("GIMP Palette\n"));
+        // blue and white share a cluster when there are only two
+        assert!(gpl.ends_with("200  30  30\t#c81e1e\n 97 110 230\t#616ee6\n"));
+
+        let ase = image.export_palette(PaletteFormat::Ase, 3).unwrap();
+        assert_eq!(&ase[..12], b"ASEF\0\x01\0\0\0\0\0\x03");
+        // type, length, 8 units of name, model, 3 floats and the swatch type
+        assert_eq!(ase.len(), 12 + 3 * (6 + 2 + 16 + 4 + 12 + 2));
+    }
+}
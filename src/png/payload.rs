@@ -0,0 +1,214 @@
+// storing application data in private ancillary chunks
+//
+// every payload chunk starts with PAYLOAD_MAGIC and a flag byte saying how the
+// rest is encoded, stored as given or deflated. the chunk type is what marks a
+// chunk as a payload, so payloads() expects the header on every chunk of the
+// type it is asked for instead of guessing from what the data looks like.
+
+use std::borrow::Cow;
+
+use super::{locale::message, PNGChunk, PngError, PngImage};
+
+const PAYLOAD_MAGIC: [u8; 3] = [0x89, b'z', b'P'];
+const FLAG_STORED: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+#[cfg(feature = "codec")]
+const COMPRESSION_LEVEL: u8 = 6;
+// compressed payloads come from files, this is as far as they may inflate
+pub const MAX_PAYLOAD_SIZE: usize = 64 << 20;
+
+// private chunks must be ancillary, private and have the reserved bit clear
+pub fn check_private_type(chunk_type: &str) -> Result<(), PngError> {
+    let bytes = chunk_type.as_bytes();
+
+    if bytes.len() != 4 || !bytes.iter().all(|b| b.is_ascii_alphabetic()) {
+        return Err(PngError::InvalidChunkType(message(
+            "payload.not_letters",
+            &[&chunk_type],
+        )));
+    }
+
+    if !bytes[0].is_ascii_lowercase()
+        || !bytes[1].is_ascii_lowercase()
+        || !bytes[2].is_ascii_uppercase()
+    {
+        return Err(PngError::InvalidChunkType(message(
+            "payload.not_private",
+            &[&chunk_type],
+        )));
+    }
+
+    Ok(())
+}
+
+fn with_header(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    out.extend_from_slice(&PAYLOAD_MAGIC);
+    out.push(flag);
+    out.extend_from_slice(data);
+    out
+}
+
+fn encode_payload(data: Vec<u8>, compress: bool) -> Vec<u8> {
+    #[cfg(feature = "codec")]
+    if compress {
+        let compressed = super::deflate::deflate(&data, COMPRESSION_LEVEL);
+        // small or random data can come out bigger
+        if compressed.len() + 4 < data.len() {
+            return with_header(FLAG_DEFLATE, &compressed);
+        }
+    }
+    #[cfg(not(feature = "codec"))]
+    let _ = compress;
+
+    with_header(FLAG_STORED, &data)
+}
+
+fn decode_payload<'a>(
+    chunk_type: &str,
+    data: &'a [u8],
+    limit: usize,
+) -> Result<Cow<'a, [u8]>, PngError> {
+    let rest = match data.strip_prefix(&PAYLOAD_MAGIC) {
+        Some(rest) if !rest.is_empty() => rest,
+        _ => {
+            return Err(PngError::InvalidImageData(message(
+                "payload.no_header",
+                &[&chunk_type],
+            )))
+        }
+    };
+
+    match rest[0] {
+        FLAG_STORED => Ok(Cow::Borrowed(&rest[1..])),
+        #[cfg(feature = "codec")]
+        FLAG_DEFLATE => super::inflate::inflate(&rest[1..], limit)
+            .map(Cow::Owned)
+            .map_err(|e| PngError::StreamFailed(message("payload.corrupt", &[&e]))),
+        #[cfg(not(feature = "codec"))]
+        FLAG_DEFLATE => {
+            let _ = limit;
+            Err(PngError::InvalidOperation(message(
+                "payload.needs_codec",
+                &[],
+            )))
+        }
+        flag => Err(PngError::InvalidOperation(message(
+            "payload.unknown_encoding",
+            &[&flag],
+        ))),
+    }
+}
+
+impl PngImage {
+    // adds the payload as a new chunk right before IEND
+    pub fn add_payload(&mut self, chunk_type: &str, data: Vec<u8>) -> Result<(), PngError> {
+        self.add_payload_with(chunk_type, data, false)
+    }
+
+    // compress deflates the payload when that makes it smaller, payloads
+    // inflates it again on the way out, up to MAX_PAYLOAD_SIZE
+    pub fn add_payload_with(
+        &mut self,
+        chunk_type: &str,
+        data: Vec<u8>,
+        compress: bool,
+    ) -> Result<(), PngError> {
+        check_private_type(chunk_type)?;
+        let data = encode_payload(data, compress);
+
+        let index = match self.chunks.iter().rposition(|c| c.chunk_type == "IEND") {
+            Some(i) => i,
+            None => self.chunks.len(),
+        };
+
+        self.chunks.insert(index, PNGChunk::new(chunk_type, data));
+        Ok(())
+    }
+
+    // the payloads as they were added, decompressed if need be
+    pub fn payloads<'a>(
+        &'a self,
+        chunk_type: &'a str,
+    ) -> impl Iterator<Item = Result<Cow<'a, [u8]>, PngError>> {
+        self.get_chunks(chunk_type)
+            .map(move |c| decode_payload(chunk_type, &c.data, MAX_PAYLOAD_SIZE))
+    }
+
+    pub fn remove_payloads(&mut self, chunk_type: &str) -> usize {
+        let before = self.chunks.len();
+        self.chunks.retain(|c| c.chunk_type != chunk_type);
+        before - self.chunks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_payload_round_trip() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image.add_payload("apPs", b"first".to_vec()).unwrap();
+        image.add_payload("apPs", b"second".to_vec()).unwrap();
+
+        let saved = PngImage::from_bytes(image.to_bytes()).unwrap();
+        let payloads: Vec<_> = saved.payloads("apPs").map(|p| p.unwrap()).collect();
+        assert_eq!(payloads, [b"first".as_slice(), b"second".as_slice()]);
+        assert_eq!(saved.chunks.last().unwrap().chunk_type, "IEND");
+
+        let mut saved = saved;
+        assert_eq!(saved.remove_payloads("apPs"), 2);
+        assert_eq!(saved.payloads("apPs").count(), 0);
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn test_compressed_payloads() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        let text = b"the same line over and over\n".repeat(200);
+        image.add_payload_with("apPs", text.clone(), true).unwrap();
+        // too short to shrink, stays as it is
+        image
+            .add_payload_with("apPs", b"tiny".to_vec(), true)
+            .unwrap();
+        // raw data that looks like a header survives the round trip
+        let lookalike = [&PAYLOAD_MAGIC[..], &[FLAG_DEFLATE, 1, 2]].concat();
+        image.add_payload("apPs", lookalike.clone()).unwrap();
+
+        let stored: Vec<usize> = image.get_chunks("apPs").map(|c| c.data.len()).collect();
+        assert!(stored[0] < text.len() / 10);
+        assert_eq!(stored[1], 4 + 4);
+
+        let saved = PngImage::from_bytes(image.to_bytes()).unwrap();
+        let payloads: Vec<_> = saved.payloads("apPs").map(|p| p.unwrap()).collect();
+        assert_eq!(payloads[0], text.as_slice());
+        assert_eq!(payloads[1], b"tiny".as_slice());
+        assert_eq!(payloads[2], lookalike.as_slice());
+    }
+
+    #[cfg(feature = "codec")]
+    #[test]
+    fn test_payload_limits() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        // a chunk of the type written by something else
+        image
+            .chunks
+            .insert(1, PNGChunk::new("apPs", b"foreign".to_vec()));
+        assert!(image.payloads("apPs").next().unwrap().is_err());
+
+        // zeros deflate to almost nothing, the limit stops them inflating
+        let zeros = encode_payload(vec![0; 4096], true);
+        assert!(decode_payload("apPs", &zeros, 4096).is_ok());
+        assert!(decode_payload("apPs", &zeros, 4095).is_err());
+    }
+
+    #[test]
+    fn test_payload_type_must_be_private() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        assert!(image.add_payload("tEXt", vec![]).is_err());
+        assert!(image.add_payload("IDAT", vec![]).is_err());
+        assert!(image.add_payload("ap1s", vec![]).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+// decoded pixels, always stored as 8 bit RGBA regardless of the source format
+
+use super::{locale::message, PngError};
+
+// the most pixels a buffer may hold, a GiB of RGBA. IHDR allows up to 2^31 on
+// each side and a crafted header shouldn't get to allocate whatever it claims
+pub const MAX_PIXELS: usize = 1 << 28;
+
+// bytes of RGBA data in a width x height buffer
+pub(crate) fn rgba_len(width: u32, height: u32) -> Result<usize, PngError> {
+    (width as usize)
+        .checked_mul(height as usize)
+        .filter(|pixels| *pixels <= MAX_PIXELS)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .ok_or_else(|| {
+            PngError::InvalidPngInfo(message("pixels.too_large", &[&width, &height, &MAX_PIXELS]))
+        })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    // the part of the rect that lies inside a width x height image
+    pub fn clip(&self, width: u32, height: u32) -> Option<Rect> {
+        let right = self.x.saturating_add(self.width).min(width);
+        let bottom = self.y.saturating_add(self.height).min(height);
+
+        if self.x >= right || self.y >= bottom {
+            return None;
+        }
+
+        Some(Rect::new(self.x, self.y, right - self.x, bottom - self.y))
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && y >= self.y && x - self.x < self.width && y - self.y < self.height
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PixelBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl PixelBuffer {
+    pub fn new(width: u32, height: u32) -> Result<Self, PngError> {
+        Ok(PixelBuffer {
+            width,
+            height,
+            data: vec![0; rgba_len(width, height)?],
+        })
+    }
+
+    pub fn from_rgba(width: u32, height: u32, data: Vec<u8>) -> Result<Self, PngError> {
+        let expected = rgba_len(width, height)?;
+        if data.len() != expected {
+            return Err(PngError::InvalidOperation(message(
+                "pixels.wrong_length",
+                &[&expected, &width, &height, &data.len()],
+            )));
+        }
+
+        Ok(PixelBuffer {
+            width,
+            height,
+            data,
+        })
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y as usize * self.width as usize + x as usize) * 4
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let i = self.index(x, y);
+        [
+            self.data[i],
+            self.data[i + 1],
+            self.data[i + 2],
+            self.data[i + 3],
+        ]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: [u8; 4]) {
+        let i = self.index(x, y);
+        self.data[i..i + 4].copy_from_slice(&pixel);
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, pixel: [u8; 4]) {
+        let rect = match rect.clip(self.width, self.height) {
+            Some(r) => r,
+            None => return,
+        };
+
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.set_pixel(x, y, pixel);
+            }
+        }
+    }
+
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = self.index(0, y);
+        &self.data[start..start + self.width as usize * 4]
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    // a copy of the part of the buffer inside rect
+    pub fn crop(&self, rect: Rect) -> PixelBuffer {
+        let rect = rect
+            .clip(self.width, self.height)
+            .unwrap_or(Rect::new(0, 0, 0, 0));
+        let (left, right) = (rect.x as usize * 4, (rect.x + rect.width) as usize * 4);
+
+        let mut data = Vec::with_capacity(rect.width as usize * rect.height as usize * 4);
+        for y in rect.y..rect.y + rect.height {
+            data.extend_from_slice(&self.row(y)[left..right]);
+        }
+
+        PixelBuffer {
+            width: rect.width,
+            height: rect.height,
+            data,
+        }
+    }
+
+    // box filter, every pixel averages the block of source pixels it covers.
+    // colors are weighted by alpha so transparent pixels don't bleed into the edges
+    pub fn resize(&self, width: u32, height: u32) -> Result<PixelBuffer, PngError> {
+        let mut out = PixelBuffer::new(width, height)?;
+        if self.width == 0 || self.height == 0 {
+            return Ok(out);
+        }
+
+        let span = |i: u32, from: u32, to: u32| {
+            let start = (i as u64 * from as u64 / to as u64) as u32;
+            let end = ((i as u64 + 1) * from as u64 / to as u64) as u32;
+            start..end.max(start + 1)
+        };
+
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u64; 4];
+                let mut count = 0;
+
+                for sy in span(y, self.height, height) {
+                    for sx in span(x, self.width, width) {
+                        let [r, g, b, a] = self.get_pixel(sx, sy);
+                        let a = a as u64;
+                        sum[0] += r as u64 * a;
+                        sum[1] += g as u64 * a;
+                        sum[2] += b as u64 * a;
+                        sum[3] += a;
+                        count += 1;
+                    }
+                }
+
+                let pixel = match sum[3] {
+                    0 => [0, 0, 0, 0],
+                    alpha => [
+                        ((sum[0] + alpha / 2) / alpha) as u8,
+                        ((sum[1] + alpha / 2) / alpha) as u8,
+                        ((sum[2] + alpha / 2) / alpha) as u8,
+                        ((alpha + count / 2) / count) as u8,
+                    ],
+                };
+                out.set_pixel(x, y, pixel);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rect_clip() {
+        let rect = Rect::new(5, 5, 10, 10);
+        assert_eq!(rect.clip(8, 20), Some(Rect::new(5, 5, 3, 10)));
+        assert_eq!(rect.clip(5, 5), None);
+    }
+
+    #[test]
+    fn test_crop_and_resize() {
+        let mut pixels = PixelBuffer::new(4, 4).unwrap();
+        pixels.fill_rect(Rect::new(0, 0, 2, 4), [200, 100, 0, 255]);
+        pixels.set_pixel(3, 3, [0, 0, 255, 255]);
+
+        let cropped = pixels.crop(Rect::new(1, 1, 10, 10));
+        assert_eq!((cropped.width, cropped.height), (3, 3));
+        assert_eq!(cropped.get_pixel(0, 0), [200, 100, 0, 255]);
+        assert_eq!(cropped.get_pixel(2, 2), [0, 0, 255, 255]);
+
+        let half = pixels.resize(2, 2).unwrap();
+        assert_eq!(half.get_pixel(0, 0), [200, 100, 0, 255]);
+        // one opaque pixel among three transparent ones keeps its color
+        assert_eq!(half.get_pixel(1, 1), [0, 0, 255, 64]);
+    }
+
+    #[test]
+    fn test_buffer_size_limit() {
+        assert!(matches!(
+            PixelBuffer::new(u32::MAX, u32::MAX),
+            Err(PngError::InvalidPngInfo(_))
+        ));
+        assert!(PixelBuffer::new(1 << 15, 1 << 14).is_err());
+        assert!(PixelBuffer::from_rgba(u32::MAX, 2, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_fill_rect() {
+        let mut pixels = PixelBuffer::new(4, 4).unwrap();
+        pixels.fill_rect(Rect::new(2, 2, 10, 10), [1, 2, 3, 4]);
+        assert_eq!(pixels.get_pixel(3, 3), [1, 2, 3, 4]);
+        assert_eq!(pixels.get_pixel(1, 1), [0, 0, 0, 0]);
+    }
+}
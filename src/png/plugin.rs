@@ -0,0 +1,272 @@
+// plumbing for external whats-a-png-<name> commands
+//
+// the cli runs any whats-a-png-<name> executable on the PATH as the <name>
+// subcommand, git style. a plugin written in rust implements Plugin and
+// calls plugin::main, which gives it the same argument handling, parse
+// diagnostics, --json output and exit codes as the built in commands:
+//
+//   struct Chunks;
+//
+//   impl Plugin for Chunks {
+//       fn name(&self) -> &str { "chunks" }
+//       fn run(&self, image: &PngImage, _: &[String]) -> Result<Report, Failure> {
+//           let mut report = Report::default();
+//           report.add("chunks", image.chunks.len());
+//           Ok(report)
+//       }
+//   }
+//
+//   fn main() { std::process::exit(plugin::main(&Chunks)) }
+
+use std::{
+    fmt::{Display, Formatter},
+    fs,
+};
+
+use super::{diagnostics::diagnose, locale::message, PngError, PngImage};
+use crate::json::Value;
+
+// executables named this plus a command name extend the cli
+pub const PLUGIN_PREFIX: &str = "whats-a-png-";
+// the cli passes this on when it was given it
+pub const JSON_FLAG: &str = "--json";
+
+// why a command failed. codes up to 99 are PngError::code, the cli's own
+// failures start at 100, and both are stable so scripts can branch on them
+#[derive(Debug)]
+pub enum Failure {
+    // bad arguments or an unusable catalog file
+    Usage(String),
+    // whats-a-png.toml doesn't parse or names options that don't exist
+    Config(String),
+    // a file couldn't be read or written
+    Io(String),
+    // the png itself is broken or the operation on it failed
+    Png(PngError, String),
+    // compare found differences
+    Mismatch(String),
+}
+
+impl Failure {
+    // the png error's message after context, usually the file it came from
+    pub fn png(context: &str, error: PngError) -> Self {
+        let text = format!("{}: {}", context, error.get_message());
+        Failure::Png(error, text)
+    }
+
+    pub fn code(&self) -> u16 {
+        match self {
+            Failure::Usage(_) => 100,
+            Failure::Io(_) => 101,
+            Failure::Mismatch(_) => 102,
+            Failure::Config(_) => 103,
+            Failure::Png(e, _) => e.code(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Failure::Usage(_) => "usage",
+            Failure::Io(_) => "io",
+            Failure::Mismatch(_) => "images_differ",
+            Failure::Config(_) => "config",
+            Failure::Png(e, _) => e.name(),
+        }
+    }
+
+    // the process exit status, coarser than the code
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Mismatch(_) => 1,
+            Failure::Usage(_) | Failure::Config(_) => 2,
+            Failure::Io(_) => 3,
+            Failure::Png(..) => 4,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Failure::Usage(m)
+            | Failure::Config(m)
+            | Failure::Io(m)
+            | Failure::Png(_, m)
+            | Failure::Mismatch(m) => m,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object(vec![(
+            "error",
+            Value::object(vec![
+                ("code", self.code().into()),
+                ("name", self.name().into()),
+                ("exit_code", self.exit_code().into()),
+                ("message", self.message().into()),
+            ]),
+        )])
+    }
+}
+
+impl From<PngError> for Failure {
+    fn from(error: PngError) -> Self {
+        let text = error.get_message();
+        Failure::Png(error, text)
+    }
+}
+
+// parse failures come with an annotated hex dump of the offending bytes
+pub fn load(path: &str) -> Result<PngImage, Failure> {
+    let bytes = fs::read(path).map_err(|e| Failure::Io(format!("{}: {}", path, e)))?;
+
+    PngImage::from_bytes(bytes.clone()).map_err(|e| {
+        let mut text = format!("{}: {}", path, e.get_message());
+        if let Some(d) = diagnose(&bytes) {
+            text += &format!("\n\n{}", d.render(&bytes));
+        }
+        Failure::Png(e, text)
+    })
+}
+
+// what an analysis found, as labelled values in the order they were added
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub entries: Vec<(String, Value)>,
+}
+
+impl Report {
+    pub fn add(&mut self, label: &str, value: impl Into<Value>) -> &mut Self {
+        self.entries.push((label.to_string(), value.into()));
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Object(self.entries.clone())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (label, value) in &self.entries {
+            match value {
+                Value::String(s) => writeln!(f, "{}: {}", label, s)?,
+                other => writeln!(f, "{}: {}", label, other)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Plugin {
+    // the <name> in whats-a-png-<name>
+    fn name(&self) -> &str;
+
+    // args are everything after the input file
+    fn run(&self, image: &PngImage, args: &[String]) -> Result<Report, Failure>;
+}
+
+// where --json stops being a flag, everything after a -- is left to the plugin
+fn flags_end(args: &[String]) -> usize {
+    args.iter().position(|a| a == "--").unwrap_or(args.len())
+}
+
+// loads the input file named by the first argument and runs the plugin on it
+pub fn run(plugin: &dyn Plugin, args: &[String]) -> Result<Report, Failure> {
+    let (flags, rest) = args.split_at(flags_end(args));
+    let args: Vec<String> = flags
+        .iter()
+        .filter(|a| *a != JSON_FLAG)
+        .chain(rest)
+        .cloned()
+        .collect();
+
+    let input = match args.first() {
+        Some(path) => path,
+        None => {
+            let usage = format!("whats-a-png {} <input> [arguments]", plugin.name());
+            return Err(Failure::Usage(format!(
+                "{}\n\n{}",
+                message("cli.missing_argument", &[]),
+                message("cli.usage", &[&usage])
+            )));
+        }
+    };
+
+    plugin.run(&load(input)?, &args[1..])
+}
+
+// the body of a plugin's main, returns the exit code to leave with
+pub fn main(plugin: &dyn Plugin) -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args[..flags_end(&args)].iter().any(|a| a == JSON_FLAG);
+
+    match (run(plugin, &args), json) {
+        (Ok(report), true) => println!("{}", report.to_json()),
+        (Ok(report), false) => print!("{}", report),
+        (Err(failure), true) => {
+            println!("{}", failure.to_json());
+            return failure.exit_code();
+        }
+        (Err(failure), false) => {
+            eprintln!("{}", message("cli.error", &[&failure.message()]));
+            return failure.exit_code();
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    struct Chunks;
+
+    impl Plugin for Chunks {
+        fn name(&self) -> &str {
+            "chunks"
+        }
+
+        fn run(&self, image: &PngImage, args: &[String]) -> Result<Report, Failure> {
+            if !args.is_empty() {
+                return Err(Failure::Usage("no arguments".to_string()));
+            }
+
+            let mut report = Report::default();
+            report
+                .add("chunks", image.chunks.len())
+                .add("first", image.chunks[0].chunk_type.as_str());
+            Ok(report)
+        }
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_plugin() {
+        let report = run(&Chunks, &args(&[IMAGE_PATH, "--json"])).unwrap();
+        assert_eq!(report.entries[1].1, Value::from("IHDR"));
+        assert!(report.to_string().starts_with("chunks: "));
+        assert!(report.to_json().to_string().ends_with(r#""first":"IHDR"}"#));
+
+        assert_eq!(run(&Chunks, &[]).unwrap_err().code(), 100);
+        assert_eq!(
+            run(&Chunks, &args(&["./missing.png"])).unwrap_err().code(),
+            101
+        );
+        let failure = run(&Chunks, &args(&["./README.md"])).unwrap_err();
+        assert_eq!((failure.code(), failure.exit_code()), (1, 4));
+        assert!(failure.message().contains("not a png file"));
+    }
+
+    #[test]
+    fn test_failure_json() {
+        let failure = Failure::png("a.png", PngError::InvalidFileType);
+        assert_eq!(
+            failure.to_json().to_string(),
+            r#"{"error":{"code":1,"name":"invalid_file_type","exit_code":4,"message":"a.png: Invalid file type"}}"#
+        );
+    }
+}
@@ -0,0 +1,47 @@
+// the stable api, `use whats_a_png::png::prelude::*` to get all of it
+//
+// everything here follows semver: it only changes in a breaking way with a
+// new major version. the public enums and option structs are
+// #[non_exhaustive], so new variants and fields are not breaking changes:
+// build options from Default and assign the fields you need. modules marked #[doc(hidden)] are implementation details
+// that stay public for the crate's own binaries and can change in any release.
+
+#[cfg(feature = "cli")]
+pub use super::plugin::{Failure, Plugin, Report};
+#[cfg(feature = "ops")]
+pub use super::{
+    compare::{CompareOptions, Comparison, RegionThreshold},
+    palette::PaletteFormat,
+    PixelHistory, RedactStyle,
+};
+pub use super::{
+    locale::LocaleProvider,
+    memory::{Footprint, MemoryFootprint},
+    ChunkEdit, EditSession, PNGChunk, PNGInfo, PngError, PngImage,
+};
+#[cfg(feature = "codec")]
+pub use super::{
+    region::{ScanlineIndex, DEFAULT_INDEX_SPACING},
+    transcode, BlockCounts, CompressionStats, EncodeOptions, FilterStrategy, FilterType,
+    MetadataPolicy, PixelBuffer, Preset, Rect, TranscodeOptions,
+};
+
+#[cfg(all(test, feature = "ops"))]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_prelude_round_trip() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let pixels: PixelBuffer = image.decode().unwrap();
+
+        let encoded =
+            PngImage::from_pixels(&pixels, &EncodeOptions::preset(Preset::Fastest)).unwrap();
+        let options = CompareOptions {
+            threshold: 0.0,
+            ..Default::default()
+        };
+        assert!(encoded.compare(&image, &options).unwrap().is_match());
+    }
+}
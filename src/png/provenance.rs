@@ -0,0 +1,308 @@
+// experimental content credentials in the caBX chunk
+//
+// C2PA keeps a JUMBF manifest store in caBX. this writes the same box layout,
+// a jumb superbox labelled c2pa holding a labelled manifest box, but the
+// manifest inside is plain json rather than signed CBOR, so it records where
+// an image came from without proving it. the IDAT hash assertion lets a
+// reader check that the image data hasn't changed since the manifest was
+// written.
+
+use super::{checksum::Sha256, locale::message, PNGChunk, PngError, PngImage};
+use crate::json::Value;
+
+pub const MANIFEST_CHUNK: &str = "caBX";
+pub const IDAT_HASH_LABEL: &str = "org.whats-a-png.hash.idat";
+
+// jumd content types, "c2pa" and "json" followed by the ISO base suffix
+const C2PA_UUID: [u8; 16] = *b"c2pa\x00\x11\x00\x10\x80\x00\x00\xaa\x00\x38\x9b\x71";
+const JSON_UUID: [u8; 16] = *b"json\x00\x11\x00\x10\x80\x00\x00\xaa\x00\x38\x9b\x71";
+// requestable with a label
+const JUMD_TOGGLES: u8 = 0x03;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assertion {
+    // reverse domain names for anything outside the c2pa.* set
+    pub label: String,
+    pub data: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    // the tool that wrote the manifest, name/version
+    pub claim_generator: String,
+    pub title: Option<String>,
+    pub assertions: Vec<Assertion>,
+}
+
+impl Manifest {
+    pub fn new(title: Option<&str>) -> Self {
+        Manifest {
+            claim_generator: format!("whats-a-png/{}", env!("CARGO_PKG_VERSION")),
+            title: title.map(|t| t.to_string()),
+            assertions: vec![],
+        }
+    }
+
+    pub fn add_assertion(&mut self, label: &str, data: Value) -> &mut Self {
+        self.assertions.push(Assertion {
+            label: label.to_string(),
+            data,
+        });
+        self
+    }
+
+    pub fn assertion(&self, label: &str) -> Option<&Assertion> {
+        self.assertions.iter().find(|a| a.label == label)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let assertions = self
+            .assertions
+            .iter()
+            .map(|a| {
+                Value::object(vec![
+                    ("label", a.label.as_str().into()),
+                    ("data", a.data.clone()),
+                ])
+            })
+            .collect();
+
+        Value::object(vec![
+            ("claim_generator", self.claim_generator.as_str().into()),
+            (
+                "title",
+                self.title
+                    .as_deref()
+                    .map(Value::from)
+                    .unwrap_or(Value::Null),
+            ),
+            ("format", "image/png".into()),
+            ("assertions", Value::Array(assertions)),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self, PngError> {
+        let text = |v: Option<&Value>| v.and_then(|v| v.as_str()).map(|s| s.to_string());
+
+        let mut manifest = Manifest {
+            claim_generator: text(value.get("claim_generator")).ok_or_else(malformed)?,
+            title: text(value.get("title")),
+            assertions: vec![],
+        };
+        for a in value
+            .get("assertions")
+            .and_then(|a| a.as_array())
+            .unwrap_or_default()
+        {
+            let label = text(a.get("label")).ok_or_else(malformed)?;
+            manifest.add_assertion(&label, a.get("data").cloned().unwrap_or(Value::Null));
+        }
+
+        Ok(manifest)
+    }
+}
+
+fn malformed() -> PngError {
+    PngError::InvalidOperation(message("provenance.malformed", &[]))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn jumbf_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(box_type);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn write_superbox(uuid: &[u8; 16], label: &str, content: &[u8]) -> Vec<u8> {
+    let mut description = uuid.to_vec();
+    description.push(JUMD_TOGGLES);
+    description.extend_from_slice(label.as_bytes());
+    description.push(0);
+
+    let mut payload = jumbf_box(b"jumd", &description);
+    payload.extend_from_slice(content);
+    jumbf_box(b"jumb", &payload)
+}
+
+type JumbfBox<'a> = (&'a [u8], &'a [u8]);
+
+// the boxes directly inside bytes as (type, payload)
+fn boxes(mut bytes: &[u8]) -> Result<Vec<JumbfBox<'_>>, PngError> {
+    let mut out = vec![];
+
+    while !bytes.is_empty() {
+        if bytes.len() < 8 {
+            return Err(malformed());
+        }
+        let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if size < 8 || size > bytes.len() {
+            return Err(malformed());
+        }
+
+        out.push((&bytes[4..8], &bytes[8..size]));
+        bytes = &bytes[size..];
+    }
+
+    Ok(out)
+}
+
+// the boxes after the description in the first superbox of the given content type
+fn find_superbox<'a>(
+    boxes_in: &[JumbfBox<'a>],
+    uuid: &[u8; 16],
+) -> Result<Option<Vec<JumbfBox<'a>>>, PngError> {
+    for (kind, payload) in boxes_in {
+        if *kind != b"jumb" {
+            continue;
+        }
+
+        let mut inner = boxes(payload)?;
+        if inner
+            .first()
+            .is_some_and(|(k, d)| *k == b"jumd" && d.starts_with(uuid))
+        {
+            inner.remove(0);
+            return Ok(Some(inner));
+        }
+    }
+
+    Ok(None)
+}
+
+impl PngImage {
+    // sha256 over the IDAT payloads in file order
+    pub fn idat_hash(&self) -> [u8; 32] {
+        let mut sha = Sha256::new();
+        for chunk in self.get_chunks("IDAT") {
+            sha.update(&chunk.data);
+        }
+        sha.finish()
+    }
+
+    // replaces any manifest already in the image and adds the IDAT hash to it
+    pub fn embed_manifest(&mut self, manifest: &Manifest) -> Result<(), PngError> {
+        let mut manifest = manifest.clone();
+        manifest.assertions.retain(|a| a.label != IDAT_HASH_LABEL);
+        manifest.add_assertion(
+            IDAT_HASH_LABEL,
+            Value::object(vec![
+                ("alg", "sha256".into()),
+                ("hash", hex(&self.idat_hash()).into()),
+            ]),
+        );
+
+        let content = jumbf_box(b"json", manifest.to_json().to_string().as_bytes());
+        let manifest_box = write_superbox(&JSON_UUID, "manifest", &content);
+        let store = write_superbox(&C2PA_UUID, "c2pa", &manifest_box);
+
+        self.chunks.retain(|c| c.chunk_type != MANIFEST_CHUNK);
+        let index = match self.chunks.iter().position(|c| c.chunk_type == "IDAT") {
+            Some(i) => i,
+            None => {
+                return Err(PngError::InvalidImageData(message(
+                    "provenance.no_idat",
+                    &[],
+                )))
+            }
+        };
+        self.chunks
+            .insert(index, PNGChunk::new(MANIFEST_CHUNK, store));
+
+        Ok(())
+    }
+
+    pub fn read_manifest(&self) -> Result<Option<Manifest>, PngError> {
+        let chunk = match self.get_chunk(MANIFEST_CHUNK) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let store = find_superbox(&boxes(&chunk.data)?, &C2PA_UUID)?.ok_or_else(malformed)?;
+        let manifest = find_superbox(&store, &JSON_UUID)?.ok_or_else(malformed)?;
+        let json = manifest
+            .iter()
+            .find(|(k, _)| *k == b"json")
+            .map(|b| b.1)
+            .ok_or_else(malformed)?;
+
+        let text = std::str::from_utf8(json).map_err(|_| malformed())?;
+        Manifest::from_json(&Value::parse(text)?).map(Some)
+    }
+
+    // whether the IDAT hash assertion still matches, None if there isn't one to check
+    pub fn verify_manifest(&self) -> Result<Option<bool>, PngError> {
+        let manifest = match self.read_manifest()? {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        let hash = match manifest.assertion(IDAT_HASH_LABEL) {
+            Some(a) => a.data.get("hash").and_then(|h| h.as_str()),
+            None => return Ok(None),
+        };
+
+        Ok(Some(hash == Some(hex(&self.idat_hash()).as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        assert_eq!(image.read_manifest().unwrap(), None);
+
+        let mut manifest = Manifest::new(Some("test.png"));
+        manifest.add_assertion(
+            "c2pa.actions",
+            Value::object(vec![(
+                "actions",
+                Value::Array(vec![Value::object(vec![("action", "c2pa.created".into())])]),
+            )]),
+        );
+        image.embed_manifest(&manifest).unwrap();
+        // embedding again replaces the first one
+        image.embed_manifest(&manifest).unwrap();
+        assert_eq!(image.get_chunks(MANIFEST_CHUNK).count(), 1);
+
+        let image = PngImage::from_bytes(image.to_bytes()).unwrap();
+        let read = image.read_manifest().unwrap().unwrap();
+        assert_eq!(read.title.as_deref(), Some("test.png"));
+        assert_eq!(read.assertions.len(), 2);
+        assert_eq!(read.assertions[0], manifest.assertions[0]);
+        assert_eq!(image.verify_manifest().unwrap(), Some(true));
+
+        // the caBX chunk sits before the image data
+        let position = |t: &str| image.chunks.iter().position(|c| c.chunk_type == t);
+        assert!(position(MANIFEST_CHUNK) < position("IDAT"));
+    }
+
+    #[test]
+    fn test_manifest_detects_changes() {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image.embed_manifest(&Manifest::new(None)).unwrap();
+
+        let idat = image
+            .chunks
+            .iter_mut()
+            .find(|c| c.chunk_type == "IDAT")
+            .unwrap();
+        idat.data[10] ^= 1;
+        assert_eq!(image.verify_manifest().unwrap(), Some(false));
+
+        let chunk = image
+            .chunks
+            .iter_mut()
+            .find(|c| c.chunk_type == MANIFEST_CHUNK)
+            .unwrap();
+        chunk.data.truncate(20);
+        assert!(image.read_manifest().is_err());
+    }
+}
@@ -0,0 +1,284 @@
+// destroys the pixels inside a set of regions and strips metadata that could
+// still describe or contain what was there
+
+use super::{locale::message, EncodeOptions, PixelBuffer, PngError, PngImage, Rect};
+
+// ancillary chunks that only describe how to display the pixels
+const REDACT_KEEP_CHUNKS: [&str; 6] = ["gAMA", "cHRM", "sRGB", "iCCP", "cICP", "pHYs"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RedactStyle {
+    SolidBlack,
+    // replaces each n x n block with its average color
+    Pixelate(u32),
+    // pixelates with n x n blocks first and then box blurs with radius n, so
+    // nothing finer than the block averages survives
+    Blur(u32),
+}
+
+fn pixelate(pixels: &mut PixelBuffer, region: Rect, size: u32) {
+    // blocks bigger than the region only cover it once
+    let size = size.min(region.width.max(region.height));
+    let mut by = region.y;
+    while by < region.y + region.height {
+        let mut bx = region.x;
+        while bx < region.x + region.width {
+            let block = Rect::new(bx, by, size, size)
+                .clip(region.x + region.width, region.y + region.height);
+            let block = match block {
+                Some(b) => b,
+                None => break,
+            };
+
+            let mut sum = [0u64; 4];
+            for y in block.y..block.y + block.height {
+                for x in block.x..block.x + block.width {
+                    let p = pixels.get_pixel(x, y);
+                    for c in 0..4 {
+                        sum[c] += p[c] as u64;
+                    }
+                }
+            }
+
+            let count = block.width as u64 * block.height as u64;
+            let average = sum.map(|s| ((s + count / 2) / count) as u8);
+            pixels.fill_rect(block, average);
+
+            bx = bx.saturating_add(size);
+        }
+        by = by.saturating_add(size);
+    }
+}
+
+// the sum of the values within radius of each index, from running sums so
+// the cost doesn't depend on the radius. windows stop at the ends
+fn window_sums(values: &[[u64; 4]], radius: usize) -> Vec<[u64; 4]> {
+    let mut running = vec![[0u64; 4]; values.len() + 1];
+    for (i, v) in values.iter().enumerate() {
+        for c in 0..4 {
+            running[i + 1][c] = running[i][c] + v[c];
+        }
+    }
+
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(radius);
+            let end = i.saturating_add(radius).min(values.len() - 1) + 1;
+            let mut sum = [0u64; 4];
+            for c in 0..4 {
+                sum[c] = running[end][c] - running[start][c];
+            }
+            sum
+        })
+        .collect()
+}
+
+// how many values window_sums added up at index i
+fn window_len(i: usize, len: usize, radius: usize) -> u64 {
+    (i.saturating_add(radius).min(len - 1) + 1 - i.saturating_sub(radius)) as u64
+}
+
+// box blur that only samples pixels inside the region, done as a pass over
+// the rows and then the columns
+fn box_blur(pixels: &mut PixelBuffer, region: Rect, radius: u32) {
+    let width = region.width as usize;
+    let height = region.height as usize;
+    let radius = radius as usize;
+
+    let mut rows = Vec::with_capacity(width * height);
+    for y in region.y..region.y + region.height {
+        let row: Vec<[u64; 4]> = (region.x..region.x + region.width)
+            .map(|x| pixels.get_pixel(x, y).map(|v| v as u64))
+            .collect();
+        rows.extend(window_sums(&row, radius));
+    }
+
+    for x in 0..width {
+        let column: Vec<[u64; 4]> = (0..height).map(|y| rows[y * width + x]).collect();
+        for (y, sum) in window_sums(&column, radius).into_iter().enumerate() {
+            let count = window_len(x, width, radius) * window_len(y, height, radius);
+            pixels.set_pixel(
+                region.x + x as u32,
+                region.y + y as u32,
+                sum.map(|s| ((s + count / 2) / count) as u8),
+            );
+        }
+    }
+}
+
+impl PngImage {
+    pub fn redact(&mut self, regions: &[Rect], style: RedactStyle) -> Result<(), PngError> {
+        match style {
+            RedactStyle::Pixelate(0) | RedactStyle::Blur(0) => {
+                return Err(PngError::InvalidOperation(message(
+                    "redact.block_size",
+                    &[],
+                )))
+            }
+            _ => (),
+        }
+
+        let mut pixels = self.decode()?;
+
+        for region in regions {
+            let region = match region.clip(pixels.width, pixels.height) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            match style {
+                RedactStyle::SolidBlack => pixels.fill_rect(region, [0, 0, 0, 255]),
+                RedactStyle::Pixelate(size) => pixelate(&mut pixels, region, size),
+                RedactStyle::Blur(radius) => {
+                    pixelate(&mut pixels, region, radius);
+                    box_blur(&mut pixels, region, radius);
+                }
+            }
+        }
+
+        // every IDAT is rewritten from the redacted buffer so none of the old
+        // compressed stream makes it into the output
+        self.set_pixels(&pixels, &EncodeOptions::default())?;
+
+        // text, exif, private chunks and anything else ancillary could hold a
+        // caption or thumbnail of what was redacted
+        self.chunks
+            .retain(|c| c.is_critical() || REDACT_KEEP_CHUNKS.contains(&c.chunk_type.as_str()));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{EncodeOptions, FilterType, PNGChunk};
+    use std::sync::Arc;
+
+    const SECRET: &[u8] = b"TOP-SECRET-PIXELS!";
+
+    // an image whose middle rows spell out SECRET in the raw pixel bytes
+    fn secret_image() -> PngImage {
+        let mut pixels = PixelBuffer::new(32, 32).unwrap();
+        for y in 0..32 {
+            for x in 0..32 {
+                let i = (x * 4 + y) as usize;
+                let s = |o: usize| SECRET[(i + o) % SECRET.len()];
+                pixels.set_pixel(x, y, [s(0), s(1), s(2), s(3)]);
+            }
+        }
+
+        let options = EncodeOptions {
+            filter: Arc::new(FilterType::None),
+            compression_level: 0,
+            idat_size: 8192,
+            ..Default::default()
+        };
+        let mut image = PngImage::from_pixels(&pixels, &options).unwrap();
+        image.chunks.insert(
+            1,
+            PNGChunk::new("tEXt", b"Comment\0TOP-SECRET caption".to_vec()),
+        );
+        image
+            .chunks
+            .insert(1, PNGChunk::new("eXIf", b"MM\0*TOP-SECRET".to_vec()));
+        image
+            .chunks
+            .insert(1, PNGChunk::new("prVt", b"TOP-SECRET thumbnail".to_vec()));
+        image
+            .chunks
+            .insert(1, PNGChunk::new("gAMA", 45455u32.to_be_bytes().to_vec()));
+        image
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn test_redact_solid_black() {
+        let mut image = secret_image();
+        assert!(contains(&image.to_bytes(), SECRET));
+        let original = image.decode().unwrap();
+
+        image
+            .redact(&[Rect::new(0, 0, 32, 16)], RedactStyle::SolidBlack)
+            .unwrap();
+
+        let saved = PngImage::from_bytes(image.to_bytes()).unwrap();
+        let pixels = saved.decode().unwrap();
+        for y in 0..32 {
+            for x in 0..32 {
+                if y < 16 {
+                    assert_eq!(pixels.get_pixel(x, y), [0, 0, 0, 255]);
+                } else {
+                    assert_eq!(pixels.get_pixel(x, y), original.get_pixel(x, y));
+                }
+            }
+        }
+        // no run of the secret is left in the redacted rows' bytes
+        assert!(!contains(&pixels.data[..32 * 16 * 4], &SECRET[..4]));
+    }
+
+    #[test]
+    fn test_redact_leaves_nothing_recoverable() {
+        for style in [
+            RedactStyle::SolidBlack,
+            RedactStyle::Pixelate(8),
+            RedactStyle::Blur(4),
+        ] {
+            let mut image = secret_image();
+            image.redact(&[Rect::new(0, 0, 64, 64)], style).unwrap();
+
+            // neither the file nor its decompressed scanlines hold any of the secret
+            let bytes = image.to_bytes();
+            assert!(!contains(&bytes, b"TOP-SECRET"));
+            let saved = PngImage::from_bytes(bytes).unwrap();
+            let pixels = saved.decode().unwrap();
+            assert!(!contains(&pixels.data, &SECRET[..4]));
+
+            let types: Vec<&str> = saved.chunks.iter().map(|c| c.chunk_type.as_str()).collect();
+            assert_eq!(types, ["IHDR", "gAMA", "IDAT", "IEND"]);
+        }
+    }
+
+    #[test]
+    fn test_redact_pixelate_blocks() {
+        let mut image = secret_image();
+        image
+            .redact(&[Rect::new(0, 0, 32, 32)], RedactStyle::Pixelate(8))
+            .unwrap();
+        let pixels = image.decode().unwrap();
+
+        // only one color per block survives
+        for y in 0..32 {
+            for x in 0..32 {
+                assert_eq!(
+                    pixels.get_pixel(x, y),
+                    pixels.get_pixel(x / 8 * 8, y / 8 * 8)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_redact_huge_block_size() {
+        for style in [RedactStyle::Pixelate(u32::MAX), RedactStyle::Blur(u32::MAX)] {
+            let mut image = secret_image();
+            image.redact(&[Rect::new(8, 8, 16, 16)], style).unwrap();
+            let pixels = image.decode().unwrap();
+
+            // a block bigger than the region averages all of it
+            assert_eq!(pixels.get_pixel(8, 8), pixels.get_pixel(23, 23));
+        }
+    }
+
+    #[test]
+    fn test_redact_zero_block_size() {
+        let mut image = secret_image();
+        assert!(image
+            .redact(&[Rect::new(0, 0, 8, 8)], RedactStyle::Pixelate(0))
+            .is_err());
+    }
+}
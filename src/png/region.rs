@@ -0,0 +1,267 @@
+// decoding part of an image, optionally with an index into the zlib stream
+//
+// without an index a region still has to inflate every row above it. the
+// index remembers, every so often, where a deflate block starts together with
+// the 32K window and the unfiltered row the next scanline needs, so
+// decode_region can start inflating at the last block before the region.
+// building it costs one full inflate pass.
+
+use std::{io::Read, mem::size_of};
+
+use super::{
+    decode::{read_full, ColorConverter, Layout, ScanlineReader},
+    filter::unfilter_row,
+    inflate::Inflater,
+    locale::message,
+    memory::{Footprint, MemoryFootprint},
+    PixelBuffer, PngError, PngImage, Rect,
+};
+
+// uncompressed bytes between seek points, each one holds about a window of memory
+pub const DEFAULT_INDEX_SPACING: usize = 1 << 20;
+
+#[derive(Debug, Clone)]
+struct SeekPoint {
+    // first scanline that starts after the block boundary
+    row: u32,
+    // the block header in bits from the start of the zlib stream
+    bit: u64,
+    // uncompressed bytes before the block
+    out: u64,
+    // output window before the block
+    history: Vec<u8>,
+    // row - 1 unfiltered
+    prev: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScanlineIndex {
+    // to notice an index being used with other image data
+    idat_crcs: Vec<u32>,
+    points: Vec<SeekPoint>,
+}
+
+impl ScanlineIndex {
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    // the scanlines decoding can start at without inflating what comes before
+    pub fn rows(&self) -> impl Iterator<Item = u32> + '_ {
+        self.points.iter().map(|p| p.row)
+    }
+
+    fn matches(&self, image: &PngImage) -> bool {
+        self.idat_crcs
+            .iter()
+            .copied()
+            .eq(image.get_chunks("IDAT").map(|c| c.crc))
+    }
+}
+
+impl MemoryFootprint for ScanlineIndex {
+    fn memory_footprint(&self) -> Footprint {
+        let points: usize = self
+            .points
+            .iter()
+            .map(|p| size_of::<SeekPoint>() + p.history.capacity() + p.prev.capacity())
+            .sum();
+
+        Footprint {
+            caches: size_of::<Self>() + self.idat_crcs.capacity() * size_of::<u32>() + points,
+            ..Default::default()
+        }
+    }
+}
+
+fn stream_error(e: std::io::Error) -> PngError {
+    PngError::InvalidImageData(e.to_string())
+}
+
+impl PngImage {
+    // one pass over the image data recording a seek point at the first block
+    // boundary after every spacing bytes of output
+    pub fn build_scanline_index(&self, spacing: usize) -> Result<ScanlineIndex, PngError> {
+        let layout = Layout::new(&self.info)?;
+        if layout.interlaced {
+            return Err(PngError::InvalidOperation(message(
+                "region.interlaced",
+                &[],
+            )));
+        }
+
+        let row_bytes = layout.row_bytes(layout.width);
+        let mut inflater = Inflater::new(self.idat_reader());
+        inflater.pause_at_blocks(true);
+
+        let mut points = vec![];
+        let mut filtered = vec![0; row_bytes + 1];
+        let mut prev = vec![0; row_bytes];
+        let mut last = 0;
+
+        for row in 0..layout.height {
+            // a boundary inside this row makes row + 1 the first whole one after it
+            let mut pending = vec![];
+            let mut filled = 0;
+
+            while filled < filtered.len() {
+                let n = inflater
+                    .read(&mut filtered[filled..])
+                    .map_err(stream_error)?;
+                if n == 0 {
+                    return Err(PngError::InvalidImageData(message(
+                        "decode.ended_early",
+                        &[],
+                    )));
+                }
+                filled += n;
+
+                let out = inflater.total_out();
+                if inflater.at_block_boundary() && out - last >= spacing as u64 {
+                    last = out;
+                    pending.push(SeekPoint {
+                        row: row + 1,
+                        bit: inflater.bit_position(),
+                        out,
+                        history: inflater.history(),
+                        prev: vec![],
+                    });
+                }
+            }
+
+            let (filter, current) = filtered.split_first_mut().unwrap();
+            unfilter_row(*filter, layout.filter_bpp(), current, &prev)?;
+            prev.copy_from_slice(current);
+
+            for mut point in pending {
+                if point.row < layout.height {
+                    point.prev = prev.clone();
+                    points.push(point);
+                }
+            }
+        }
+
+        Ok(ScanlineIndex {
+            idat_crcs: self.get_chunks("IDAT").map(|c| c.crc).collect(),
+            points,
+        })
+    }
+
+    // decodes only the rows the rect covers, starting from the closest seek
+    // point above it when an index is given
+    pub fn decode_region(
+        &self,
+        rect: Rect,
+        index: Option<&ScanlineIndex>,
+    ) -> Result<PixelBuffer, PngError> {
+        let layout = Layout::new(&self.info)?;
+        let rect = rect.clip(layout.width, layout.height).ok_or_else(|| {
+            PngError::InvalidOperation(message(
+                "region.outside",
+                &[&rect.width, &rect.height, &rect.x, &rect.y],
+            ))
+        })?;
+
+        // every adam7 pass covers the whole image, there is nothing to skip
+        if layout.interlaced {
+            return Ok(self.decode()?.crop(rect));
+        }
+
+        let converter =
+            ColorConverter::new(layout, self.get_chunk("PLTE"), self.get_chunk("tRNS"))?;
+
+        let point = match index {
+            Some(index) if !index.matches(self) => {
+                return Err(PngError::InvalidOperation(message(
+                    "region.wrong_index",
+                    &[],
+                )))
+            }
+            Some(index) => index.points.iter().rev().find(|p| p.row <= rect.y),
+            None => None,
+        };
+
+        let mut reader = match point {
+            Some(point) => {
+                let mut compressed = self.idat_reader();
+                compressed.skip_to(point.bit / 8);
+                let mut inflater =
+                    Inflater::resume(compressed, (point.bit % 8) as u8, point.out, &point.history)
+                        .map_err(stream_error)?;
+
+                // the rest of the row the block started in
+                let stride = layout.row_bytes(layout.width) as u64 + 1;
+                let mut partial = vec![0; (point.row as u64 * stride - point.out) as usize];
+                read_full(&mut inflater, &mut partial)?;
+
+                ScanlineReader::resume(layout, inflater, point.row, point.prev.clone())
+            }
+            None => ScanlineReader::new(layout, self.idat_reader()),
+        };
+
+        let mut pixels = PixelBuffer::new(rect.width, rect.height)?;
+        let (left, right) = (rect.x as usize * 4, (rect.x + rect.width) as usize * 4);
+        let mut rgba = vec![];
+
+        while let Some(line) = reader.next_row()? {
+            if line.y < rect.y {
+                continue;
+            }
+
+            converter.expand(line.data, line.pass.width, &mut rgba)?;
+            let start = (line.y - rect.y) as usize * (right - left);
+            pixels.data[start..start + right - left].copy_from_slice(&rgba[left..right]);
+
+            if line.y + 1 == rect.y + rect.height {
+                break;
+            }
+        }
+
+        Ok(pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    #[test]
+    fn test_decode_region_with_index() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let index = image.build_scanline_index(64 * 1024).unwrap();
+        assert!(index.len() > 1);
+
+        let full = image.decode().unwrap();
+        let last = index.rows().last().unwrap();
+        for rect in [
+            Rect::new(0, 0, 800, 1),
+            Rect::new(100, last, 50, 20),
+            Rect::new(700, 590, 200, 200),
+        ] {
+            let expected = full.crop(rect);
+            assert_eq!(image.decode_region(rect, None).unwrap(), expected);
+            assert_eq!(image.decode_region(rect, Some(&index)).unwrap(), expected);
+        }
+
+        assert!(image
+            .decode_region(Rect::new(800, 0, 10, 10), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_index_belongs_to_image() {
+        let image = PngImage::new(IMAGE_PATH).unwrap();
+        let index = image.build_scanline_index(DEFAULT_INDEX_SPACING).unwrap();
+
+        let mut other = image.clone();
+        other.chunks.retain(|c| c.chunk_type != "IDAT");
+        assert!(other
+            .decode_region(Rect::new(0, 0, 1, 1), Some(&index))
+            .is_err());
+    }
+}
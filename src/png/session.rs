@@ -0,0 +1,305 @@
+// chunk level editing over an immutable base image
+//
+// edits are recorded as a log and only replayed into a new image on commit,
+// which keeps undo and redo down to moving entries between two stacks. the
+// current chunk order is kept next to the log as positions into the base and
+// the log, applying each edit as it comes. each log entry remembers the slot
+// it removed or replaced, so undo puts that back instead of replaying.
+
+use std::{mem::size_of, sync::Arc};
+
+use super::{
+    locale::message,
+    memory::{Footprint, MemoryFootprint},
+    PNGChunk, PngError, PngImage,
+};
+
+#[derive(Debug, Clone)]
+pub enum ChunkEdit {
+    Insert { index: usize, chunk: PNGChunk },
+    Remove { index: usize },
+    Replace { index: usize, chunk: PNGChunk },
+}
+
+// where a chunk of the edited image lives
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Base(usize),
+    Edit(usize),
+}
+
+pub struct EditSession {
+    base: Arc<PngImage>,
+    log: Vec<ChunkEdit>,
+    // the slot each logged edit removed or replaced, parallel to log
+    displaced: Vec<Option<Slot>>,
+    undone: Vec<ChunkEdit>,
+    current: Vec<Slot>,
+}
+
+// log_index is the edit's own position in the log, returns the slot the edit
+// took out of current
+fn apply(current: &mut Vec<Slot>, edit: &ChunkEdit, log_index: usize) -> Option<Slot> {
+    match edit {
+        ChunkEdit::Insert { index, .. } => {
+            current.insert(*index, Slot::Edit(log_index));
+            None
+        }
+        ChunkEdit::Remove { index } => Some(current.remove(*index)),
+        ChunkEdit::Replace { index, .. } => Some(std::mem::replace(
+            &mut current[*index],
+            Slot::Edit(log_index),
+        )),
+    }
+}
+
+// the inverse of apply, given what apply returned
+fn revert(current: &mut Vec<Slot>, edit: &ChunkEdit, displaced: Option<Slot>) {
+    match (edit, displaced) {
+        (ChunkEdit::Insert { index, .. }, None) => {
+            current.remove(*index);
+        }
+        (ChunkEdit::Remove { index }, Some(slot)) => current.insert(*index, slot),
+        (ChunkEdit::Replace { index, .. }, Some(slot)) => current[*index] = slot,
+        _ => unreachable!("apply displaces a slot exactly for removals and replacements"),
+    }
+}
+
+impl EditSession {
+    pub fn new(base: Arc<PngImage>) -> Self {
+        let current = (0..base.chunks.len()).map(Slot::Base).collect();
+
+        EditSession {
+            base,
+            log: vec![],
+            displaced: vec![],
+            undone: vec![],
+            current,
+        }
+    }
+
+    pub fn base(&self) -> &PngImage {
+        &self.base
+    }
+
+    pub fn edits(&self) -> &[ChunkEdit] {
+        &self.log
+    }
+
+    fn chunk(&self, slot: Slot) -> &PNGChunk {
+        match slot {
+            Slot::Base(i) => &self.base.chunks[i],
+            Slot::Edit(i) => match &self.log[i] {
+                ChunkEdit::Insert { chunk, .. } | ChunkEdit::Replace { chunk, .. } => chunk,
+                ChunkEdit::Remove { .. } => unreachable!("a removal leaves no chunk behind"),
+            },
+        }
+    }
+
+    // the chunks as they stand after every edit in the log, without copying payloads
+    pub fn chunks(&self) -> Vec<&PNGChunk> {
+        self.current.iter().map(|slot| self.chunk(*slot)).collect()
+    }
+
+    fn push(&mut self, edit: ChunkEdit) {
+        let displaced = apply(&mut self.current, &edit, self.log.len());
+        self.log.push(edit);
+        self.displaced.push(displaced);
+    }
+
+    fn record(&mut self, edit: ChunkEdit) -> Result<(), PngError> {
+        let len = self.current.len();
+
+        let in_range = match &edit {
+            ChunkEdit::Insert { index, .. } => *index <= len,
+            ChunkEdit::Remove { index } | ChunkEdit::Replace { index, .. } => *index < len,
+        };
+
+        if !in_range {
+            return Err(PngError::InvalidOperation(message(
+                "session.out_of_range",
+                &[&len],
+            )));
+        }
+
+        self.push(edit);
+        self.undone.clear();
+        Ok(())
+    }
+
+    pub fn insert_chunk(&mut self, index: usize, chunk: PNGChunk) -> Result<(), PngError> {
+        self.record(ChunkEdit::Insert { index, chunk })
+    }
+
+    // adds the chunk right before IEND
+    pub fn add_chunk(&mut self, chunk: PNGChunk) -> Result<(), PngError> {
+        let index = match self
+            .current
+            .iter()
+            .rposition(|slot| self.chunk(*slot).chunk_type == "IEND")
+        {
+            Some(i) => i,
+            None => self.current.len(),
+        };
+
+        self.insert_chunk(index, chunk)
+    }
+
+    pub fn remove_chunk(&mut self, index: usize) -> Result<(), PngError> {
+        self.record(ChunkEdit::Remove { index })
+    }
+
+    pub fn replace_chunk(&mut self, index: usize, chunk: PNGChunk) -> Result<(), PngError> {
+        self.record(ChunkEdit::Replace { index, chunk })
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.log.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match (self.log.pop(), self.displaced.pop()) {
+            (Some(edit), Some(displaced)) => {
+                revert(&mut self.current, &edit, displaced);
+                self.undone.push(edit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.undone.pop() {
+            Some(edit) => {
+                self.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // builds the edited image, the base and the log are left untouched
+    pub fn commit(&self) -> Result<PngImage, PngError> {
+        let chunks: Vec<PNGChunk> = self.chunks().into_iter().cloned().collect();
+
+        let header = match chunks.first() {
+            Some(c) => c,
+            None => {
+                return Err(PngError::InvalidOperation(message(
+                    "session.no_chunks",
+                    &[],
+                )))
+            }
+        };
+        let info = PngImage::get_png_info(header)?;
+
+        if chunks.last().map(|c| c.chunk_type.as_str()) != Some("IEND") {
+            return Err(PngError::InvalidOperation(message("session.no_iend", &[])));
+        }
+
+        Ok(PngImage { info, chunks })
+    }
+}
+
+impl MemoryFootprint for EditSession {
+    // the base image as it is, chunks held by the edit log as caches
+    fn memory_footprint(&self) -> Footprint {
+        let mut footprint = self.base.memory_footprint();
+        footprint.caches += size_of::<Self>()
+            + (self.log.capacity() + self.undone.capacity()) * size_of::<ChunkEdit>()
+            + self.displaced.capacity() * size_of::<Option<Slot>>()
+            + self.current.capacity() * size_of::<Slot>();
+
+        for edit in self.log.iter().chain(&self.undone) {
+            if let ChunkEdit::Insert { chunk, .. } | ChunkEdit::Replace { chunk, .. } = edit {
+                footprint += chunk.memory_footprint().as_cache();
+            }
+        }
+        footprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const IMAGE_PATH: &str = "./test.png";
+
+    fn session() -> EditSession {
+        EditSession::new(Arc::new(PngImage::new(IMAGE_PATH).unwrap()))
+    }
+
+    fn types(chunks: &[&PNGChunk]) -> Vec<String> {
+        chunks.iter().map(|c| c.chunk_type.clone()).collect()
+    }
+
+    #[test]
+    fn test_session_edits() {
+        let mut session = session();
+        session
+            .add_chunk(PNGChunk::new("tEXt", b"Title\0one".to_vec()))
+            .unwrap();
+        session
+            .insert_chunk(1, PNGChunk::new("gAMA", 45455u32.to_be_bytes().to_vec()))
+            .unwrap();
+        session
+            .replace_chunk(3, PNGChunk::new("tEXt", b"Title\0two".to_vec()))
+            .unwrap();
+        assert_eq!(
+            types(&session.chunks()),
+            ["IHDR", "gAMA", "IDAT", "tEXt", "IEND"]
+        );
+
+        session.remove_chunk(1).unwrap();
+        let image = session.commit().unwrap();
+        assert_eq!(image.get_chunk("tEXt").unwrap().data, b"Title\0two");
+        assert_eq!(session.base().chunks.len(), 3);
+    }
+
+    #[test]
+    fn test_session_undo_redo() {
+        let mut session = session();
+        session
+            .add_chunk(PNGChunk::new("tEXt", b"Title\0one".to_vec()))
+            .unwrap();
+        session.remove_chunk(0).unwrap();
+
+        assert!(session.undo());
+        assert_eq!(types(&session.chunks()), ["IHDR", "IDAT", "tEXt", "IEND"]);
+        assert!(session.undo());
+        assert!(!session.undo());
+        assert_eq!(types(&session.chunks()), ["IHDR", "IDAT", "IEND"]);
+
+        assert!(session.redo());
+        assert_eq!(session.chunks().len(), 4);
+
+        // a new edit drops anything left to redo
+        session.remove_chunk(2).unwrap();
+        assert!(!session.can_redo());
+        // undo puts back whatever a replacement covered, base or edited
+        session
+            .replace_chunk(2, PNGChunk::new("tEXt", b"Title\0two".to_vec()))
+            .unwrap();
+        session
+            .replace_chunk(2, PNGChunk::new("tEXt", b"Title\0three".to_vec()))
+            .unwrap();
+        assert!(session.undo());
+        assert_eq!(session.chunks()[2].data, b"Title\0two");
+        assert!(session.undo());
+        assert_eq!(types(&session.chunks()), ["IHDR", "IDAT", "IEND"]);
+        assert!(session.undo());
+        assert_eq!(types(&session.chunks()), ["IHDR", "IDAT", "tEXt", "IEND"]);
+    }
+
+    #[test]
+    fn test_session_invalid_edits() {
+        let mut session = session();
+        assert!(session.remove_chunk(3).is_err());
+
+        session.remove_chunk(0).unwrap();
+        assert!(session.commit().is_err());
+    }
+}
@@ -0,0 +1,71 @@
+// numbers about the compressed image data, gathered while decoding or encoding it
+
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
+
+use super::locale::message;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCounts {
+    pub stored: u32,
+    pub fixed: u32,
+    pub dynamic: u32,
+}
+
+impl BlockCounts {
+    // btype as written in the block header
+    pub(crate) fn add(&mut self, btype: u32) {
+        match btype {
+            0 => self.stored += 1,
+            1 => self.fixed += 1,
+            _ => self.dynamic += 1,
+        }
+    }
+
+    pub fn total(&self) -> u32 {
+        self.stored + self.fixed + self.dynamic
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompressionStats {
+    // the zlib stream across every IDAT chunk
+    pub compressed_bytes: u64,
+    // the filtered scanlines including their filter type bytes
+    pub uncompressed_bytes: u64,
+    pub blocks: BlockCounts,
+    // time spent running the image data through (de)compression and filtering
+    pub duration: Duration,
+}
+
+impl CompressionStats {
+    // compressed size over uncompressed size, lower is better
+    pub fn ratio(&self) -> f64 {
+        if self.uncompressed_bytes == 0 {
+            return 0.0;
+        }
+
+        self.compressed_bytes as f64 / self.uncompressed_bytes as f64
+    }
+}
+
+impl Display for CompressionStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let report = message(
+            "report.compression",
+            &[
+                &self.uncompressed_bytes,
+                &self.compressed_bytes,
+                &format!("{:.1}", self.ratio() * 100.0),
+                &self.blocks.total(),
+                &self.blocks.stored,
+                &self.blocks.fixed,
+                &self.blocks.dynamic,
+                &format!("{:.2?}", self.duration),
+            ],
+        );
+        f.write_str(&report)
+    }
+}
@@ -0,0 +1,191 @@
+// golden file assertions for downstream test suites
+//
+// on a mismatch the actual image and a diff image are written next to the
+// golden file as <name>.actual.png and <name>.diff.png. set UPDATE_GOLDEN=1
+// to write the actual image as the new golden file instead of comparing.
+
+use std::path::Path;
+
+use super::{
+    compare::{diff_image, CompareOptions},
+    locale::message,
+    EncodeOptions, PixelBuffer, PngError, PngImage,
+};
+
+pub const UPDATE_GOLDEN_VAR: &str = "UPDATE_GOLDEN";
+
+// anything assert_png_eq! can take as the actual image
+pub trait GoldenSource {
+    fn golden_pixels(&self) -> Result<PixelBuffer, PngError>;
+}
+
+impl GoldenSource for PixelBuffer {
+    fn golden_pixels(&self) -> Result<PixelBuffer, PngError> {
+        Ok(self.clone())
+    }
+}
+
+impl GoldenSource for PngImage {
+    fn golden_pixels(&self) -> Result<PixelBuffer, PngError> {
+        self.decode()
+    }
+}
+
+impl<T: GoldenSource> GoldenSource for &T {
+    fn golden_pixels(&self) -> Result<PixelBuffer, PngError> {
+        (*self).golden_pixels()
+    }
+}
+
+fn sibling(golden: &Path, suffix: &str) -> String {
+    golden
+        .with_extension(format!("{}.png", suffix))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn save(pixels: &PixelBuffer, path: &str) -> Result<(), PngError> {
+    PngImage::from_pixels(pixels, &EncodeOptions::default())?.save_image(path)
+}
+
+// the comparison behind assert_png_eq!, returns what went wrong instead of panicking
+pub fn check_golden(
+    actual: &impl GoldenSource,
+    golden: &str,
+    options: &CompareOptions,
+) -> Result<(), String> {
+    let actual = actual
+        .golden_pixels()
+        .map_err(|e| message("golden.actual_unreadable", &[&e.get_message()]))?;
+
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some_and(|v| v != "0") {
+        return save(&actual, golden)
+            .map_err(|e| message("golden.unwritable", &[&golden, &e.get_message()]));
+    }
+
+    let path = Path::new(golden);
+    if !path.exists() {
+        return Err(message("golden.missing", &[&golden, &UPDATE_GOLDEN_VAR]));
+    }
+
+    let expected = PngImage::new(golden)
+        .and_then(|image| image.decode())
+        .map_err(|e| message("golden.unreadable", &[&golden, &e.get_message()]))?;
+
+    let mismatch = if expected.width != actual.width || expected.height != actual.height {
+        message(
+            "golden.size_differs",
+            &[
+                &expected.width,
+                &expected.height,
+                &actual.width,
+                &actual.height,
+            ],
+        )
+    } else {
+        let (result, diff) =
+            diff_image(&expected, &actual, options).map_err(|e| e.get_message())?;
+        if result.is_match() {
+            return Ok(());
+        }
+
+        let diff_path = sibling(path, "diff");
+        save(&diff, &diff_path)
+            .map_err(|e| message("golden.unwritable", &[&diff_path, &e.get_message()]))?;
+        message(
+            "golden.pixels_differ",
+            &[&result.diff_pixels, &result.total_pixels, &diff_path],
+        )
+    };
+
+    let actual_path = sibling(path, "actual");
+    save(&actual, &actual_path)
+        .map_err(|e| message("golden.unwritable", &[&actual_path, &e.get_message()]))?;
+
+    Err(message(
+        "golden.mismatch",
+        &[&golden, &mismatch, &actual_path],
+    ))
+}
+
+#[track_caller]
+pub fn assert_golden(actual: &impl GoldenSource, golden: &str, options: &CompareOptions) {
+    if let Err(e) = check_golden(actual, golden, options) {
+        panic!("{}", message("golden.failed", &[&e]));
+    }
+}
+
+// compares an image against a golden png, with CompareOptions as an optional
+// third argument for tolerances and ignore areas
+#[macro_export]
+macro_rules! assert_png_eq {
+    ($actual:expr, $golden:expr $(,)?) => {
+        $crate::png::testing::assert_golden(
+            &$actual,
+            $golden,
+            &$crate::png::compare::CompareOptions::default(),
+        )
+    };
+    ($actual:expr, $golden:expr, $options:expr $(,)?) => {
+        $crate::png::testing::assert_golden(&$actual, $golden, &$options)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::Rect;
+    use std::fs;
+
+    fn card() -> PixelBuffer {
+        let mut pixels = PixelBuffer::new(16, 16).unwrap();
+        pixels.fill_rect(pixels.bounds(), [255, 255, 255, 255]);
+        pixels.fill_rect(Rect::new(4, 4, 8, 8), [0, 90, 200, 255]);
+        pixels
+    }
+
+    fn golden(name: &str) -> String {
+        fs::create_dir_all("./save_test/golden").unwrap();
+        let path = format!("./save_test/golden/{}.png", name);
+        save(&card(), &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_assert_png_eq() {
+        let path = golden("card");
+        let image = PngImage::from_pixels(&card(), &EncodeOptions::default()).unwrap();
+        assert_png_eq!(image, &path);
+        assert_png_eq!(card(), &path);
+
+        let mut shifted = card();
+        shifted.set_pixel(4, 4, [255, 255, 255, 255]);
+        let ignore_corner = CompareOptions {
+            ignore: vec![Rect::new(4, 4, 1, 1)],
+            ..Default::default()
+        };
+        assert_png_eq!(shifted, &path, ignore_corner);
+    }
+
+    #[test]
+    fn test_golden_mismatch_writes_artifacts() {
+        let path = golden("card-mismatch");
+        let mut changed = card();
+        changed.fill_rect(Rect::new(0, 0, 2, 2), [255, 0, 0, 255]);
+
+        let error = check_golden(&changed, &path, &CompareOptions::default()).unwrap_err();
+        assert!(error.contains("4 of 256 pixels differ"));
+
+        let diff = PngImage::new("./save_test/golden/card-mismatch.diff.png").unwrap();
+        assert_eq!(diff.decode().unwrap().get_pixel(1, 1), [255, 0, 0, 255]);
+        let actual = PngImage::new("./save_test/golden/card-mismatch.actual.png").unwrap();
+        assert_eq!(actual.decode().unwrap(), changed);
+
+        assert!(check_golden(
+            &changed,
+            "./save_test/golden/missing.png",
+            &CompareOptions::default()
+        )
+        .is_err());
+    }
+}
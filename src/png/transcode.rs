@@ -0,0 +1,510 @@
+// one pass re-encoder that streams chunks from a reader to a writer
+//
+// nothing is buffered beyond a chunk header, a couple of scanlines and the
+// deflate window, so it can sit in a proxy and sanitize images on the fly.
+
+use std::io::{self, Read, Write};
+
+use super::{
+    checksum::Crc32,
+    decode::{Layout, ScanlineReader},
+    encode::RowEncoder,
+    is_critical_type,
+    locale::message,
+    EncodeOptions, PNGChunk, PngError, PngImage, PNG_SIGNATURE,
+};
+
+const COPY_BUFFER_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetadataPolicy {
+    Keep,
+    // drop every ancillary chunk
+    Strip,
+    // drop every ancillary chunk not in the list
+    KeepOnly(Vec<String>),
+}
+
+impl MetadataPolicy {
+    // critical chunks are always kept
+    pub fn keeps(&self, chunk_type: &str) -> bool {
+        if is_critical_type(chunk_type) {
+            return true;
+        }
+
+        match self {
+            MetadataPolicy::Keep => true,
+            MetadataPolicy::Strip => false,
+            MetadataPolicy::KeepOnly(types) => types.iter().any(|t| t == chunk_type),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TranscodeOptions {
+    // decompress and recompress the image data, otherwise IDAT is copied as is
+    pub recompress: bool,
+    // pick new filters with encode.filter, otherwise each row keeps its filter type
+    pub refilter: bool,
+    // encode.metadata applies whether or not the data is recompressed
+    pub encode: EncodeOptions,
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> Self {
+        TranscodeOptions {
+            recompress: true,
+            refilter: true,
+            encode: EncodeOptions::default(),
+        }
+    }
+}
+
+fn stream_error(e: io::Error) -> PngError {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        return PngError::StreamFailed(message("transcode.ended_early", &[]));
+    }
+
+    PngError::StreamFailed(e.to_string())
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk_type: &str, data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc32::new();
+    crc.update(chunk_type.as_bytes());
+    crc.update(data);
+
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(chunk_type.as_bytes())?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.finish().to_be_bytes())
+}
+
+struct ChunkHeader {
+    size: u32,
+    chunk_type: String,
+}
+
+struct ChunkStream<R: Read> {
+    inner: R,
+    pending: Option<ChunkHeader>,
+}
+
+impl<R: Read> ChunkStream<R> {
+    fn read_header(&mut self) -> Result<ChunkHeader, PngError> {
+        if let Some(header) = self.pending.take() {
+            return Ok(header);
+        }
+
+        let mut bytes = [0u8; 8];
+        self.inner.read_exact(&mut bytes).map_err(stream_error)?;
+
+        let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if !bytes[4..].iter().all(|b| b.is_ascii_alphabetic()) {
+            return Err(PngError::InvalidChunkType(message(
+                "transcode.bad_type",
+                &[],
+            )));
+        }
+
+        Ok(ChunkHeader {
+            size,
+            chunk_type: String::from_utf8_lossy(&bytes[4..]).to_string(),
+        })
+    }
+
+    fn check_crc(&mut self, crc: &Crc32, chunk_type: &str) -> io::Result<()> {
+        let mut bytes = [0u8; 4];
+        self.inner.read_exact(&mut bytes)?;
+
+        if u32::from_be_bytes(bytes) != crc.finish() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                message("transcode.crc_mismatch", &[&chunk_type]),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn read_data(&mut self, header: &ChunkHeader) -> Result<Vec<u8>, PngError> {
+        let mut data = vec![];
+        let mut crc = Crc32::new();
+        crc.update(header.chunk_type.as_bytes());
+        self.copy_data(header, &mut crc, &mut data)?;
+        self.check_crc(&crc, &header.chunk_type)
+            .map_err(|_| PngError::InvalidChunkCrc(header.chunk_type.clone()))?;
+        Ok(data)
+    }
+
+    // streams the payload and crc through, sink is None for dropped chunks
+    fn copy_chunk<W: Write>(
+        &mut self,
+        header: &ChunkHeader,
+        sink: Option<&mut W>,
+    ) -> Result<(), PngError> {
+        let mut crc = Crc32::new();
+        crc.update(header.chunk_type.as_bytes());
+
+        match sink {
+            Some(writer) => {
+                writer
+                    .write_all(&header.size.to_be_bytes())
+                    .map_err(stream_error)?;
+                writer
+                    .write_all(header.chunk_type.as_bytes())
+                    .map_err(stream_error)?;
+                self.copy_data(header, &mut crc, writer)?;
+                writer
+                    .write_all(&crc.finish().to_be_bytes())
+                    .map_err(stream_error)?;
+            }
+            None => self.copy_data(header, &mut crc, &mut io::sink())?,
+        }
+
+        self.check_crc(&crc, &header.chunk_type)
+            .map_err(|_| PngError::InvalidChunkCrc(header.chunk_type.clone()))
+    }
+
+    fn copy_data<W: Write>(
+        &mut self,
+        header: &ChunkHeader,
+        crc: &mut Crc32,
+        writer: &mut W,
+    ) -> Result<(), PngError> {
+        let mut remaining = header.size as usize;
+        let mut buf = [0u8; COPY_BUFFER_SIZE];
+
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            self.inner.read_exact(&mut buf[..n]).map_err(stream_error)?;
+            crc.update(&buf[..n]);
+            writer.write_all(&buf[..n]).map_err(stream_error)?;
+            remaining -= n;
+        }
+
+        Ok(())
+    }
+}
+
+// reads the payloads of consecutive IDAT chunks, stopping at the first other chunk
+struct IdatRun<'a, R: Read> {
+    stream: &'a mut ChunkStream<R>,
+    remaining: u32,
+    crc: Crc32,
+    done: bool,
+}
+
+impl<'a, R: Read> IdatRun<'a, R> {
+    fn new(stream: &'a mut ChunkStream<R>, size: u32) -> Self {
+        let mut crc = Crc32::new();
+        crc.update(b"IDAT");
+
+        IdatRun {
+            stream,
+            remaining: size,
+            crc,
+            done: false,
+        }
+    }
+}
+
+impl<R: Read> Read for IdatRun<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+
+            if self.remaining > 0 {
+                let want = buf.len().min(self.remaining as usize);
+                let n = self.stream.inner.read(&mut buf[..want])?;
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+
+                self.crc.update(&buf[..n]);
+                self.remaining -= n as u32;
+                return Ok(n);
+            }
+
+            self.stream.check_crc(&self.crc, "IDAT")?;
+
+            let header = match self.stream.read_header() {
+                Ok(h) => h,
+                Err(e) => return Err(io::Error::other(e.get_message())),
+            };
+
+            if header.chunk_type == "IDAT" {
+                self.remaining = header.size;
+                self.crc = Crc32::new();
+                self.crc.update(b"IDAT");
+            } else {
+                self.stream.pending = Some(header);
+                self.done = true;
+            }
+        }
+    }
+}
+
+// buffers compressed bytes and writes them out as IDAT chunks of a fixed size
+struct IdatWriter<W: Write> {
+    inner: W,
+    size: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> IdatWriter<W> {
+    fn new(inner: W, size: usize) -> Self {
+        IdatWriter {
+            inner,
+            size: size.max(1),
+            buf: vec![],
+        }
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            write_chunk(&mut self.inner, "IDAT", &self.buf)?;
+        }
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for IdatWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+
+        while self.buf.len() >= self.size {
+            let rest = self.buf.split_off(self.size);
+            write_chunk(&mut self.inner, "IDAT", &self.buf)?;
+            self.buf = rest;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn recompress_idat<R: Read, W: Write>(
+    stream: &mut ChunkStream<R>,
+    first_size: u32,
+    writer: &mut W,
+    layout: Layout,
+    options: &TranscodeOptions,
+) -> Result<(), PngError> {
+    let mut rows = ScanlineReader::new(layout, IdatRun::new(stream, first_size));
+    let idat = IdatWriter::new(&mut *writer, options.encode.idat_size);
+    let mut encoder = RowEncoder::new(idat, layout.filter_bpp(), &options.encode);
+
+    while let Some(line) = rows.next_row()? {
+        if line.y == 0 {
+            encoder.start_pass();
+        }
+
+        let filter = if options.refilter {
+            None
+        } else {
+            Some(line.filter)
+        };
+        encoder
+            .write_row_as(line.data, filter)
+            .map_err(stream_error)?;
+    }
+
+    encoder
+        .finish()
+        .and_then(|idat| idat.finish())
+        .map_err(stream_error)?;
+
+    // skip whatever is left of the run so the stream lands on the next chunk
+    let mut run = rows.finish()?;
+    if let Err(e) = io::copy(&mut run, &mut io::sink()) {
+        return Err(PngError::InvalidImageData(e.to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn transcode<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    options: &TranscodeOptions,
+) -> Result<(), PngError> {
+    let mut stream = ChunkStream {
+        inner: reader,
+        pending: None,
+    };
+
+    let mut signature = [0u8; 8];
+    if stream.inner.read_exact(&mut signature).is_err() || signature != PNG_SIGNATURE {
+        return Err(PngError::InvalidFileType);
+    }
+    writer.write_all(&PNG_SIGNATURE).map_err(stream_error)?;
+
+    let mut layout = None;
+    let mut idat_written = false;
+
+    loop {
+        let header = stream.read_header()?;
+
+        match header.chunk_type.as_str() {
+            "IHDR" => {
+                let chunk = PNGChunk::new("IHDR", stream.read_data(&header)?);
+                layout = Some(Layout::new(&PngImage::get_png_info(&chunk)?)?);
+                write_chunk(&mut writer, "IHDR", &chunk.data).map_err(stream_error)?;
+            }
+            "IDAT" if options.recompress => {
+                let layout = match layout {
+                    Some(l) => l,
+                    None => {
+                        return Err(PngError::InvalidPngInfo(message(
+                            "png.header_not_ihdr",
+                            &[],
+                        )))
+                    }
+                };
+
+                if idat_written {
+                    return Err(PngError::InvalidImageData(message(
+                        "transcode.idat_split",
+                        &[],
+                    )));
+                }
+
+                recompress_idat(&mut stream, header.size, &mut writer, layout, options)?;
+                idat_written = true;
+            }
+            chunk_type => {
+                let keep = options.encode.metadata.keeps(chunk_type);
+                stream.copy_chunk(&header, if keep { Some(&mut writer) } else { None })?;
+
+                if chunk_type == "IEND" {
+                    break;
+                }
+            }
+        }
+    }
+
+    writer.flush().map_err(stream_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::{encode::split_idat, PNGInfo, PixelBuffer};
+    use std::{fs::File, io::BufReader};
+    const IMAGE_PATH: &str = "./test.png";
+
+    fn image_with_metadata() -> PngImage {
+        let mut image = PngImage::new(IMAGE_PATH).unwrap();
+        image
+            .chunks
+            .insert(1, PNGChunk::new("tEXt", b"Comment\0hello".to_vec()));
+        image
+            .chunks
+            .insert(1, PNGChunk::new("gAMA", 45455u32.to_be_bytes().to_vec()));
+        image
+    }
+
+    #[test]
+    fn test_transcode_round_trip() {
+        let reader = BufReader::new(File::open(IMAGE_PATH).unwrap());
+        let mut out = vec![];
+        transcode(reader, &mut out, &TranscodeOptions::default()).unwrap();
+
+        let original = PngImage::new(IMAGE_PATH).unwrap();
+        let transcoded = PngImage::from_bytes(out).unwrap();
+        assert_eq!(transcoded.decode().unwrap(), original.decode().unwrap());
+        assert!(transcoded.get_chunks("IDAT").all(|c| c.size <= 8192));
+    }
+
+    #[test]
+    fn test_transcode_strips_metadata() {
+        let image = image_with_metadata();
+        let options = TranscodeOptions {
+            recompress: false,
+            encode: EncodeOptions {
+                metadata: MetadataPolicy::KeepOnly(vec!["gAMA".to_string()]),
+                ..EncodeOptions::default()
+            },
+            ..TranscodeOptions::default()
+        };
+
+        let mut out = vec![];
+        transcode(image.to_bytes().as_slice(), &mut out, &options).unwrap();
+        let transcoded = PngImage::from_bytes(out).unwrap();
+
+        let types: Vec<&str> = transcoded
+            .chunks
+            .iter()
+            .map(|c| c.chunk_type.as_str())
+            .collect();
+        assert_eq!(types, ["IHDR", "gAMA", "IDAT", "IEND"]);
+        assert_eq!(
+            transcoded.get_chunk("IDAT").unwrap().data,
+            image.get_chunk("IDAT").unwrap().data
+        );
+    }
+
+    #[test]
+    fn test_transcode_interlaced() {
+        let mut pixels = PixelBuffer::new(13, 11).unwrap();
+        for (i, b) in pixels.data.iter_mut().enumerate() {
+            *b = (i * 7 % 251) as u8;
+        }
+
+        // lay the pixels out as adam7 passes by hand
+        let info = PNGInfo {
+            width: 13,
+            height: 11,
+            bit_depth: 8,
+            color_type: 6,
+            compression_method: 0,
+            filter_method: 0,
+            interlace_method: 1,
+        };
+        let mut encoder = RowEncoder::new(vec![], 4, &EncodeOptions::default());
+        for pass in Layout::new(&info).unwrap().passes() {
+            encoder.start_pass();
+            for y in 0..pass.height {
+                let mut row = vec![];
+                for x in 0..pass.width {
+                    row.extend(pixels.get_pixel(pass.x0 + x * pass.dx, pass.y0 + y * pass.dy));
+                }
+                encoder.write_row(&row).unwrap();
+            }
+        }
+        let compressed = encoder.finish().unwrap();
+
+        let mut chunks = vec![info.to_chunk()];
+        chunks.extend(split_idat(&compressed, 100));
+        chunks.push(PNGChunk::new("IEND", vec![]));
+        let image = PngImage { info, chunks };
+        assert_eq!(image.decode().unwrap(), pixels);
+
+        let mut out = vec![];
+        transcode(
+            image.to_bytes().as_slice(),
+            &mut out,
+            &TranscodeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(PngImage::from_bytes(out).unwrap().decode().unwrap(), pixels);
+    }
+
+    #[test]
+    fn test_transcode_bad_crc() {
+        let mut bytes = image_with_metadata().to_bytes();
+        let last = bytes.len() - 20;
+        bytes[last] ^= 0xFF;
+
+        let mut out = vec![];
+        assert!(transcode(bytes.as_slice(), &mut out, &TranscodeOptions::default()).is_err());
+    }
+}